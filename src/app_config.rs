@@ -1,14 +1,15 @@
 use clap::Parser;
 
-use std::{net::Ipv4Addr, str::FromStr};
+use std::{io, net::Ipv4Addr, num::NonZeroUsize, str::FromStr, time::Duration};
 
 use tracing::debug;
 use tracing_subscriber::{
     filter::{EnvFilter, LevelFilter},
-    fmt,
+    fmt::{self, writer::BoxMakeWriter},
 };
 
 const INVALID_IP_ADDRESS_ERROR: &str = "IP address isn't valid";
+const INVALID_DATABASE_PATTERN_ERROR: &str = "database pattern should look like host/dbname";
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
@@ -36,9 +37,64 @@ pub struct AppConfig {
     /// Path to config file
     #[clap(long, short)]
     pub config: String,
+
+    /// Run collectors of the matching databases only, pattern is host/dbname with optional * wildcards,
+    /// may be specified several times
+    #[clap(long, value_parser = AppConfig::parse_database_pattern)]
+    pub only_database: Vec<String>,
+
+    /// Print definitions of all metrics produced by the config in JSON format and exit
+    #[clap(long)]
+    pub list_metrics: bool,
+
+    /// Print resolved definitions of all queries (with inherited intervals and without passwords) in JSON format and exit
+    #[clap(long)]
+    pub list_queries: bool,
+
+    /// Load all certificates and keys referenced by the config before start and exit if any of them is broken
+    #[clap(long)]
+    pub strict: bool,
+
+    /// Print metrics to stdout with specified interval (like 30s or 1m) instead of serving them over http
+    #[clap(long, value_parser = humantime_serde::re::humantime::parse_duration)]
+    pub stdout_interval: Option<Duration>,
+
+    /// Path to file to write failed queries records to
+    #[clap(long)]
+    pub error_log: Option<String>,
+
+    /// Maximum size of the error log file in bytes before rotation, 0 means no limit
+    #[clap(long, default_value_t = 10485760)]
+    pub error_log_max_size: u64,
+
+    /// Path to file to save metric values to on shutdown and to restore them from on startup
+    #[clap(long)]
+    pub state_file: Option<String>,
+
+    /// Maximum age of the state file to restore metric values from (like 10m or 1h)
+    #[clap(long, default_value = "10m", value_parser = humantime_serde::re::humantime::parse_duration)]
+    pub state_max_age: Duration,
+
+    /// Maximum number of queries running at the same time across all databases, 0 means no limit
+    #[clap(long, default_value_t = 0)]
+    pub max_concurrent_queries: usize,
+
+    /// Run collector of each database on a dedicated thread with its own runtime
+    #[clap(long)]
+    pub dedicated_threads: bool,
+
+    /// Number of worker threads of the main runtime, number of CPU cores by default
+    #[clap(long)]
+    pub worker_threads: Option<NonZeroUsize>,
+
+    /// Bearer token to access admin endpoints, admin endpoints are disabled if it's not set
+    #[clap(long, env = "PSQL_EXPORTER_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
 }
 
 impl AppConfig {
+    // Parses command line and sets up logger, so it isn't a default value
+    #[allow(clippy::new_without_default)]
     pub fn new() -> AppConfig {
         let config: AppConfig = Parser::parse();
         debug!("Application config: {:?}", config);
@@ -59,7 +115,16 @@ impl AppConfig {
         let log_filter = EnvFilter::from_default_env().add_directive(level_filter.into());
         let log_format = fmt::format().with_level(true).with_target(self.debug);
 
-        let subscriber = tracing_subscriber::fmt().with_env_filter(log_filter);
+        // Keep stdout clean for metrics if they're printed there
+        let log_writer = if self.stdout_interval.is_some() {
+            BoxMakeWriter::new(io::stderr)
+        } else {
+            BoxMakeWriter::new(io::stdout)
+        };
+
+        let subscriber = tracing_subscriber::fmt()
+            .with_env_filter(log_filter)
+            .with_writer(log_writer);
         if self.json_log {
             subscriber
                 .event_format(log_format.json().flatten_event(true))
@@ -72,6 +137,15 @@ impl AppConfig {
     fn parse_ip_address(ip: &str) -> Result<Ipv4Addr, String> {
         Ipv4Addr::from_str(ip).map_err(|_| String::from(INVALID_IP_ADDRESS_ERROR))
     }
+
+    fn parse_database_pattern(pattern: &str) -> Result<String, String> {
+        match pattern.split_once('/') {
+            Some((host, dbname)) if !host.is_empty() && !dbname.is_empty() => {
+                Ok(pattern.to_string())
+            }
+            _ => Err(String::from(INVALID_DATABASE_PATTERN_ERROR)),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -90,6 +164,26 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_database_patterns() {
+        assert_eq!(
+            AppConfig::parse_database_pattern("db1.example.com/app"),
+            Ok(String::from("db1.example.com/app"))
+        );
+        assert_eq!(
+            AppConfig::parse_database_pattern("*/app_*"),
+            Ok(String::from("*/app_*"))
+        );
+
+        for pattern in ["app", "/app", "db1.example.com/", ""] {
+            assert_eq!(
+                AppConfig::parse_database_pattern(pattern),
+                Err(String::from(INVALID_DATABASE_PATTERN_ERROR)),
+                "{pattern}"
+            );
+        }
+    }
+
     #[test]
     fn parse_incorrect_ip() {
         assert_eq!(
@@ -1,42 +1,76 @@
 use crate::{
+    error_log::{self, ErrorRecord},
     errors::PsqlExporterError,
-    utils::{ShutdownReceiver, SleepHelper},
+    internal_metrics,
+    utils::{QueryLimiter, ShutdownReceiver, SleepHelper},
 };
 
 use serde::Deserialize;
 use std::{
     fmt::{Debug, Display},
-    time::Duration,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
 };
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
-use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::{
+    error::ErrorStack,
+    pkey::PKey,
+    ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode, SslVersion},
+    x509::{store::X509Lookup, verify::X509VerifyFlags, X509},
+};
 use postgres_openssl::MakeTlsConnector;
-use tokio::task::JoinHandle;
-use tokio_postgres::{Client, Row};
+use std::future::Future;
+use tokio::{
+    net::lookup_host,
+    task::JoinHandle,
+    time::{timeout_at, Instant},
+};
+use tokio_postgres::{
+    error::SqlState,
+    tls::{MakeTlsConnect, TlsConnect},
+    types::{FromSql, Type},
+    Client, Row, Socket,
+};
 
 const DB_APP_NAME: &str = env!("CARGO_PKG_NAME");
 const DB_APP_VERSION: &str = env!("CARGO_PKG_VERSION");
+const CONNECTION_ATTRIBUTES_QUERY: &str =
+    "select current_setting('server_version'), pg_backend_pid(), pg_is_in_recovery()";
+const CONNECTION_ATTRIBUTES_TIMEOUT: Duration = Duration::from_secs(10);
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct PostgresConnectionString {
     pub host: String,
     pub port: u16,
     pub dbname: String,
+    // name of the database in the startup message if it differs from dbname (alias of the pooler, for example)
+    pub connect_dbname: Option<String>,
     pub user: String,
     pub password: String,
     pub sslmode: PostgresSslMode,
+    pub pooler: PostgresPooler,
+    pub statement_timeout: Duration,
+    pub client_encoding: ClientEncoding,
+    pub ip_family: IpFamily,
+    pub connect_timeout: Duration,
+    pub ssl_min_protocol_version: SslProtocolVersion,
+    pub clock_skew_tolerance: Duration,
 }
 
 impl Display for PostgresConnectionString {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "host={host} port={port} dbname={dbname} user={user} password='***' sslmode={sslmode} application_name={DB_APP_NAME}-v{DB_APP_VERSION}", host=self.host, port=self.port, user=self.user, sslmode=self.sslmode, dbname=self.dbname)
+        write!(f, "host={host} port={port} dbname={dbname} user={user} password='***' sslmode={sslmode} application_name={DB_APP_NAME}-v{DB_APP_VERSION}{options}", host=escape_conn_value(&self.host), port=self.port, user=escape_conn_value(&self.user), sslmode=self.sslmode, dbname=escape_conn_value(self.connect_dbname()), options=self.get_options())
     }
 }
 
 impl Debug for PostgresConnectionString {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "host={host} port={port} dbname={dbname} user={user} password='***' sslmode={sslmode} application_name={DB_APP_NAME}-v{DB_APP_VERSION}", host=self.host, port=self.port, user=self.user, sslmode=self.sslmode, dbname=self.dbname)
+        write!(f, "host={host} port={port} dbname={dbname} user={user} password='***' sslmode={sslmode} application_name={DB_APP_NAME}-v{DB_APP_VERSION}{options}", host=escape_conn_value(&self.host), port=self.port, user=escape_conn_value(&self.user), sslmode=self.sslmode, dbname=escape_conn_value(self.connect_dbname()), options=self.get_options())
     }
 }
 
@@ -46,31 +80,135 @@ impl Default for PostgresConnectionString {
             host: String::new(),
             port: 5432,
             dbname: String::new(),
+            connect_dbname: None,
             user: String::new(),
             password: String::new(),
             sslmode: PostgresSslMode::Prefer,
+            pooler: PostgresPooler::default(),
+            statement_timeout: Duration::ZERO,
+            client_encoding: ClientEncoding::default(),
+            ip_family: IpFamily::default(),
+            connect_timeout: Duration::ZERO,
+            ssl_min_protocol_version: SslProtocolVersion::default(),
+            clock_skew_tolerance: Duration::ZERO,
         }
     }
 }
 
 impl PostgresConnectionString {
+    // dbname identifies the database in labels and logs, the connection is established to this one
+    fn connect_dbname(&self) -> &str {
+        self.connect_dbname.as_deref().unwrap_or(&self.dbname)
+    }
+
+    pub fn id(&self) -> String {
+        format!("{}@{}:{}/{}", self.user, self.host, self.port, self.dbname)
+    }
+
     fn get_conn_string(&self) -> String {
-        format!("host={host} port={port} dbname={dbname} user={user} password='{password}' sslmode={sslmode} application_name={DB_APP_NAME}-v{DB_APP_VERSION}", host=self.host, port=self.port, user=self.user, password=self.password, sslmode=self.sslmode, dbname=self.dbname)
+        format!("host={host} port={port} dbname={dbname} user={user} password={password} sslmode={sslmode} application_name={DB_APP_NAME}-v{DB_APP_VERSION}{options}", host=escape_conn_value(&self.host), port=self.port, user=escape_conn_value(&self.user), password=escape_conn_value(&self.password), sslmode=self.sslmode, dbname=escape_conn_value(self.connect_dbname()), options=self.get_options())
+    }
+
+    // Resolves host and orders its addresses by preferred IP family,
+    // tokio_postgres tries them one by one, connect_timeout is applied to each attempt
+    async fn get_resolved_conn_string(&self) -> String {
+        if self.ip_family == IpFamily::Any {
+            return self.get_conn_string();
+        }
+
+        match lookup_host((self.host.as_str(), self.port)).await {
+            Ok(addresses) => {
+                let mut addresses: Vec<IpAddr> = addresses.map(|a| a.ip()).collect();
+                self.ip_family.order_addresses(&mut addresses);
+                self.get_conn_string_with_addresses(&addresses)
+            }
+            Err(e) => {
+                error!("unable to resolve host {}: {e}", self.host);
+                self.get_conn_string()
+            }
+        }
+    }
+
+    fn get_conn_string_with_addresses(&self, addresses: &[IpAddr]) -> String {
+        if addresses.is_empty() {
+            return self.get_conn_string();
+        }
+
+        let hosts = vec![self.host.as_str(); addresses.len()].join(",");
+        let hostaddrs: Vec<String> = addresses.iter().map(|a| a.to_string()).collect();
+        format!("host={host} hostaddr={hostaddr} port={port} dbname={dbname} user={user} password={password} sslmode={sslmode} application_name={DB_APP_NAME}-v{DB_APP_VERSION}{options}", host=escape_conn_value(&hosts), hostaddr=hostaddrs.join(","), port=self.port, user=escape_conn_value(&self.user), password=escape_conn_value(&self.password), sslmode=self.sslmode, dbname=escape_conn_value(self.connect_dbname()), options=self.get_options())
+    }
+
+    // tokio_postgres bounds TCP connect to each address only, so the host which accepts connections
+    // but never completes TLS or startup handshake would block the connection attempt forever
+    async fn connect_deadline(&self) -> Option<Duration> {
+        if self.connect_timeout == Duration::ZERO {
+            return None;
+        }
+
+        let addresses = lookup_host((self.host.as_str(), self.port))
+            .await
+            .map(|addresses| addresses.count())
+            .unwrap_or_default()
+            .max(1);
+        // the same whole seconds as in the connection string
+        Some(Duration::from_secs(self.connect_timeout.as_secs().max(1)) * addresses as u32)
+    }
+
+    fn get_options(&self) -> String {
+        // PgBouncer rejects unknown startup parameters, so its ignore_startup_parameters should include options
+        let mut options = if self.pooler.allows_session_settings() {
+            String::new()
+        } else {
+            format!(
+                " options='-c statement_timeout={}'",
+                self.statement_timeout.as_millis()
+            )
+        };
+
+        if self.connect_timeout != Duration::ZERO {
+            // connect_timeout is in whole seconds
+            options.push_str(&format!(
+                " connect_timeout={}",
+                self.connect_timeout.as_secs().max(1)
+            ));
+        }
+
+        options
     }
 }
+
+// Quote value and escape backslashes and single quotes according to libpq connection string rules
+fn escape_conn_value(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
 #[derive(Debug)]
 pub struct PostgresConnection {
     db_connection_string: PostgresConnectionString,
     client: Client,
     connection_handler: JoinHandle<()>,
     sslmode: PostgresSslMode,
+    verify_ca_allow: Vec<VerifyCaAllowance>,
+    reconnect_on_error_codes: Vec<String>,
     certificates: PostgresSslCertificates,
     default_backoff_interval: Duration,
     max_backoff_interval: Duration,
     shutdown_channel: ShutdownReceiver,
+    // connections of the same DB in the pool are told apart by the index in connection status
+    pool_index: usize,
+    attributes: Option<ConnectionAttributes>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+// Attributes of the server session, they don't change until reconnect
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionAttributes {
+    pub server_version: String,
+    pub backend_pid: i32,
+    pub in_recovery: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum PostgresSslMode {
     Disable,
@@ -82,12 +220,214 @@ pub enum PostgresSslMode {
     VerifyFull,
 }
 
+impl PostgresSslMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Disable => "disable",
+            Self::Prefer => "prefer",
+            Self::Require => "require",
+            Self::VerifyCa => "verify-ca",
+            Self::VerifyFull => "verify-full",
+        }
+    }
+}
+
+// tokio_postgres doesn't tell if connection is encrypted,
+// so TLS connector remembers if handshake has been started (server may refuse it in prefer mode)
+struct TrackingTlsConnector<M> {
+    inner: M,
+    encrypted: Arc<AtomicBool>,
+}
+
+impl<M: MakeTlsConnect<Socket>> MakeTlsConnect<Socket> for TrackingTlsConnector<M> {
+    type Stream = M::Stream;
+    type TlsConnect = TrackingTlsConnect<M::TlsConnect>;
+    type Error = M::Error;
+
+    fn make_tls_connect(&mut self, domain: &str) -> Result<Self::TlsConnect, Self::Error> {
+        Ok(TrackingTlsConnect {
+            inner: self.inner.make_tls_connect(domain)?,
+            encrypted: self.encrypted.clone(),
+        })
+    }
+}
+
+struct TrackingTlsConnect<T> {
+    inner: T,
+    encrypted: Arc<AtomicBool>,
+}
+
+impl<T: TlsConnect<Socket>> TlsConnect<Socket> for TrackingTlsConnect<T> {
+    type Stream = T::Stream;
+    type Error = T::Error;
+    type Future = T::Future;
+
+    fn connect(self, stream: Socket) -> Self::Future {
+        self.encrypted.store(true, Ordering::Relaxed);
+        self.inner.connect(stream)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PostgresPooler {
+    #[default]
+    None,
+    Pgbouncer,
+}
+
+impl PostgresPooler {
+    pub fn allows_session_settings(&self) -> bool {
+        match self {
+            Self::None => true,
+            Self::Pgbouncer => false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientEncoding {
+    #[default]
+    Utf8,
+    Latin1,
+}
+
+impl ClientEncoding {
+    // Client encoding can't be set using connection options because tokio_postgres always sends UTF8 in startup message
+    fn set_statement(&self) -> Option<&'static str> {
+        match self {
+            Self::Utf8 => None,
+            Self::Latin1 => Some("SET client_encoding TO 'LATIN1'"),
+        }
+    }
+
+    // NULL is returned as None, so the caller decides whether the row is usable at all
+    pub fn label_value(
+        &self,
+        row: &Row,
+        column: &str,
+        row_index: usize,
+    ) -> Result<Option<String>, PsqlExporterError> {
+        let value = match self {
+            Self::Utf8 => row.try_get::<_, Option<String>>(column),
+            Self::Latin1 => row
+                .try_get::<_, Option<RawText>>(column)
+                .map(|value| value.map(|value| self.decode(value.0))),
+        };
+
+        value.map_err(|cause| {
+            let oid = row
+                .columns()
+                .iter()
+                .find(|c| c.name() == column)
+                .map(|c| c.type_().oid());
+            PsqlExporterError::InvalidFieldValue {
+                column: column.to_string(),
+                oid: oid.unwrap_or_default(),
+                row: row_index,
+                cause,
+            }
+        })
+    }
+
+    fn decode(&self, bytes: &[u8]) -> String {
+        match self {
+            Self::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            Self::Latin1 => bytes.iter().copied().map(char::from).collect(),
+        }
+    }
+}
+
+// Text column value as is, without UTF-8 validation
+struct RawText<'a>(&'a [u8]);
+
+// Size of the column value as received from the DB, NULL has no value bytes
+struct RawSize(usize);
+
+impl<'a> FromSql<'a> for RawSize {
+    fn from_sql(
+        _ty: &Type,
+        raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        Ok(Self(raw.len()))
+    }
+
+    fn from_sql_null(_ty: &Type) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        Ok(Self(0))
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+}
+
+impl<'a> FromSql<'a> for RawText<'a> {
+    fn from_sql(
+        _ty: &Type,
+        raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        Ok(Self(raw))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <&str as FromSql>::accepts(ty)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum SslProtocolVersion {
+    #[serde(rename = "TLSv1")]
+    Tls1,
+    #[serde(rename = "TLSv1.1")]
+    Tls1_1,
+    #[default]
+    #[serde(rename = "TLSv1.2")]
+    Tls1_2,
+    #[serde(rename = "TLSv1.3")]
+    Tls1_3,
+}
+
+impl SslProtocolVersion {
+    fn ssl_version(&self) -> SslVersion {
+        match self {
+            Self::Tls1 => SslVersion::TLS1,
+            Self::Tls1_1 => SslVersion::TLS1_1,
+            Self::Tls1_2 => SslVersion::TLS1_2,
+            Self::Tls1_3 => SslVersion::TLS1_3,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl IpFamily {
+    // Addresses of the preferred family go first, the rest are kept as a fallback
+    fn order_addresses(&self, addresses: &mut [IpAddr]) {
+        match self {
+            Self::Any => {}
+            Self::Ipv4 => addresses.sort_by_key(|a| !a.is_ipv4()),
+            Self::Ipv6 => addresses.sort_by_key(|a| !a.is_ipv6()),
+        }
+    }
+}
+
 impl Default for PostgresSslMode {
     fn default() -> Self {
         Self::Prefer
     }
 }
 
+// This is the mode of the driver's connection string: tokio-postgres doesn't accept verify-ca and verify-full
+// there, so both of them are passed as require, and certificate is verified by the TLS connector
+// built for the configured mode (see build_ssl_connector); use as_str() to get the configured mode
 impl Display for PostgresSslMode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let s = match self {
@@ -101,18 +441,83 @@ impl Display for PostgresSslMode {
     }
 }
 
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyCaAllowance {
+    Hostname,
+    Ip,
+    Email,
+}
+
+impl VerifyCaAllowance {
+    pub fn default_set() -> Vec<Self> {
+        vec![Self::Hostname, Self::Ip, Self::Email]
+    }
+
+    fn x509_error(&self) -> i32 {
+        match self {
+            Self::Hostname => openssl_sys::X509_V_ERR_HOSTNAME_MISMATCH,
+            Self::Ip => openssl_sys::X509_V_ERR_IP_ADDRESS_MISMATCH,
+            Self::Email => openssl_sys::X509_V_ERR_EMAIL_MISMATCH,
+        }
+    }
+}
+
+// Certificate or key is either a path to PEM file or PEM content itself
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(from = "String")]
+pub enum PemSource {
+    File(String),
+    Inline(String),
+}
+
+impl From<String> for PemSource {
+    fn from(path: String) -> Self {
+        Self::File(path)
+    }
+}
+
+// Inline content may be a private key, so it never goes to logs
+impl Display for PemSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{path}"),
+            Self::Inline(_) => write!(f, "<inline PEM>"),
+        }
+    }
+}
+
+impl Debug for PemSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "File({path:?})"),
+            Self::Inline(_) => write!(f, "Inline(***)"),
+        }
+    }
+}
+
+// Empty stack means there is no valid certificate at all, so fall back to single one parsing to get an error
+fn pem_certificates(pem: &str) -> Result<Vec<X509>, ErrorStack> {
+    let certs = X509::stack_from_pem(pem.as_bytes())?;
+    if certs.is_empty() {
+        X509::from_pem(pem.as_bytes())?;
+    }
+    Ok(certs)
+}
+
 #[derive(Debug, Clone)]
 pub struct PostgresSslCertificates {
-    rootcert: Option<String>,
-    cert: Option<String>,
-    key: Option<String>,
+    rootcert: Option<PemSource>,
+    cert: Option<PemSource>,
+    key: Option<PemSource>,
+    crl: Option<String>,
 }
 
 impl PostgresSslCertificates {
     pub fn from(
-        rootcert: Option<String>,
-        cert: Option<String>,
-        key: Option<String>,
+        rootcert: Option<PemSource>,
+        cert: Option<PemSource>,
+        key: Option<PemSource>,
     ) -> Result<Self, PsqlExporterError> {
         match (cert, key) {
             (Some(cert), None) => Err(PsqlExporterError::PostgresTlsClientConfig(format!(
@@ -127,41 +532,98 @@ impl PostgresSslCertificates {
                 rootcert,
                 cert: Some(cert),
                 key: Some(key),
+                crl: None,
             }),
             (None, None) => Ok(Self {
                 rootcert,
                 cert: None,
                 key: None,
+                crl: None,
             }),
         }
     }
 
+    pub fn with_crl(mut self, crl: Option<String>) -> Self {
+        self.crl = crl;
+        self
+    }
+
     pub fn has_client_cert(&self) -> bool {
         self.cert.is_some()
     }
 }
 
 impl PostgresConnection {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         db_connection_string: PostgresConnectionString,
         sslmode: PostgresSslMode,
+        verify_ca_allow: Vec<VerifyCaAllowance>,
+        reconnect_on_error_codes: Vec<String>,
         certificates: PostgresSslCertificates,
         default_backoff_interval: Duration,
         max_backoff_interval: Duration,
         shutdown_channel: ShutdownReceiver,
+        pool_index: usize,
     ) -> Result<Self, PsqlExporterError> {
         debug!("PostgresConnection::new: construct new postgres connection");
+        Self::connect(
+            db_connection_string,
+            sslmode,
+            verify_ca_allow,
+            reconnect_on_error_codes,
+            certificates,
+            default_backoff_interval,
+            max_backoff_interval,
+            shutdown_channel,
+            pool_index,
+            0,
+        )
+        .await
+    }
 
+    // Zero max_attempts means connection attempts are repeated until success or shutdown
+    #[allow(clippy::too_many_arguments)]
+    async fn connect(
+        db_connection_string: PostgresConnectionString,
+        sslmode: PostgresSslMode,
+        verify_ca_allow: Vec<VerifyCaAllowance>,
+        reconnect_on_error_codes: Vec<String>,
+        certificates: PostgresSslCertificates,
+        default_backoff_interval: Duration,
+        max_backoff_interval: Duration,
+        shutdown_channel: ShutdownReceiver,
+        pool_index: usize,
+        max_attempts: u32,
+    ) -> Result<Self, PsqlExporterError> {
         let mut backoff_interval = default_backoff_interval;
         let mut sleeper = SleepHelper::from(shutdown_channel.clone());
+        let mut attempts = 0;
 
         loop {
-            let connector = Self::build_tls_connector(&sslmode, &certificates)?;
-            let connection =
-                tokio_postgres::connect(&db_connection_string.get_conn_string(), connector).await;
+            let encrypted = Arc::new(AtomicBool::new(false));
+            let connector = TrackingTlsConnector {
+                inner: Self::build_tls_connector(
+                    &sslmode,
+                    &verify_ca_allow,
+                    db_connection_string.ssl_min_protocol_version,
+                    &certificates,
+                )?,
+                encrypted: encrypted.clone(),
+            };
+            // host name is resolved before the timer start, so the duration doesn't include DNS lookup
+            let conn_string = db_connection_string.get_resolved_conn_string().await;
+            let deadline = db_connection_string.connect_deadline().await;
+            let started = Instant::now();
+            let connect = tokio_postgres::connect(&conn_string, connector);
+            let connection = match deadline {
+                Some(deadline) => tokio::time::timeout(deadline, connect).await,
+                None => Ok(connect.await),
+            };
 
             match connection {
-                Ok((client, connection)) => {
+                Ok(Ok((client, connection))) => {
+                    let connect_duration = started.elapsed();
                     let connection_handler = tokio::spawn(async move {
                         debug!("PostgresConnection::new: spawn new connection task");
                         if let Err(e) = connection.await {
@@ -169,63 +631,177 @@ impl PostgresConnection {
                         }
                     });
 
-                    return Ok(PostgresConnection {
-                        client,
-                        db_connection_string,
-                        connection_handler,
-                        sslmode,
-                        certificates,
-                        default_backoff_interval,
-                        max_backoff_interval,
-                        shutdown_channel,
-                    });
+                    let encoding_result = match db_connection_string.client_encoding.set_statement()
+                    {
+                        Some(statement) => client.batch_execute(statement).await,
+                        None => Ok(()),
+                    };
+
+                    match encoding_result {
+                        Ok(_) => {
+                            internal_metrics::set_connection_status(
+                                &db_connection_string.id(),
+                                pool_index,
+                                true,
+                            );
+                            internal_metrics::set_connection_sslmode(
+                                &db_connection_string.host,
+                                &db_connection_string.dbname,
+                                sslmode.as_str(),
+                                encrypted.load(Ordering::Relaxed),
+                            );
+                            internal_metrics::set_connect_duration(
+                                &db_connection_string.host,
+                                &db_connection_string.dbname,
+                                connect_duration,
+                            );
+                            if db_connection_string.clock_skew_tolerance != Duration::ZERO {
+                                Self::check_clock_skew(&client, &db_connection_string).await;
+                            }
+                            return Ok(PostgresConnection {
+                                client,
+                                db_connection_string,
+                                connection_handler,
+                                sslmode,
+                                verify_ca_allow,
+                                reconnect_on_error_codes,
+                                certificates,
+                                default_backoff_interval,
+                                max_backoff_interval,
+                                shutdown_channel,
+                                pool_index,
+                                attributes: None,
+                            });
+                        }
+                        Err(e) => {
+                            error!("PostgresConnection::new: unable to set client encoding: {e}");
+                            connection_handler.abort();
+                        }
+                    }
                 }
-                Err(e) => {
+                Ok(Err(e)) => {
                     error!("PostgresConnection::new: client error: {e}");
                 }
+                Err(_) => {
+                    error!(
+                        "PostgresConnection::new: connection attempt has timed out after {:?}",
+                        started.elapsed()
+                    );
+                }
             };
 
+            attempts += 1;
+            if max_attempts > 0 && attempts >= max_attempts {
+                return Err(PsqlExporterError::ConnectAttemptsExhausted {
+                    dbname: db_connection_string.dbname.clone(),
+                    attempts,
+                });
+            }
             sleeper.sleep(backoff_interval).await?;
-            backoff_interval += default_backoff_interval;
-            if backoff_interval > max_backoff_interval {
-                backoff_interval = max_backoff_interval;
+            backoff_interval = Self::next_backoff_interval(
+                backoff_interval,
+                default_backoff_interval,
+                max_backoff_interval,
+            );
+        }
+    }
+
+    // Timestamps of the DB are compared with the local clock, so broken NTP is visible; failed check doesn't
+    // affect the connection
+    async fn check_clock_skew(client: &Client, db_connection_string: &PostgresConnectionString) {
+        let sent_at = SystemTime::now();
+        let server_time = client
+            .query_one("select extract(epoch from clock_timestamp())::float8", &[])
+            .await
+            .and_then(|row| row.try_get::<_, f64>(0));
+        let received_at = SystemTime::now();
+
+        match server_time {
+            Ok(server_time) => {
+                let skew = clock_skew(server_time, sent_at, received_at);
+                internal_metrics::set_clock_skew(
+                    &db_connection_string.host,
+                    &db_connection_string.dbname,
+                    skew,
+                );
+                if skew.abs() > db_connection_string.clock_skew_tolerance.as_secs_f64() {
+                    warn!(
+                        "clock of {} differs from the local one by {skew:.3}s, check NTP on both hosts",
+                        db_connection_string.id()
+                    );
+                }
             }
+            Err(e) => warn!(
+                "unable to check clock skew of {}: {e}",
+                db_connection_string.id()
+            ),
         }
     }
 
+    // Loads all configured certificates and keys exactly like connect does, but without connecting
+    pub fn check_tls_config(
+        sslmode: &PostgresSslMode,
+        verify_ca_allow: &[VerifyCaAllowance],
+        ssl_min_protocol_version: SslProtocolVersion,
+        certificates: &PostgresSslCertificates,
+    ) -> Result<(), PsqlExporterError> {
+        Self::build_ssl_connector(
+            sslmode,
+            verify_ca_allow,
+            ssl_min_protocol_version,
+            certificates,
+        )
+        .map(|_| ())
+    }
+
     fn build_tls_connector(
         sslmode: &PostgresSslMode,
+        verify_ca_allow: &[VerifyCaAllowance],
+        ssl_min_protocol_version: SslProtocolVersion,
         certificates: &PostgresSslCertificates,
     ) -> Result<MakeTlsConnector, PsqlExporterError> {
+        let connector = Self::build_ssl_connector(
+            sslmode,
+            verify_ca_allow,
+            ssl_min_protocol_version,
+            certificates,
+        )?;
+        Ok(MakeTlsConnector::new(connector))
+    }
+
+    fn build_ssl_connector(
+        sslmode: &PostgresSslMode,
+        verify_ca_allow: &[VerifyCaAllowance],
+        ssl_min_protocol_version: SslProtocolVersion,
+        certificates: &PostgresSslCertificates,
+    ) -> Result<SslConnector, PsqlExporterError> {
         let mut connector = SslConnector::builder(SslMethod::tls())
             .map_err(PsqlExporterError::PostgresTlsConnector)?;
+        connector
+            .set_min_proto_version(Some(ssl_min_protocol_version.ssl_version()))
+            .map_err(PsqlExporterError::PostgresTlsConnector)?;
 
         match *sslmode {
             PostgresSslMode::Disable => connector.set_verify(SslVerifyMode::NONE),
             PostgresSslMode::Prefer => connector.set_verify(SslVerifyMode::NONE),
             PostgresSslMode::Require => connector.set_verify(SslVerifyMode::NONE),
             PostgresSslMode::VerifyCa => {
+                let allowed_errors: Vec<i32> =
+                    verify_ca_allow.iter().map(|a| a.x509_error()).collect();
                 connector.set_verify_callback(
                     SslVerifyMode::PEER,
-                    |verify_indicator, x509_result| {
-                        let allowed_errors: Vec<i32> = vec![
-                            openssl_sys::X509_V_ERR_IP_ADDRESS_MISMATCH,
-                            openssl_sys::X509_V_ERR_HOSTNAME_MISMATCH,
-                            openssl_sys::X509_V_ERR_EMAIL_MISMATCH,
-                        ];
+                    move |verify_indicator, x509_result| {
                         debug!(
                             "verify_callback, indicator={}, x509_result={}",
                             verify_indicator,
                             x509_result.error()
                         );
 
-                        if !verify_indicator
-                            && allowed_errors.contains(&x509_result.error().as_raw())
-                        {
-                            true
-                        } else {
-                            verify_indicator
-                        }
+                        Self::verify_ca_result(
+                            &allowed_errors,
+                            verify_indicator,
+                            x509_result.error().as_raw(),
+                        )
                     },
                 );
             }
@@ -234,101 +810,332 @@ impl PostgresConnection {
 
         if let Some(rootcert) = certificates.rootcert.as_ref() {
             debug!("loading CA bundle from {}", rootcert);
-            connector.set_ca_file(rootcert).map_err(|e| {
-                PsqlExporterError::PostgresTlsRootCertificate {
-                    rootcert: (*rootcert).clone(),
-                    cause: e,
-                }
+            match rootcert {
+                PemSource::File(path) => connector.set_ca_file(path),
+                PemSource::Inline(pem) => pem_certificates(pem).and_then(|certs| {
+                    certs
+                        .into_iter()
+                        .try_for_each(|cert| connector.cert_store_mut().add_cert(cert))
+                }),
+            }
+            .map_err(|e| PsqlExporterError::PostgresTlsRootCertificate {
+                rootcert: rootcert.to_string(),
+                cause: e,
             })?;
         }
 
+        // CRL of the server certificate issuer is required then, as libpq does;
+        // server certificate isn't verified at all in the rest of modes, so CRL is ignored there
+        if let Some(crl) = certificates.crl.as_ref() {
+            if matches!(
+                sslmode,
+                PostgresSslMode::VerifyCa | PostgresSslMode::VerifyFull
+            ) {
+                debug!("loading CRL from {}", crl);
+                let store = connector.cert_store_mut();
+                store
+                    .add_lookup(X509Lookup::file())
+                    .and_then(|lookup| lookup.load_crl_file(crl, SslFiletype::PEM))
+                    .and_then(|_| store.set_flags(X509VerifyFlags::CRL_CHECK))
+                    .map_err(|e| PsqlExporterError::PostgresTlsCrl {
+                        crl: crl.clone(),
+                        cause: e,
+                    })?;
+            }
+        }
+
         if certificates.has_client_cert() {
             if let Some(cert) = certificates.cert.as_ref() {
                 debug!("loading client certificate from {}", cert);
-                connector
-                    .set_certificate_file(cert, SslFiletype::PEM)
-                    .map_err(|e| PsqlExporterError::PostgresTlsClientCertificate {
-                        filename: (*cert).clone(),
-                        cause: e,
-                    })?;
+                match cert {
+                    PemSource::File(path) => connector.set_certificate_file(path, SslFiletype::PEM),
+                    // the first certificate is the client one, the rest of them are its chain
+                    PemSource::Inline(pem) => pem_certificates(pem).and_then(|certs| {
+                        let mut certs = certs.into_iter();
+                        if let Some(client_cert) = certs.next() {
+                            connector.set_certificate(&client_cert)?;
+                        }
+                        certs.try_for_each(|cert| connector.add_extra_chain_cert(cert))
+                    }),
+                }
+                .map_err(|e| PsqlExporterError::PostgresTlsClientCertificate {
+                    filename: cert.to_string(),
+                    cause: e,
+                })?;
             }
 
             if let Some(key) = certificates.key.as_ref() {
                 debug!("loading client private key from {}", key);
-                connector
-                    .set_private_key_file(key, SslFiletype::PEM)
-                    .map_err(|e| PsqlExporterError::PostgresTlsClientCertificate {
-                        filename: (*key).clone(),
-                        cause: e,
-                    })?;
+                match key {
+                    PemSource::File(path) => connector.set_private_key_file(path, SslFiletype::PEM),
+                    PemSource::Inline(pem) => PKey::private_key_from_pem(pem.as_bytes())
+                        .and_then(|key| connector.set_private_key(&key)),
+                }
+                .map_err(|e| PsqlExporterError::PostgresTlsClientCertificate {
+                    filename: key.to_string(),
+                    cause: e,
+                })?;
             }
         }
 
-        let connector = MakeTlsConnector::new(connector.build());
-        Ok(connector)
+        Ok(connector.build())
+    }
+
+    fn verify_ca_result(allowed_errors: &[i32], verify_indicator: bool, error: i32) -> bool {
+        if !verify_indicator && allowed_errors.contains(&error) {
+            true
+        } else {
+            verify_indicator
+        }
     }
 
     pub async fn query(
         &mut self,
         query: &str,
         query_timeout: Duration,
+        default_backoff_interval: Duration,
+        max_backoff_interval: Duration,
+        max_retries: u32,
+        query_limiter: &QueryLimiter,
     ) -> Result<Vec<Row>, PsqlExporterError> {
         debug!("PostgresConnection::query: {query:?}");
 
-        let mut backoff_interval = self.default_backoff_interval;
+        let mut backoff_interval = default_backoff_interval;
         let mut sleeper = SleepHelper::from(self.shutdown_channel.clone());
+        let mut retries = 0;
 
         loop {
-            // Set statement timeout
-            let set_timeout_query = format!("set statement_timeout={};", query_timeout.as_millis());
-            let result = self.client.query(set_timeout_query.as_str(), &[]).await;
-            if let Err(e) = result {
-                error!("PostgresConnection::query: {e}");
-                if e.code().is_none() {
-                    debug!("PostgresConnection::query: try to reconnect after error");
-                    self.reconnect().await?;
-                } else {
-                    return Err(PsqlExporterError::PostgresQuery {
-                        query: set_timeout_query,
-                        cause: e,
-                    });
+            // Permit is held during a single attempt only, so unreachable databases don't hold permits
+            // while they reconnect and back off
+            let permit = query_limiter.acquire().await;
+            let result = self.query_attempt(query, query_timeout).await;
+            drop(permit);
+
+            match result? {
+                Ok(result) => {
+                    self.count_received(&result);
+                    return Ok(result);
                 }
-            } else {
-                // Execute actual query
-                let result = self.client.query(query, &[]).await;
-                if let Err(e) = result {
+                Err((failed_query, e)) => {
                     error!("PostgresConnection::query: {e}");
-                    if e.code().is_none() {
-                        debug!("PostgresConnection::query: try to reconnect after error");
-                        self.reconnect().await?;
-                    } else {
-                        return Err(PsqlExporterError::PostgresQuery {
-                            query: query.to_string(),
-                            cause: e,
-                        });
+                    if !self.should_reconnect(e.code()) {
+                        return Err(Self::query_error(failed_query, e));
                     }
-                } else {
-                    return Ok(result.unwrap());
+                    self.write_error_log(&failed_query, &e);
+                    debug!("PostgresConnection::query: try to reconnect after error");
+                    self.reconnect(max_retries).await?;
                 }
             }
 
+            retries = Self::count_retry(&self.db_connection_string, query, retries, max_retries)?;
             sleeper.sleep(backoff_interval).await?;
-            backoff_interval += self.default_backoff_interval;
-            if backoff_interval > self.max_backoff_interval {
-                backoff_interval = self.max_backoff_interval;
-            }
+            backoff_interval = Self::next_backoff_interval(
+                backoff_interval,
+                default_backoff_interval,
+                max_backoff_interval,
+            );
         }
     }
 
-    async fn reconnect(&mut self) -> Result<&Self, PsqlExporterError> {
+    // Single attempt without reconnects, failed statement is returned along with its error,
+    // so the caller decides whether to reconnect
+    async fn query_attempt(
+        &self,
+        query: &str,
+        query_timeout: Duration,
+    ) -> Result<Result<Vec<Row>, (String, tokio_postgres::Error)>, PsqlExporterError> {
+        // Single client-side deadline for all statements of the attempt
+        let deadline = Instant::now() + query_timeout;
+
+        if !self.db_connection_string.pooler.allows_session_settings() {
+            // Session-level SET doesn't persist in transaction pooling mode:
+            // statement timeout is passed via connection options and unnamed statements are used
+            let result =
+                Self::run_before_deadline(deadline, query, self.client.query_typed(query, &[]))
+                    .await?;
+            return Ok(result.map_err(|e| (query.to_string(), e)));
+        }
+
+        // Set statement timeout
+        let set_timeout_query = format!("set statement_timeout={};", query_timeout.as_millis());
+        let result = Self::run_before_deadline(
+            deadline,
+            &set_timeout_query,
+            self.client.query(set_timeout_query.as_str(), &[]),
+        )
+        .await?;
+        if let Err(e) = result {
+            return Ok(Err((set_timeout_query, e)));
+        }
+
+        // Execute actual query
+        let result =
+            Self::run_before_deadline(deadline, query, self.client.query(query, &[])).await?;
+        Ok(result.map_err(|e| (query.to_string(), e)))
+    }
+
+    // Zero max_retries means the query is retried after connection-level errors until success or shutdown
+    fn count_retry(
+        db_connection_string: &PostgresConnectionString,
+        query: &str,
+        retries: u32,
+        max_retries: u32,
+    ) -> Result<u32, PsqlExporterError> {
+        if max_retries > 0 && retries >= max_retries {
+            return Err(PsqlExporterError::QueryRetriesExhausted {
+                query: query.to_string(),
+                retries,
+            });
+        }
+        internal_metrics::count_query_retry(
+            &db_connection_string.host,
+            &db_connection_string.dbname,
+        );
+
+        Ok(retries + 1)
+    }
+
+    // Only values of columns are counted, so it's a lower estimate of the traffic
+    fn count_received(&self, rows: &[Row]) {
+        let bytes = rows
+            .iter()
+            .flat_map(|row| {
+                (0..row.len()).map(|index| {
+                    row.try_get::<_, RawSize>(index)
+                        .map(|size| size.0)
+                        .unwrap_or_default()
+                })
+            })
+            .sum();
+        internal_metrics::count_bytes_received(
+            &self.db_connection_string.host,
+            &self.db_connection_string.dbname,
+            bytes,
+        );
+    }
+
+    // Attributes are queried once per connection and cached until reconnect
+    pub async fn attributes(&mut self) -> Result<&ConnectionAttributes, PsqlExporterError> {
+        if self.attributes.is_none() {
+            let attributes = Self::run_before_deadline(
+                Instant::now() + CONNECTION_ATTRIBUTES_TIMEOUT,
+                CONNECTION_ATTRIBUTES_QUERY,
+                self.client.query_one(CONNECTION_ATTRIBUTES_QUERY, &[]),
+            )
+            .await?
+            .and_then(|row| {
+                Ok(ConnectionAttributes {
+                    server_version: row.try_get(0)?,
+                    backend_pid: row.try_get(1)?,
+                    in_recovery: row.try_get(2)?,
+                })
+            })
+            .map_err(|e| Self::query_error(CONNECTION_ATTRIBUTES_QUERY.to_string(), e))?;
+            internal_metrics::set_connection_info(
+                &self.db_connection_string.host,
+                &self.db_connection_string.dbname,
+                &Self::info_labels(&attributes),
+            );
+            self.attributes = Some(attributes);
+        }
+
+        Ok(self.attributes.as_ref().expect("looks like a BUG"))
+    }
+
+    fn info_labels(attributes: &ConnectionAttributes) -> [String; 3] {
+        [
+            attributes.server_version.clone(),
+            attributes.backend_pid.to_string(),
+            attributes.in_recovery.to_string(),
+        ]
+    }
+
+    fn forget_attributes(&mut self) {
+        if let Some(attributes) = self.attributes.take() {
+            internal_metrics::forget_connection_info(
+                &self.db_connection_string.host,
+                &self.db_connection_string.dbname,
+                &Self::info_labels(&attributes),
+            );
+        }
+    }
+
+    fn next_backoff_interval(
+        backoff_interval: Duration,
+        default_backoff_interval: Duration,
+        max_backoff_interval: Duration,
+    ) -> Duration {
+        (backoff_interval + default_backoff_interval).min(max_backoff_interval)
+    }
+
+    fn should_reconnect(&self, code: Option<&SqlState>) -> bool {
+        Self::is_reconnect_error_code(&self.reconnect_on_error_codes, code)
+    }
+
+    // Connection-level errors have no SQLSTATE code, so reconnect is needed anyway
+    fn is_reconnect_error_code(
+        reconnect_on_error_codes: &[String],
+        code: Option<&SqlState>,
+    ) -> bool {
+        match code {
+            None => true,
+            Some(code) => reconnect_on_error_codes.iter().any(|c| c == code.code()),
+        }
+    }
+
+    // Statement cancelled by the server-side statement_timeout is reported separately from other failures
+    fn query_error(query: String, cause: tokio_postgres::Error) -> PsqlExporterError {
+        if Self::is_statement_timeout(cause.code()) {
+            PsqlExporterError::StatementTimeout { query, cause }
+        } else {
+            PsqlExporterError::PostgresQuery { query, cause }
+        }
+    }
+
+    fn is_statement_timeout(code: Option<&SqlState>) -> bool {
+        code == Some(&SqlState::QUERY_CANCELED)
+    }
+
+    async fn run_before_deadline<T>(
+        deadline: Instant,
+        query: &str,
+        future: impl Future<Output = T>,
+    ) -> Result<T, PsqlExporterError> {
+        timeout_at(deadline, future)
+            .await
+            .map_err(|_| PsqlExporterError::QueryTimeout {
+                query: query.to_string(),
+            })
+    }
+
+    fn write_error_log(&self, query: &str, error: &tokio_postgres::Error) {
+        error_log::write(&ErrorRecord {
+            database: &self.db_connection_string.dbname,
+            query,
+            sqlstate: error.code().map(|c| c.code()),
+            error: &error.to_string(),
+        });
+    }
+
+    async fn reconnect(&mut self, max_attempts: u32) -> Result<&Self, PsqlExporterError> {
         debug!("PostgresConnection::reconnect: try to reconnect");
-        let new_connection = PostgresConnection::new(
+        internal_metrics::set_connection_status(
+            &self.db_connection_string.id(),
+            self.pool_index,
+            false,
+        );
+        self.forget_attributes();
+        let new_connection = PostgresConnection::connect(
             self.db_connection_string.clone(),
             self.sslmode.clone(),
+            self.verify_ca_allow.clone(),
+            self.reconnect_on_error_codes.clone(),
             self.certificates.clone(),
             self.default_backoff_interval,
             self.max_backoff_interval,
             self.shutdown_channel.clone(),
+            self.pool_index,
+            max_attempts,
         )
         .await;
 
@@ -345,3 +1152,929 @@ impl PostgresConnection {
         }
     }
 }
+
+// Server time is compared with the middle of the query round trip, positive skew means the DB clock is ahead
+fn clock_skew(server_time: f64, sent_at: SystemTime, received_at: SystemTime) -> f64 {
+    let epoch_seconds = |time: SystemTime| {
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64()
+    };
+
+    server_time - (epoch_seconds(sent_at) + epoch_seconds(received_at)) / 2.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::Private,
+        ssl::SslAcceptor,
+        x509::X509NameBuilder,
+    };
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+
+    fn self_signed_certificate() -> (PKey<Private>, X509) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+        (key, cert.build())
+    }
+
+    // Accepts single TLS connection with protocol versions up to max_version
+    fn tls_server(max_version: SslVersion) -> SocketAddr {
+        tls_server_with_certificate(max_version, self_signed_certificate())
+    }
+
+    fn tls_server_with_certificate(
+        max_version: SslVersion,
+        (key, cert): (PKey<Private>, X509),
+    ) -> SocketAddr {
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        acceptor.set_cipher_list("DEFAULT@SECLEVEL=0").unwrap();
+        acceptor
+            .set_min_proto_version(Some(SslVersion::TLS1))
+            .unwrap();
+        acceptor.set_max_proto_version(Some(max_version)).unwrap();
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            if let Ok((stream, _)) = listener.accept() {
+                let _ = acceptor.accept(stream);
+            }
+        });
+
+        addr
+    }
+
+    fn tls_handshake(server_max_version: SslVersion) -> bool {
+        let addr = tls_server(server_max_version);
+        let connector = PostgresConnection::build_ssl_connector(
+            &PostgresSslMode::Require,
+            &[],
+            SslProtocolVersion::default(),
+            &PostgresSslCertificates::from(None, None, None).unwrap(),
+        )
+        .unwrap();
+
+        connector
+            .connect("localhost", TcpStream::connect(addr).unwrap())
+            .is_ok()
+    }
+
+    #[test]
+    fn tls_1_0_only_server_is_rejected() {
+        assert!(!tls_handshake(SslVersion::TLS1));
+        assert!(tls_handshake(SslVersion::TLS1_2));
+    }
+
+    fn connector_with(
+        certificates: PostgresSslCertificates,
+    ) -> Result<SslConnector, PsqlExporterError> {
+        PostgresConnection::build_ssl_connector(
+            &PostgresSslMode::Require,
+            &[],
+            SslProtocolVersion::default(),
+            &certificates,
+        )
+    }
+
+    #[test]
+    fn inline_pem_certificates() {
+        let (key, cert) = self_signed_certificate();
+        let cert = String::from_utf8(cert.to_pem().unwrap()).unwrap();
+        let key = String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+        let certificates = PostgresSslCertificates::from(
+            Some(PemSource::Inline(cert.clone())),
+            Some(PemSource::Inline(format!("{cert}{cert}"))),
+            Some(PemSource::Inline(key.clone())),
+        )
+        .unwrap();
+        assert!(connector_with(certificates).is_ok());
+
+        let garbage = PemSource::Inline(String::from("not a PEM"));
+        for certificates in [
+            PostgresSslCertificates::from(Some(garbage.clone()), None, None),
+            PostgresSslCertificates::from(
+                None,
+                Some(garbage.clone()),
+                Some(PemSource::Inline(key.clone())),
+            ),
+            PostgresSslCertificates::from(
+                None,
+                Some(PemSource::Inline(cert.clone())),
+                Some(garbage.clone()),
+            ),
+        ] {
+            let err = connector_with(certificates.unwrap()).unwrap_err();
+            assert!(err.to_string().contains("<inline PEM>"), "{err}");
+            assert!(!err.to_string().contains("not a PEM"), "{err}");
+        }
+
+        // inline content never leaks to logs
+        assert_eq!(format!("{:?}", PemSource::Inline(key)), "Inline(***)");
+    }
+
+    // Empty CRL of some other CA, so the server certificate issuer has no CRL
+    const OTHER_CA_CRL: &str = "-----BEGIN X509 CRL-----
+MIGsMFMCAQEwCgYIKoZIzj0EAwIwEjEQMA4GA1UEAwwHdGVzdC1jYRcNMjYxMDE2
+MTA0MTEzWhgPMjEyNjA5MjIxMDQxMTNaoA4wDDAKBgNVHRQEAwIBATAKBggqhkjO
+PQQDAgNJADBGAiEA6bfIedAEK26GQdtV95OKMwZ8bVI0k+zjnZiuayF1k84CIQDk
+839R//VC4AoAmdQVv24gI95Jp4XnQFi70VorItBQYw==
+-----END X509 CRL-----
+";
+
+    #[test]
+    fn crl_check_in_verify_modes() {
+        let dir = std::env::temp_dir();
+        let crl_file = dir.join(format!(
+            "psql-query-exporter-crl-{}.pem",
+            std::process::id()
+        ));
+        let garbage_file = dir.join(format!(
+            "psql-query-exporter-bad-crl-{}.pem",
+            std::process::id()
+        ));
+        std::fs::write(&crl_file, OTHER_CA_CRL).unwrap();
+        std::fs::write(&garbage_file, "not a CRL").unwrap();
+        let path = |file: &std::path::Path| Some(file.to_str().unwrap().to_string());
+
+        let (key, cert) = self_signed_certificate();
+        let rootcert = PemSource::Inline(String::from_utf8(cert.to_pem().unwrap()).unwrap());
+        let handshake = |crl: Option<String>| {
+            let addr = tls_server_with_certificate(SslVersion::TLS1_3, (key.clone(), cert.clone()));
+            let certificates = PostgresSslCertificates::from(Some(rootcert.clone()), None, None)
+                .unwrap()
+                .with_crl(crl);
+            let connector = PostgresConnection::build_ssl_connector(
+                &PostgresSslMode::VerifyFull,
+                &[],
+                SslProtocolVersion::default(),
+                &certificates,
+            )
+            .unwrap();
+            connector
+                .connect("localhost", TcpStream::connect(addr).unwrap())
+                .is_ok()
+        };
+        assert!(handshake(None));
+        // CRL check is enabled, so the server certificate can't be verified without the CRL of its issuer
+        assert!(!handshake(path(&crl_file)));
+
+        let certificates = |crl| {
+            PostgresSslCertificates::from(None, None, None)
+                .unwrap()
+                .with_crl(crl)
+        };
+        let err = PostgresConnection::check_tls_config(
+            &PostgresSslMode::VerifyCa,
+            &[],
+            SslProtocolVersion::default(),
+            &certificates(path(&garbage_file)),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err, PsqlExporterError::PostgresTlsCrl { .. }),
+            "{err}"
+        );
+        // server certificate isn't verified in require mode, so CRL isn't even loaded
+        assert!(PostgresConnection::check_tls_config(
+            &PostgresSslMode::Require,
+            &[],
+            SslProtocolVersion::default(),
+            &certificates(path(&garbage_file)),
+        )
+        .is_ok());
+
+        std::fs::remove_file(&crl_file).unwrap();
+        std::fs::remove_file(&garbage_file).unwrap();
+    }
+
+    fn allowed_errors(allowances: &[VerifyCaAllowance]) -> Vec<i32> {
+        allowances.iter().map(|a| a.x509_error()).collect()
+    }
+
+    #[test]
+    fn verify_ca_hostname_mismatch() {
+        let hostname_mismatch = openssl_sys::X509_V_ERR_HOSTNAME_MISMATCH;
+
+        let default_set = allowed_errors(&VerifyCaAllowance::default_set());
+        assert!(PostgresConnection::verify_ca_result(
+            &default_set,
+            false,
+            hostname_mismatch
+        ));
+
+        let hostname_and_ip = allowed_errors(&[VerifyCaAllowance::Hostname, VerifyCaAllowance::Ip]);
+        assert!(PostgresConnection::verify_ca_result(
+            &hostname_and_ip,
+            false,
+            hostname_mismatch
+        ));
+        assert!(!PostgresConnection::verify_ca_result(
+            &hostname_and_ip,
+            false,
+            openssl_sys::X509_V_ERR_EMAIL_MISMATCH
+        ));
+
+        let email_only = allowed_errors(&[VerifyCaAllowance::Email]);
+        assert!(!PostgresConnection::verify_ca_result(
+            &email_only,
+            false,
+            hostname_mismatch
+        ));
+
+        assert!(!PostgresConnection::verify_ca_result(
+            &[],
+            false,
+            hostname_mismatch
+        ));
+    }
+
+    #[test]
+    fn pgbouncer_pooler_disables_session_settings() {
+        let mut conn_string = PostgresConnectionString {
+            host: String::from("localhost"),
+            dbname: String::from("postgres"),
+            statement_timeout: Duration::from_secs(5),
+            ..Default::default()
+        };
+
+        assert!(conn_string.pooler.allows_session_settings());
+        assert!(!conn_string.get_conn_string().contains("options="));
+
+        conn_string.pooler = PostgresPooler::Pgbouncer;
+        assert!(!conn_string.pooler.allows_session_settings());
+        assert!(conn_string
+            .get_conn_string()
+            .ends_with(" options='-c statement_timeout=5000'"));
+    }
+
+    #[test]
+    fn preferred_ip_family_goes_first() {
+        let v4: IpAddr = "192.0.2.10".parse().unwrap();
+        let v6: IpAddr = "2001:db8::10".parse().unwrap();
+        let v6_2: IpAddr = "2001:db8::20".parse().unwrap();
+
+        let mut addresses = vec![v6, v4, v6_2];
+        IpFamily::Ipv4.order_addresses(&mut addresses);
+        assert_eq!(addresses, vec![v4, v6, v6_2]);
+
+        let mut addresses = vec![v4, v6, v6_2];
+        IpFamily::Ipv6.order_addresses(&mut addresses);
+        assert_eq!(addresses, vec![v6, v6_2, v4]);
+
+        let mut addresses = vec![v6, v4];
+        IpFamily::Any.order_addresses(&mut addresses);
+        assert_eq!(addresses, vec![v6, v4]);
+    }
+
+    #[test]
+    fn dual_stack_host_with_connect_timeout() {
+        let conn_string = PostgresConnectionString {
+            host: String::from("db.example.com"),
+            dbname: String::from("postgres"),
+            user: String::from("postgres"),
+            ip_family: IpFamily::Ipv4,
+            connect_timeout: Duration::from_secs(3),
+            ..Default::default()
+        };
+
+        // IPv6 is black-holed: IPv4 address is tried first, and each attempt is limited by connect_timeout
+        let mut addresses: Vec<IpAddr> = vec![
+            "2001:db8::10".parse().unwrap(),
+            "192.0.2.10".parse().unwrap(),
+        ];
+        conn_string.ip_family.order_addresses(&mut addresses);
+        let config: tokio_postgres::Config = conn_string
+            .get_conn_string_with_addresses(&addresses)
+            .parse()
+            .unwrap();
+
+        assert_eq!(config.get_hostaddrs(), addresses.as_slice());
+        assert_eq!(config.get_hosts().len(), 2);
+        assert_eq!(config.get_connect_timeout(), Some(&Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn escape_password_with_quote_and_backslash() {
+        let conn_string = PostgresConnectionString {
+            host: String::from("localhost"),
+            dbname: String::from("my db"),
+            user: String::from("postgres"),
+            password: String::from(r"pa'ss\word"),
+            ..Default::default()
+        };
+
+        let config: tokio_postgres::Config = conn_string.get_conn_string().parse().unwrap();
+        assert_eq!(config.get_password(), Some(r"pa'ss\word".as_bytes()));
+        assert_eq!(config.get_dbname(), Some("my db"));
+        assert_eq!(config.get_user(), Some("postgres"));
+    }
+
+    #[tokio::test]
+    async fn connect_via_bootstrap_database() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (startup_tx, startup_rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut len = [0u8; 4];
+            stream.read_exact(&mut len).unwrap();
+            let mut startup = vec![0u8; u32::from_be_bytes(len) as usize - 4];
+            stream.read_exact(&mut startup).unwrap();
+            startup_tx.send(startup).unwrap();
+            // AuthenticationOk and ReadyForQuery(idle)
+            stream
+                .write_all(&[b'R', 0, 0, 0, 8, 0, 0, 0, 0, b'Z', 0, 0, 0, 5, b'I'])
+                .unwrap();
+            let _ = stream.read_to_end(&mut vec![]);
+        });
+        let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        let conn_string = PostgresConnectionString {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            dbname: String::from("target-db"),
+            connect_dbname: Some(String::from("bootstrap")),
+            user: String::from("postgres"),
+            sslmode: PostgresSslMode::Disable,
+            ..Default::default()
+        };
+        assert!(conn_string.id().ends_with("/target-db"));
+        assert!(conn_string.to_string().contains("dbname='bootstrap'"));
+
+        let connection = PostgresConnection::new(
+            conn_string,
+            PostgresSslMode::Disable,
+            vec![],
+            vec![],
+            PostgresSslCertificates::from(None, None, None).unwrap(),
+            Duration::from_millis(10),
+            Duration::from_millis(10),
+            shutdown_rx,
+            0,
+        )
+        .await
+        .unwrap();
+
+        // parameters of the startup message are null-terminated name/value pairs after the protocol version
+        let startup = startup_rx.recv().unwrap();
+        let parameters: Vec<String> = startup[4..]
+            .split(|b| *b == 0)
+            .map(|p| String::from_utf8_lossy(p).to_string())
+            .collect();
+        let database = parameters
+            .iter()
+            .position(|p| p == "database")
+            .map(|i| parameters[i + 1].as_str());
+        assert_eq!(database, Some("bootstrap"));
+        // but the connection is exposed under the target name
+        assert!(
+            internal_metrics::CONNECT_DURATION
+                .get_metric_with_label_values(&["127.0.0.1", "target-db"])
+                .unwrap()
+                .get()
+                > 0.0
+        );
+        drop(connection);
+    }
+
+    #[test]
+    fn latin1_client_encoding() {
+        let encoding = ClientEncoding::Latin1;
+        assert_eq!(
+            encoding.set_statement(),
+            Some("SET client_encoding TO 'LATIN1'")
+        );
+        assert_eq!(encoding.decode(b"M\xfcnchen"), "München");
+        assert_eq!(encoding.decode(b"caf\xe9"), "café");
+
+        let encoding = ClientEncoding::default();
+        assert_eq!(encoding.set_statement(), None);
+        assert_eq!(encoding.decode("München".as_bytes()), "München");
+    }
+
+    #[test]
+    fn received_bytes_grow_with_result_size() {
+        let result_size = |values: &[Option<&[u8]>]| -> usize {
+            values
+                .iter()
+                .map(|value| match value {
+                    Some(raw) => RawSize::from_sql(&Type::TEXT, raw).unwrap().0,
+                    None => RawSize::from_sql_null(&Type::TEXT).unwrap().0,
+                })
+                .sum()
+        };
+        let received = || {
+            internal_metrics::DB_BYTES_RECEIVED_TOTAL
+                .with_label_values(&["received-host", "app"])
+                .get()
+        };
+
+        let small = result_size(&[Some(b"slot_1"), Some(&42i64.to_be_bytes()), None]);
+        assert_eq!(small, 14);
+        internal_metrics::count_bytes_received("received-host", "app", small);
+        let after_small = received();
+
+        let large_value = vec![b'x'; 4096];
+        let large = result_size(&[Some(&large_value), Some(&large_value), None]);
+        internal_metrics::count_bytes_received("received-host", "app", large);
+        assert_eq!(received() - after_small, 8192);
+        assert!(received() - after_small > after_small);
+    }
+
+    #[test]
+    fn sslmode_strings() {
+        let modes = [
+            (PostgresSslMode::Disable, "disable", "disable"),
+            (PostgresSslMode::Prefer, "prefer", "prefer"),
+            (PostgresSslMode::Require, "require", "require"),
+            (PostgresSslMode::VerifyCa, "require", "verify-ca"),
+            (PostgresSslMode::VerifyFull, "require", "verify-full"),
+        ];
+        for (mode, conn_string_mode, configured_mode) in modes {
+            assert_eq!(mode.to_string(), conn_string_mode);
+            assert_eq!(mode.as_str(), configured_mode);
+            assert!(format!("host=localhost sslmode={mode}")
+                .parse::<tokio_postgres::Config>()
+                .is_ok());
+        }
+
+        // that's why verify modes are passed to the driver as require
+        for mode in ["verify-ca", "verify-full"] {
+            assert!(format!("host=localhost sslmode={mode}")
+                .parse::<tokio_postgres::Config>()
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn sslmode_metric_of_verify_full_connection() {
+        internal_metrics::set_connection_sslmode(
+            "verify-full.example.com",
+            "app",
+            PostgresSslMode::VerifyFull.as_str(),
+            true,
+        );
+
+        let families = prometheus::gather();
+        let metric = families
+            .iter()
+            .find(|f| f.get_name() == "psql_exporter_connection_sslmode")
+            .expect("sslmode metric should be registered")
+            .get_metric()
+            .iter()
+            .find(|m| {
+                m.get_label()
+                    .iter()
+                    .any(|l| l.get_name() == "host" && l.get_value() == "verify-full.example.com")
+            })
+            .expect("connection should be present");
+        let labels: Vec<(&str, &str)> = metric
+            .get_label()
+            .iter()
+            .map(|l| (l.get_name(), l.get_value()))
+            .collect();
+        assert_eq!(
+            labels,
+            vec![
+                ("dbname", "app"),
+                ("host", "verify-full.example.com"),
+                ("mode", "verify-full")
+            ]
+        );
+        assert_eq!(metric.get_gauge().get_value(), 1.0);
+    }
+
+    #[test]
+    fn query_backoff_pacing() {
+        let pacing = |backoff_interval: u64, max_backoff_interval: u64| {
+            let mut interval = Duration::from_secs(backoff_interval);
+            let mut intervals = vec![interval];
+            for _ in 0..4 {
+                interval = PostgresConnection::next_backoff_interval(
+                    interval,
+                    Duration::from_secs(backoff_interval),
+                    Duration::from_secs(max_backoff_interval),
+                );
+                intervals.push(interval);
+            }
+            intervals
+                .iter()
+                .map(Duration::as_secs)
+                .collect::<Vec<u64>>()
+        };
+
+        assert_eq!(pacing(1, 3), vec![1, 2, 3, 3, 3]);
+        assert_eq!(pacing(10, 60), vec![10, 20, 30, 40, 50]);
+    }
+
+    #[test]
+    fn reconnect_on_admin_shutdown() {
+        let codes: Vec<String> = vec![String::from("57P01"), String::from("08006")];
+
+        assert!(PostgresConnection::is_reconnect_error_code(
+            &codes,
+            Some(&SqlState::ADMIN_SHUTDOWN)
+        ));
+        assert!(PostgresConnection::is_reconnect_error_code(
+            &codes,
+            Some(&SqlState::CONNECTION_FAILURE)
+        ));
+        assert!(!PostgresConnection::is_reconnect_error_code(
+            &codes,
+            Some(&SqlState::SYNTAX_ERROR)
+        ));
+        assert!(PostgresConnection::is_reconnect_error_code(&codes, None));
+        assert!(!PostgresConnection::is_reconnect_error_code(
+            &[],
+            Some(&SqlState::ADMIN_SHUTDOWN)
+        ));
+    }
+
+    #[test]
+    fn statement_timeout_is_detected() {
+        assert!(PostgresConnection::is_statement_timeout(Some(
+            &SqlState::QUERY_CANCELED
+        )));
+        assert_eq!(SqlState::QUERY_CANCELED.code(), "57014");
+        assert!(!PostgresConnection::is_statement_timeout(Some(
+            &SqlState::SYNTAX_ERROR
+        )));
+        assert!(!PostgresConnection::is_statement_timeout(Some(
+            &SqlState::ADMIN_SHUTDOWN
+        )));
+        assert!(!PostgresConnection::is_statement_timeout(None));
+    }
+
+    #[tokio::test]
+    async fn deadline_covers_all_statements() {
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(100);
+
+        let first = PostgresConnection::run_before_deadline(
+            deadline,
+            "set statement_timeout=100;",
+            tokio::time::sleep(Duration::from_millis(60)),
+        )
+        .await;
+        assert!(first.is_ok());
+
+        let second = PostgresConnection::run_before_deadline(
+            deadline,
+            "select pg_sleep(1);",
+            tokio::time::sleep(Duration::from_millis(60)),
+        )
+        .await;
+        assert!(matches!(
+            second,
+            Err(PsqlExporterError::QueryTimeout { query }) if query == "select pg_sleep(1);"
+        ));
+        assert!(started.elapsed() < Duration::from_millis(120));
+    }
+
+    // Accepts plain connections and completes startup without authentication, queries are never answered
+    fn fake_postgres_server(connections: usize) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            for _ in 0..connections {
+                let (mut stream, _) = listener.accept().unwrap();
+                std::thread::spawn(move || {
+                    let mut len = [0u8; 4];
+                    stream.read_exact(&mut len).unwrap();
+                    let mut startup = vec![0u8; u32::from_be_bytes(len) as usize - 4];
+                    stream.read_exact(&mut startup).unwrap();
+
+                    // AuthenticationOk and ReadyForQuery(idle)
+                    stream
+                        .write_all(&[b'R', 0, 0, 0, 8, 0, 0, 0, 0, b'Z', 0, 0, 0, 5, b'I'])
+                        .unwrap();
+                    let _ = stream.read_to_end(&mut vec![]);
+                });
+            }
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn connect_duration_is_exposed() {
+        let addr = fake_postgres_server(1);
+        let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        let conn_string = PostgresConnectionString {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            dbname: String::from("connect-duration"),
+            user: String::from("postgres"),
+            sslmode: PostgresSslMode::Disable,
+            ..Default::default()
+        };
+
+        let connection = PostgresConnection::new(
+            conn_string,
+            PostgresSslMode::Disable,
+            vec![],
+            vec![],
+            PostgresSslCertificates::from(None, None, None).unwrap(),
+            Duration::from_millis(10),
+            Duration::from_millis(10),
+            shutdown_rx,
+            0,
+        )
+        .await
+        .unwrap();
+
+        let duration = internal_metrics::CONNECT_DURATION
+            .get_metric_with_label_values(&["127.0.0.1", "connect-duration"])
+            .unwrap()
+            .get();
+        assert!(duration > 0.0 && duration < 5.0);
+        drop(connection);
+    }
+
+    #[tokio::test]
+    async fn connection_attributes_are_cached_until_reconnect() {
+        let addr = fake_postgres_server(2);
+        let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        let conn_string = PostgresConnectionString {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            dbname: String::from("cached-attributes"),
+            user: String::from("postgres"),
+            sslmode: PostgresSslMode::Disable,
+            ..Default::default()
+        };
+        let mut connection = PostgresConnection::new(
+            conn_string,
+            PostgresSslMode::Disable,
+            vec![],
+            vec![],
+            PostgresSslCertificates::from(None, None, None).unwrap(),
+            Duration::from_millis(10),
+            Duration::from_millis(10),
+            shutdown_rx,
+            0,
+        )
+        .await
+        .unwrap();
+
+        let attributes = ConnectionAttributes {
+            server_version: String::from("17.2"),
+            backend_pid: 4242,
+            in_recovery: false,
+        };
+        let labels = ["127.0.0.1", "cached-attributes", "17.2", "4242", "false"];
+        internal_metrics::set_connection_info(
+            "127.0.0.1",
+            "cached-attributes",
+            &PostgresConnection::info_labels(&attributes),
+        );
+        connection.attributes = Some(attributes.clone());
+
+        // fake server never answers, so only cached value can be returned in time
+        let cached = tokio::time::timeout(Duration::from_millis(200), connection.attributes())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(cached, &attributes);
+
+        connection.reconnect(1).await.unwrap();
+        assert!(connection.attributes.is_none());
+        assert!(internal_metrics::CONNECTION_INFO
+            .remove_label_values(&labels)
+            .is_err());
+    }
+
+    #[test]
+    fn query_retries_limit() {
+        let conn_string = PostgresConnectionString {
+            host: String::from("retries-host"),
+            dbname: String::from("app"),
+            ..Default::default()
+        };
+        let retries_total = || {
+            internal_metrics::QUERY_RETRIES_TOTAL
+                .with_label_values(&["retries-host", "app"])
+                .get()
+        };
+
+        let mut retries = 0;
+        for _ in 0..3 {
+            retries =
+                PostgresConnection::count_retry(&conn_string, "select 1", retries, 3).unwrap();
+        }
+        assert_eq!(retries_total(), 3);
+        let err =
+            PostgresConnection::count_retry(&conn_string, "select 1", retries, 3).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "query has failed after 3 retries 'select 1'"
+        );
+        assert_eq!(retries_total(), 3);
+
+        // zero limit means retries without limit
+        assert_eq!(
+            PostgresConnection::count_retry(&conn_string, "select 1", 1000, 0).unwrap(),
+            1001
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnect_attempts_limit() {
+        // nothing listens on the port after the listener is dropped
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        let conn_string = PostgresConnectionString {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            dbname: String::from("unreachable"),
+            user: String::from("postgres"),
+            sslmode: PostgresSslMode::Disable,
+            ..Default::default()
+        };
+
+        let result = PostgresConnection::connect(
+            conn_string,
+            PostgresSslMode::Disable,
+            vec![],
+            vec![],
+            PostgresSslCertificates::from(None, None, None).unwrap(),
+            Duration::from_millis(10),
+            Duration::from_millis(10),
+            shutdown_rx,
+            0,
+            2,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(PsqlExporterError::ConnectAttemptsExhausted { dbname, attempts: 2 }) if dbname == "unreachable"
+        ));
+    }
+
+    #[tokio::test]
+    async fn connect_timeout_bounds_handshake() {
+        // TCP connection is accepted, but the server never answers the startup message
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let _connections: Vec<TcpStream> = listener.incoming().flatten().collect();
+        });
+        let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        let conn_string = PostgresConnectionString {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            dbname: String::from("hanging"),
+            user: String::from("postgres"),
+            sslmode: PostgresSslMode::Disable,
+            connect_timeout: Duration::from_secs(1),
+            ..Default::default()
+        };
+
+        let started = Instant::now();
+        let result = PostgresConnection::connect(
+            conn_string,
+            PostgresSslMode::Disable,
+            vec![],
+            vec![],
+            PostgresSslCertificates::from(None, None, None).unwrap(),
+            Duration::from_millis(10),
+            Duration::from_millis(10),
+            shutdown_rx,
+            0,
+            2,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(PsqlExporterError::ConnectAttemptsExhausted { attempts: 2, .. })
+        ));
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "{:?}",
+            started.elapsed()
+        );
+    }
+
+    #[tokio::test]
+    async fn down_database_does_not_hold_query_permit() {
+        // the only connection is closed shortly after the handshake, so the DB is down for good after that
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            drop(listener);
+            let mut length = [0u8; 4];
+            stream.read_exact(&mut length).unwrap();
+            let mut startup = vec![0u8; u32::from_be_bytes(length) as usize - 4];
+            stream.read_exact(&mut startup).unwrap();
+            // AuthenticationOk and ReadyForQuery
+            stream
+                .write_all(&[b'R', 0, 0, 0, 8, 0, 0, 0, 0, b'Z', 0, 0, 0, 5, b'I'])
+                .unwrap();
+            std::thread::sleep(Duration::from_millis(200));
+        });
+        let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        let conn_string = PostgresConnectionString {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            dbname: String::from("down"),
+            user: String::from("postgres"),
+            sslmode: PostgresSslMode::Disable,
+            ..Default::default()
+        };
+        let mut connection = PostgresConnection::connect(
+            conn_string,
+            PostgresSslMode::Disable,
+            vec![],
+            vec![],
+            PostgresSslCertificates::from(None, None, None).unwrap(),
+            Duration::from_millis(10),
+            Duration::from_millis(50),
+            shutdown_rx,
+            0,
+            1,
+        )
+        .await
+        .unwrap();
+
+        // zero max_retries: the query of the down DB reconnects until shutdown
+        let limiter = QueryLimiter::new(1);
+        let down_query = connection.query(
+            "select 1",
+            Duration::from_secs(5),
+            Duration::from_millis(10),
+            Duration::from_millis(50),
+            0,
+            &limiter,
+        );
+        // query of another DB gets the single permit meanwhile
+        let other_query = async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            tokio::time::timeout(Duration::from_secs(1), limiter.acquire())
+                .await
+                .is_ok()
+        };
+
+        tokio::select! {
+            result = down_query => panic!("query of the down DB has finished: {result:?}"),
+            acquired = other_query => assert!(acquired),
+        }
+    }
+
+    #[test]
+    fn skewed_server_clock() {
+        let sent_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let received_at = sent_at + Duration::from_millis(200);
+
+        // server is 1 minute ahead, its time is taken in the middle of the round trip
+        let skew = clock_skew(1_700_000_060.1, sent_at, received_at);
+        assert!((skew - 60.0).abs() < 1e-6, "{skew}");
+        let skew = clock_skew(1_699_999_990.1, sent_at, received_at);
+        assert!((skew + 10.0).abs() < 1e-6, "{skew}");
+        assert!(clock_skew(1_700_000_000.1, sent_at, received_at).abs() < 1e-6);
+
+        internal_metrics::set_clock_skew("skewed-host", "app", 60.0);
+        let exposed = internal_metrics::CLOCK_SKEW
+            .get_metric_with_label_values(&["skewed-host", "app"])
+            .unwrap()
+            .get();
+        assert_eq!(exposed, 60.0);
+    }
+
+    #[test]
+    fn verify_ca_never_rejects_verified_cert() {
+        assert!(PostgresConnection::verify_ca_result(&[], true, 0));
+    }
+}
@@ -0,0 +1,140 @@
+use crate::errors::PsqlExporterError;
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+    time::SystemTime,
+};
+use tracing::error;
+
+static ERROR_LOG: OnceLock<ErrorLog> = OnceLock::new();
+
+#[derive(Debug)]
+pub struct ErrorRecord<'a> {
+    pub database: &'a str,
+    pub query: &'a str,
+    pub sqlstate: Option<&'a str>,
+    pub error: &'a str,
+}
+
+#[derive(Debug)]
+pub struct ErrorLog {
+    path: PathBuf,
+    max_size: u64,
+    file: Mutex<File>,
+}
+
+impl ErrorLog {
+    pub fn new(path: impl Into<PathBuf>, max_size: u64) -> Result<Self, PsqlExporterError> {
+        let path = path.into();
+        let file = Self::open(&path)?;
+
+        Ok(Self {
+            path,
+            max_size,
+            file: Mutex::new(file),
+        })
+    }
+
+    fn open(path: &PathBuf) -> Result<File, PsqlExporterError> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| PsqlExporterError::ErrorLogFile {
+                filename: path.display().to_string(),
+                cause: e,
+            })
+    }
+
+    pub fn write(&self, record: &ErrorRecord) -> Result<(), PsqlExporterError> {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let line = format!(
+            "ts={timestamp:.3} database={:?} sqlstate={} error={:?} query={:?}\n",
+            record.database,
+            record.sqlstate.unwrap_or("-"),
+            record.error,
+            record.query
+        );
+
+        let mut file = self
+            .file
+            .lock()
+            .unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
+        let map_err = |e| PsqlExporterError::ErrorLogFile {
+            filename: self.path.display().to_string(),
+            cause: e,
+        };
+
+        let size = file.metadata().map_err(map_err)?.len();
+        if self.max_size > 0 && size + line.len() as u64 > self.max_size {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            fs::rename(&self.path, rotated).map_err(map_err)?;
+            *file = Self::open(&self.path)?;
+        }
+
+        file.write_all(line.as_bytes()).map_err(map_err)
+    }
+}
+
+pub fn init(path: &str, max_size: u64) -> Result<(), PsqlExporterError> {
+    let error_log = ErrorLog::new(path, max_size)?;
+    ERROR_LOG
+        .set(error_log)
+        .unwrap_or_else(|_| panic!("looks like a BUG: error log is already initialized"));
+
+    Ok(())
+}
+
+pub fn write(record: &ErrorRecord) {
+    if let Some(error_log) = ERROR_LOG.get() {
+        if let Err(e) = error_log.write(record) {
+            error!("{e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failure_writes_record() {
+        let path = std::env::temp_dir().join(format!(
+            "psql-query-exporter-error-log-{}.log",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+
+        let error_log = ErrorLog::new(&path, 150).unwrap();
+        let record = ErrorRecord {
+            database: "postgres",
+            query: "selec 1",
+            sqlstate: Some("42601"),
+            error: "syntax error",
+        };
+        error_log.write(&record).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with("ts="));
+        assert!(content.ends_with(
+            " database=\"postgres\" sqlstate=42601 error=\"syntax error\" query=\"selec 1\"\n"
+        ));
+
+        // the second record exceeds the size limit, so the file is rotated
+        error_log.write(&record).unwrap();
+        let mut rotated = path.clone().into_os_string();
+        rotated.push(".1");
+        assert_eq!(fs::read_to_string(&rotated).unwrap(), content);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&rotated);
+    }
+}
@@ -1,11 +1,16 @@
+use figment::error::Kind;
 use std::{env, io};
 use thiserror::Error;
 
+const MAX_SUGGESTION_DISTANCE: usize = 3;
+
 #[derive(Error)]
 pub enum PsqlExporterError {
     #[error("unable to load config file '{}': {}", .filename, .cause)]
     LoadConfigFile { filename: String, cause: io::Error },
-    #[error("unable to parse config: {}", .cause.kind)]
+    #[error("unable to load password file '{}': {}", .filename, .cause)]
+    LoadPasswordFile { filename: String, cause: io::Error },
+    #[error("unable to parse config{}: {}{}", config_error_path(.cause), .cause.kind, config_error_hint(.cause))]
     ParseConfigFile {
         #[from]
         cause: figment::Error,
@@ -15,11 +20,37 @@ pub enum PsqlExporterError {
         variable: String,
         cause: env::VarError,
     },
+    #[error("unable to parse boolean value '{}' of '{}'", .value, .field)]
+    ParseBooleanValue { field: String, value: String },
+    #[error("invalid value '{}' of '{}': {}", .value, .field, .reason)]
+    InvalidConfigValue {
+        field: String,
+        value: String,
+        reason: String,
+    },
+    #[error("unable to read value of column '{}' (type OID {}) in row {}: {}", .column, .oid, .row, .cause)]
+    InvalidFieldValue {
+        column: String,
+        oid: u32,
+        row: usize,
+        cause: tokio_postgres::Error,
+    },
     #[error("query failed '{}': {}", .query, .cause)]
     PostgresQuery {
         query: String,
         cause: tokio_postgres::Error,
     },
+    #[error("query has failed after {} retries '{}'", .retries, .query)]
+    QueryRetriesExhausted { query: String, retries: u32 },
+    #[error("unable to reconnect to database '{}' after {} attempts", .dbname, .attempts)]
+    ConnectAttemptsExhausted { dbname: String, attempts: u32 },
+    #[error("query timeout has been reached '{}'", .query)]
+    QueryTimeout { query: String },
+    #[error("statement timeout has been reached '{}': {}", .query, .cause)]
+    StatementTimeout {
+        query: String,
+        cause: tokio_postgres::Error,
+    },
     #[error("unable to create TLS connector: {}", .0)]
     PostgresTlsConnector(openssl::error::ErrorStack),
     #[error("unable to load CA certificate '{}': {}", .rootcert, .cause)]
@@ -32,6 +63,11 @@ pub enum PsqlExporterError {
         filename: String,
         cause: openssl::error::ErrorStack,
     },
+    #[error("unable to load CRL '{}': {}", .crl, .cause)]
+    PostgresTlsCrl {
+        crl: String,
+        cause: openssl::error::ErrorStack,
+    },
     #[error("TLS client config error: {}", .0)]
     PostgresTlsClientConfig(String),
     #[error("shutdown signal has been received during operation")]
@@ -41,10 +77,64 @@ pub enum PsqlExporterError {
         metric: String,
         cause: prometheus::Error,
     },
+    #[error("query of metric '{}' returned duplicate labels {:?}", .metric, .labels)]
+    DuplicateLabels { metric: String, labels: Vec<String> },
+    #[error("query of metric '{}' returned duplicate columns {:?}, use aliases to make their names unique", .metric, .columns)]
+    DuplicateColumns {
+        metric: String,
+        columns: Vec<String>,
+    },
+    #[error("unable to write error log file '{}': {}", .filename, .cause)]
+    ErrorLogFile { filename: String, cause: io::Error },
+    #[error("unable to read/write state file '{}': {}", .filename, .cause)]
+    StateFile { filename: String, cause: io::Error },
+    #[error("unable to start collector thread '{}': {}", .thread, .cause)]
+    SpawnCollectorThread { thread: String, cause: io::Error },
     #[error("unable to send task completion status: {}", .0)]
     MetricsBackStatusSend(#[from] tokio::sync::mpsc::error::SendError<usize>),
 }
 
+// Path to the broken key points to the exact section (and variant of the values) with an error
+fn config_error_path(error: &figment::Error) -> String {
+    if error.path.is_empty() {
+        String::new()
+    } else {
+        format!(" at '{}'", error.path.join("."))
+    }
+}
+
+// Typos in keys are suggested to be replaced with the closest expected key
+fn config_error_hint(error: &figment::Error) -> String {
+    let (found, expected) = match &error.kind {
+        Kind::UnknownField(found, expected) | Kind::UnknownVariant(found, expected) => {
+            (found, expected)
+        }
+        _ => return String::new(),
+    };
+
+    expected
+        .iter()
+        .map(|candidate| (edit_distance(found, candidate), candidate))
+        .filter(|(distance, _)| *distance <= MAX_SUGGESTION_DISTANCE)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| format!(", did you mean `{candidate}`?"))
+        .unwrap_or_default()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
 impl std::fmt::Debug for PsqlExporterError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self)
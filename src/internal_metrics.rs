@@ -0,0 +1,540 @@
+use crate::query_tags::QueryTags;
+use prometheus::{
+    register_gauge, register_gauge_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge_vec, Gauge, GaugeVec, IntCounter, IntCounterVec, IntGaugeVec,
+};
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, SystemTime},
+};
+
+const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub static BUILD_INFO: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "psql_exporter_build_info",
+        "Build information of the exporter",
+        &["version"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static PROCESS_START_TIME: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "process_start_time_seconds",
+        "Start time of the process since unix epoch in seconds"
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static READINESS_RATIO: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "psql_exporter_readiness_ratio",
+        "Ratio of connected databases to all configured databases"
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static CONFIG_PARSE_DURATION: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "psql_exporter_config_parse_duration_seconds",
+        "Duration of the latest config load (reading, parsing and defaults propagation) in seconds"
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static CONNECTION_SSLMODE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "psql_exporter_connection_sslmode",
+        "Configured SSL mode of the connection to the database, value is 1 if connection is encrypted",
+        &["host", "dbname", "mode"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static CONNECTION_INFO: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "psql_exporter_connection_info",
+        "Constant 1 with attributes of each established connection as labels",
+        &[
+            "host",
+            "dbname",
+            "server_version",
+            "backend_pid",
+            "in_recovery"
+        ]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static DB_BYTES_RECEIVED_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "psql_exporter_db_bytes_received_total",
+        "Approximate number of bytes of query results received from the database (values of columns only)",
+        &["host", "dbname"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static QUERY_RETRIES_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "psql_exporter_query_retries_total",
+        "Number of query retries after connection-level errors",
+        &["host", "dbname"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static CONNECT_DURATION: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "psql_exporter_connect_duration_seconds",
+        "Duration of the last successful connection establishment (TCP, TLS and authentication) in seconds",
+        &["host", "dbname"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static QUERY_DURATION: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "psql_exporter_query_duration_seconds",
+        "Duration of the last successful execution of the query in seconds",
+        &["source", "database", "metric_name"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static DATABASE_UP: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "psql_exporter_database_up",
+        "1 if all queries of the latest round to the database succeeded, 0 if connection or any query failed",
+        &["source", "database"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static CIRCUIT_OPEN: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "psql_exporter_circuit_open",
+        "1 if queries to the database are paused by the circuit breaker after repeated failures",
+        &["source", "database"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static QUERY_LAST_SUCCESS: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "psql_exporter_query_last_success_timestamp_seconds",
+        "Time of the last successful execution of the query since unix epoch in seconds",
+        &["source", "database", "metric_name"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static QUERY_CONFIG_VALID: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "psql_exporter_query_config_valid",
+        "1 if metrics of the query have been created successfully at startup, 0 otherwise",
+        &["metric"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static CLOCK_SKEW: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "psql_exporter_clock_skew_seconds",
+        "Difference between the DB clock and the exporter clock measured on connect in seconds",
+        &["host", "dbname"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+// Connection state of each connection of the pool of each configured database, source of the readiness ratio
+static CONNECTION_STATUS: LazyLock<Mutex<HashMap<String, ConnectionStatus>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Status of connections of the same DB by index in the pool
+type ConnectionStatus = HashMap<usize, bool>;
+
+pub static ROWS_DROPPED_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "psql_exporter_rows_dropped_total",
+        "Number of rows of query results dropped because of max_rows limit, max_families limit or NULL in metric name",
+        &["source", "database", "metric_name"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static SERIES_PRUNED_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "psql_exporter_series_pruned_total",
+        "Number of label value combinations removed because they were missing in the query result",
+        &["metric"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static QUERY_ERRORS_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "psql_exporter_query_errors_total",
+        "Number of failed queries and failed updates of metrics by query results",
+        &["source", "database", "metric_name"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static QUERY_TIMEOUT_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "psql_exporter_query_timeout_total",
+        "Number of queries failed because of statement or query timeout",
+        &["source", "database", "metric_name"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static QUERY_TIMED_OUT: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "psql_exporter_query_timed_out",
+        "1 if the latest execution of the query with flag timeout action has failed because of timeout",
+        &["source", "database", "metric_name"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static ROWS_PROCESSED_TOTAL: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "psql_exporter_rows_processed_total",
+        "Number of rows returned by all queries and processed by the exporter"
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static SHADOW_DIFF: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "psql_exporter_shadow_diff",
+        "Absolute difference between results of the query and its shadow query",
+        &["source", "database", "metric_name"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static ASSERTION_FAILED: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "psql_exporter_assertion_failed",
+        "1 if the latest value of the metric is out of the asserted range",
+        &["source", "database", "metric_name"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static QUERY_INTERVAL_DRIFT: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "psql_exporter_query_interval_drift_seconds",
+        "Actual time between successful executions of the query minus configured scrape interval",
+        &["source", "database", "metric_name"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static RESTORED_FROM_STATE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "psql_exporter_restored_from_state",
+        "1 if values of the metric have been restored from the state file and aren't refreshed yet",
+        &["metric"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static QUERY_INFO: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "psql_exporter_query_info",
+        "Text of the query which produces the metric",
+        &["metric", "query"]
+    )
+    .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+});
+
+pub static QUERY_TAGS: LazyLock<QueryTags> = LazyLock::new(|| {
+    let query_tags = QueryTags::new("psql_exporter_query_tags", "Tags of the query")
+        .unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
+    prometheus::register(Box::new(query_tags.clone()))
+        .unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
+    query_tags
+});
+
+pub fn init() {
+    LazyLock::force(&ROWS_PROCESSED_TOTAL);
+    READINESS_RATIO.set(1.0);
+    BUILD_INFO.with_label_values(&[APP_VERSION]).set(1);
+    PROCESS_START_TIME.set(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64(),
+    );
+}
+
+pub fn set_connection_status(database: &str, pool_index: usize, connected: bool) {
+    let mut status = CONNECTION_STATUS
+        .lock()
+        .unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
+    status
+        .entry(database.to_string())
+        .or_default()
+        .insert(pool_index, connected);
+    READINESS_RATIO.set(readiness_ratio(&status));
+}
+
+// Database is removed from config, so it doesn't affect readiness anymore
+pub fn forget_database(source: &str, dbname: &str, connection_id: &str) {
+    let mut status = CONNECTION_STATUS
+        .lock()
+        .unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
+    status.remove(connection_id);
+    READINESS_RATIO.set(readiness_ratio(&status));
+    let _ = DATABASE_UP.remove_label_values(&[source, dbname]);
+    let _ = CIRCUIT_OPEN.remove_label_values(&[source, dbname]);
+}
+
+pub fn set_connection_sslmode(host: &str, dbname: &str, mode: &str, encrypted: bool) {
+    CONNECTION_SSLMODE
+        .with_label_values(&[host, dbname, mode])
+        .set(encrypted as i64);
+}
+
+pub fn set_clock_skew(host: &str, dbname: &str, skew: f64) {
+    CLOCK_SKEW.with_label_values(&[host, dbname]).set(skew);
+}
+
+pub fn set_config_parse_duration(duration: Duration) {
+    CONFIG_PARSE_DURATION.set(duration.as_secs_f64());
+}
+
+pub fn set_connect_duration(host: &str, dbname: &str, duration: Duration) {
+    CONNECT_DURATION
+        .with_label_values(&[host, dbname])
+        .set(duration.as_secs_f64());
+}
+
+pub fn set_connection_info(host: &str, dbname: &str, attributes: &[String; 3]) {
+    let [server_version, backend_pid, in_recovery] = attributes;
+    CONNECTION_INFO
+        .with_label_values(&[host, dbname, server_version, backend_pid, in_recovery])
+        .set(1);
+}
+
+pub fn forget_connection_info(host: &str, dbname: &str, attributes: &[String; 3]) {
+    let [server_version, backend_pid, in_recovery] = attributes;
+    let _ = CONNECTION_INFO.remove_label_values(&[
+        host,
+        dbname,
+        server_version,
+        backend_pid,
+        in_recovery,
+    ]);
+}
+
+pub fn count_bytes_received(host: &str, dbname: &str, bytes: usize) {
+    DB_BYTES_RECEIVED_TOTAL
+        .with_label_values(&[host, dbname])
+        .inc_by(bytes as u64);
+}
+
+pub fn count_query_retry(host: &str, dbname: &str) {
+    QUERY_RETRIES_TOTAL.with_label_values(&[host, dbname]).inc();
+}
+
+pub fn set_query_duration(source: &str, database: &str, metric_name: &str, duration: Duration) {
+    QUERY_DURATION
+        .with_label_values(&[source, database, metric_name])
+        .set(duration.as_secs_f64());
+}
+
+pub fn set_query_last_success(source: &str, database: &str, metric_name: &str, time: SystemTime) {
+    QUERY_LAST_SUCCESS
+        .with_label_values(&[source, database, metric_name])
+        .set(
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+        );
+}
+
+pub fn set_database_up(source: &str, database: &str, up: bool) {
+    DATABASE_UP
+        .with_label_values(&[source, database])
+        .set(up as i64);
+}
+
+pub fn set_circuit_open(source: &str, database: &str, open: bool) {
+    CIRCUIT_OPEN
+        .with_label_values(&[source, database])
+        .set(open as i64);
+}
+
+pub fn count_query_error(source: &str, database: &str, metric_name: &str) {
+    QUERY_ERRORS_TOTAL
+        .with_label_values(&[source, database, metric_name])
+        .inc();
+}
+
+// Nothing is broken if nothing is configured, database is connected if any connection of its pool is up
+fn readiness_ratio(status: &HashMap<String, ConnectionStatus>) -> f64 {
+    if status.is_empty() {
+        return 1.0;
+    }
+
+    let connected = status
+        .values()
+        .filter(|pool| pool.values().any(|connected| *connected))
+        .count();
+    connected as f64 / status.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrape_config::ScrapeConfig;
+
+    #[test]
+    fn empty_config_exposes_build_info() {
+        let config = ScrapeConfig::from_yaml("sources: {}").unwrap();
+        assert_eq!(config.len(), 0);
+
+        init();
+        let families = prometheus::gather();
+        let build_info = families
+            .iter()
+            .find(|f| f.get_name() == "psql_exporter_build_info")
+            .expect("build info should be registered");
+        assert_eq!(
+            build_info.get_metric()[0].get_label()[0].get_value(),
+            APP_VERSION
+        );
+        assert_eq!(build_info.get_metric()[0].get_gauge().get_value(), 1.0);
+        assert!(families
+            .iter()
+            .any(|f| f.get_name() == "process_start_time_seconds"));
+    }
+
+    #[test]
+    fn rows_processed_increases() {
+        init();
+        let rows_processed = |families: &[prometheus::proto::MetricFamily]| {
+            families
+                .iter()
+                .find(|f| f.get_name() == "psql_exporter_rows_processed_total")
+                .expect("rows processed counter should be registered")
+                .get_metric()[0]
+                .get_counter()
+                .get_value()
+        };
+
+        let before = rows_processed(&prometheus::gather());
+        ROWS_PROCESSED_TOTAL.inc_by(3);
+        ROWS_PROCESSED_TOTAL.inc_by(2);
+        assert!(rows_processed(&prometheus::gather()) >= before + 5.0);
+    }
+
+    #[test]
+    fn query_duration_is_exposed() {
+        set_query_duration("postgres", "app", "slow_query", Duration::from_millis(1500));
+        let families = prometheus::gather();
+        let query_duration = families
+            .iter()
+            .find(|f| f.get_name() == "psql_exporter_query_duration_seconds")
+            .expect("query duration should be registered");
+        let metric = query_duration
+            .get_metric()
+            .iter()
+            .find(|m| m.get_label().iter().any(|l| l.get_value() == "slow_query"))
+            .unwrap();
+
+        let labels: Vec<_> = metric
+            .get_label()
+            .iter()
+            .map(|l| (l.get_name(), l.get_value()))
+            .collect();
+        assert_eq!(
+            labels,
+            vec![
+                ("database", "app"),
+                ("metric_name", "slow_query"),
+                ("source", "postgres")
+            ]
+        );
+        assert_eq!(metric.get_gauge().get_value(), 1.5);
+    }
+
+    #[test]
+    fn database_up_follows_latest_round() {
+        let database_up = || {
+            prometheus::gather()
+                .iter()
+                .find(|f| f.get_name() == "psql_exporter_database_up")
+                .expect("database up should be registered")
+                .get_metric()
+                .iter()
+                .find(|m| m.get_label().iter().any(|l| l.get_value() == "unreachable"))
+                .map(|m| m.get_gauge().get_value())
+        };
+
+        set_database_up("postgres", "unreachable", false);
+        assert_eq!(database_up(), Some(0.0));
+        set_database_up("postgres", "unreachable", true);
+        assert_eq!(database_up(), Some(1.0));
+    }
+
+    #[test]
+    fn query_last_success_is_unix_time() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        set_query_last_success("postgres", "app", "fresh_query", time);
+
+        let last_success = QUERY_LAST_SUCCESS
+            .with_label_values(&["postgres", "app", "fresh_query"])
+            .get();
+        assert_eq!(last_success, 1_700_000_000.0);
+    }
+
+    #[test]
+    fn query_errors_are_counted() {
+        let query_errors = || {
+            QUERY_ERRORS_TOTAL
+                .with_label_values(&["postgres", "app", "failing_query"])
+                .get()
+        };
+
+        let before = query_errors();
+        count_query_error("postgres", "app", "failing_query");
+        count_query_error("postgres", "app", "failing_query");
+        assert_eq!(query_errors(), before + 2);
+        assert!(prometheus::gather()
+            .iter()
+            .any(|f| f.get_name() == "psql_exporter_query_errors_total"));
+    }
+
+    #[test]
+    fn readiness_ratio_of_partially_connected() {
+        let mut status = HashMap::new();
+        assert_eq!(readiness_ratio(&status), 1.0);
+
+        let pool = |connected: &[bool]| connected.iter().copied().enumerate().collect();
+        status.insert(String::from("postgres@db1:5432/app"), pool(&[true]));
+        status.insert(String::from("postgres@db2:5432/app"), pool(&[false]));
+        status.insert(String::from("postgres@db3:5432/app"), pool(&[true]));
+        status.insert(String::from("postgres@db4:5432/app"), pool(&[false]));
+        assert_eq!(readiness_ratio(&status), 0.5);
+
+        status.insert(String::from("postgres@db2:5432/app"), pool(&[true]));
+        assert_eq!(readiness_ratio(&status), 0.75);
+
+        // reconnect of one connection of the pool doesn't make the whole DB down
+        status.insert(
+            String::from("postgres@db4:5432/app"),
+            pool(&[false, true, true]),
+        );
+        assert_eq!(readiness_ratio(&status), 1.0);
+        status.insert(String::from("postgres@db4:5432/app"), pool(&[false, false]));
+        assert_eq!(readiness_ratio(&status), 0.75);
+    }
+}
@@ -0,0 +1,192 @@
+mod app_config;
+mod db;
+mod error_log;
+mod errors;
+mod internal_metrics;
+mod metrics;
+mod presets;
+mod query_tags;
+mod scrape_config;
+mod state;
+mod summary;
+mod utils;
+
+pub use app_config::AppConfig;
+pub use metrics::add_families_hook;
+pub use prometheus::proto::MetricFamily;
+pub use utils::{build_runtime, ShutdownReceiver};
+
+use scrape_config::ScrapeConfig;
+use utils::SignalHandler;
+
+use std::{error::Error, future::Future, pin::Pin, sync::Arc};
+use tokio::sync::watch;
+use tracing::{error, info, instrument};
+
+use warp::Filter;
+
+const HOME_PAGE_CONTENT: &str = include_str!("../assets/index.html");
+
+// Exporter handles TERM/INT/QUIT/HANGUP signals itself unless the shutdown channel is provided by the caller,
+// in this case it stops gracefully (the same way as on TERM signal) when true is sent to the channel
+#[instrument(skip(app_config, shutdown))]
+pub async fn run_exporter(
+    app_config: AppConfig,
+    shutdown: Option<ShutdownReceiver>,
+) -> Result<(), Box<dyn Error>> {
+    internal_metrics::init();
+    // The same way on start and on each reload by HANGUP signal
+    let load_config = {
+        let config = app_config.config.clone();
+        let only_database = app_config.only_database.clone();
+        let strict = app_config.strict;
+        move || {
+            let mut scrape_config = ScrapeConfig::from(&config)?;
+            if !only_database.is_empty() {
+                scrape_config.retain_databases(&only_database);
+            }
+            if strict {
+                scrape_config.check_certificates()?;
+            }
+            Ok(scrape_config)
+        }
+    };
+    let scrape_config = load_config()?;
+    if app_config.list_queries {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&scrape_config.query_definitions())?
+        );
+        return Ok(());
+    }
+    let metric_definitions = metrics::list_metrics(&scrape_config)?;
+    if app_config.list_metrics {
+        println!("{}", serde_json::to_string_pretty(&metric_definitions)?);
+        return Ok(());
+    }
+    metrics::register_static_metrics(&scrape_config)?;
+    if let Some(error_log) = &app_config.error_log {
+        error_log::init(error_log, app_config.error_log_max_size)?;
+    }
+    if let Some(state_file) = &app_config.state_file {
+        state::init(state_file, app_config.state_max_age);
+    }
+
+    let mut signal_handler = match shutdown {
+        Some(shutdown) => SignalHandler::with_external_shutdown(shutdown),
+        None => SignalHandler::new()?,
+    };
+    let shutdown_channel_rx = signal_handler.get_rx_channel();
+    let draining_channel_rx = signal_handler.get_draining_channel();
+    let reload_channel_rx = signal_handler.get_reload_channel();
+
+    // GET /
+    let home_route = warp::path::end().map(|| warp::reply::html(HOME_PAGE_CONTENT));
+    // GET /health
+    let health_route = warp::path("health").map(|| "healthy\n");
+    // GET /metrics
+    let metrics_route = warp::path!("metrics")
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(metrics::compose_reply);
+    // GET /metrics/list
+    let metric_definitions = Arc::new(metric_definitions);
+    let list_metrics_route = warp::path!("metrics" / "list")
+        .and_then(move || metrics::list_reply(metric_definitions.clone()));
+    // GET /metrics/<tenant>
+    let tenant_metrics_route = warp::path!("metrics" / String)
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(metrics::compose_tenant_reply);
+    // POST /admin/reset
+    let (reset_channel_tx, reset_channel_rx) = watch::channel(());
+    let admin_token = app_config.admin_token.clone();
+    let reset_route = warp::path!("admin" / "reset")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |authorization| {
+            metrics::reset_reply(authorization, admin_token.clone(), reset_channel_tx.clone())
+        });
+    // Any request gets 503 during shutdown
+    let routes = metrics::reject_while_draining(draining_channel_rx.clone())
+        .and(
+            warp::get()
+                .and(
+                    health_route
+                        .or(metrics_route)
+                        .or(list_metrics_route)
+                        .or(tenant_metrics_route)
+                        .or(home_route),
+                )
+                .or(reset_route),
+        )
+        .recover(metrics::draining_reply);
+
+    let shutdown_signal = async move {
+        signal_handler.shutdown_on_signal().await;
+    };
+    let http_server: Pin<Box<dyn Future<Output = ()> + Send>> =
+        match (app_config.stdout_interval, utils::systemd_listener_fd()) {
+            (Some(interval), _) => {
+                info!("printing metrics to stdout every {interval:?}");
+                let printing_task = metrics::printing_task(
+                    interval,
+                    std::io::stdout(),
+                    draining_channel_rx.clone(),
+                );
+                Box::pin(async move {
+                    tokio::join!(printing_task, shutdown_signal);
+                })
+            }
+            (None, Some(fd)) => {
+                let listener = utils::tcp_listener_from_fd(fd)?;
+                info!(
+                    "listening on socket passed by systemd: {}",
+                    listener.local_addr()?
+                );
+                Box::pin(warp::serve(routes).serve_incoming_with_graceful_shutdown(
+                    utils::incoming_connections(listener),
+                    shutdown_signal,
+                ))
+            }
+            (None, None) => {
+                let (_addr, http_server) = warp::serve(routes).bind_with_graceful_shutdown(
+                    (app_config.listen_on, app_config.port),
+                    shutdown_signal,
+                );
+                Box::pin(http_server)
+            }
+        };
+
+    let metrics_collecting_task = tokio::task::spawn(metrics::collecting_task(
+        scrape_config,
+        shutdown_channel_rx,
+        reset_channel_rx,
+        reload_channel_rx,
+        load_config,
+        utils::QueryLimiter::new(app_config.max_concurrent_queries),
+        app_config.dedicated_threads,
+    ));
+    let mut http_server_task = tokio::task::spawn(http_server);
+
+    tokio::select! {
+        _ = metrics_collecting_task => {info!("all collecting tasks have been finished")},
+        _ = &mut http_server_task => {
+            info!("web server has been finished");
+            return Ok(());
+        },
+    }
+
+    if let Some(state_file) = &app_config.state_file {
+        match metrics::save_state(state_file) {
+            Ok(_) => info!("metric values have been saved to state file {state_file}"),
+            Err(e) => error!("{e}"),
+        }
+    }
+
+    // During shutdown web server is stopped after collectors
+    if *draining_channel_rx.borrow() {
+        let _ = http_server_task.await;
+        info!("web server has been finished");
+    }
+
+    Ok(())
+}
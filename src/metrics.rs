@@ -1,30 +1,77 @@
-use crate::db::{PostgresConnection, PostgresSslCertificates};
+use crate::db::{ClientEncoding, PostgresConnection, PostgresSslCertificates};
+use crate::error_log::{self, ErrorRecord};
 use crate::errors::PsqlExporterError;
+use crate::internal_metrics::{
+    self, ASSERTION_FAILED, QUERY_CONFIG_VALID, QUERY_INFO, QUERY_INTERVAL_DRIFT, QUERY_TAGS,
+    QUERY_TIMED_OUT, QUERY_TIMEOUT_TOTAL, RESTORED_FROM_STATE, ROWS_DROPPED_TOTAL,
+    ROWS_PROCESSED_TOTAL, SERIES_PRUNED_TOTAL, SHADOW_DIFF,
+};
 use crate::scrape_config::{
-    FieldType, ScrapeConfig, ScrapeConfigDatabase, ScrapeConfigQuery, ScrapeConfigValues,
+    parse_bool, AggregateFunction, DuplicateLabels, ExpirationMode, Field, FieldType,
+    FieldWithType, HistogramFields, KeyValueFields, LsnLagFields, NanPolicy, ScrapeConfig,
+    ScrapeConfigDatabase, ScrapeConfigQuery, ScrapeConfigValues, StateSetFields, StaticMetric,
+    SummaryFields, TimeoutAction, ValueAssertion, ValueTransform,
+};
+use crate::state::{self, SavedSeries, State};
+use crate::summary::{PrecomputedSummary, SummaryValue};
+use crate::utils::{
+    spawn_on_dedicated_thread, CircuitBreaker, Jitter, QueryLimiter, ReloadReceiver, ResetReceiver,
+    ResetSender, ShutdownReceiver, ShutdownSender, SleepHelper,
 };
-use crate::utils::{ShutdownReceiver, SleepHelper};
 
-use prometheus::core::{AtomicF64, AtomicI64, Collector, GenericGauge, GenericGaugeVec};
+use prometheus::core::{
+    Atomic, AtomicF64, AtomicI64, Collector, Desc, GenericGauge, GenericGaugeVec,
+};
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
 use prometheus::{
-    opts, Encoder, Gauge, GaugeVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+    opts, Encoder, Gauge, GaugeVec, HistogramOpts, HistogramVec, IntGauge, IntGaugeVec, Opts,
+    ProtobufEncoder, Registry, TextEncoder,
+};
+use tokio::sync::{mpsc, watch};
+use tokio_postgres::{
+    types::{FromSql, PgLsn, Type, WasNull},
+    Row,
 };
-use tokio::sync::mpsc;
-use tokio_postgres::Row;
+use warp::{http::StatusCode, Filter, Rejection};
 
+use futures_util::future::join_all;
 use human_repr::HumanDuration;
+use serde::Serialize;
 
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::Infallible;
-use std::time::{Duration, SystemTime};
+use std::hash::{BuildHasher, RandomState};
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
 use tracing::{debug, error, info, warn};
 
+const PROTOBUF_CONTENT_TYPE: &str = "application/vnd.google.protobuf";
+const QUERY_INFO_MAX_LENGTH: usize = 256;
+pub const TENANT_LABEL: &str = "tenant";
+// Marks the end of each metrics snapshot printed to stdout
+const STDOUT_DELIMITER: &str = "# EOF";
+
+static TENANT_REGISTRIES: LazyLock<RwLock<BTreeMap<String, Registry>>> =
+    LazyLock::new(|| RwLock::new(BTreeMap::new()));
+
+type FamiliesHook = Box<dyn Fn(&mut Vec<MetricFamily>) + Send + Sync>;
+
+static FAMILIES_HOOKS: LazyLock<RwLock<Vec<FamiliesHook>>> =
+    LazyLock::new(|| RwLock::new(Vec::new()));
+
 #[derive(Debug)]
 pub enum MetricWithType {
     SingleInt(GenericGauge<AtomicI64>),
     SingleFloat(GenericGauge<AtomicF64>),
     VectorInt(GenericGaugeVec<AtomicI64>),
     VectorFloat(GenericGaugeVec<AtomicF64>),
+    Summary(PrecomputedSummary),
+    Histogram(HistogramVec),
 }
 
 impl MetricWithType {
@@ -34,21 +81,153 @@ impl MetricWithType {
             MetricWithType::SingleFloat(m) => Box::new(m.to_owned()),
             MetricWithType::VectorInt(m) => Box::new(m.to_owned()),
             MetricWithType::VectorFloat(m) => Box::new(m.to_owned()),
+            MetricWithType::Summary(m) => Box::new(m.to_owned()),
+            MetricWithType::Histogram(m) => Box::new(m.to_owned()),
+        }
+    }
+
+    fn reset(&self) {
+        match self {
+            MetricWithType::SingleInt(m) => m.set(0),
+            MetricWithType::SingleFloat(m) => m.set(0.0),
+            MetricWithType::VectorInt(m) => m.reset(),
+            MetricWithType::VectorFloat(m) => m.reset(),
+            MetricWithType::Summary(m) => m.reset(),
+            MetricWithType::Histogram(m) => m.reset(),
+        }
+    }
+
+    fn name(&self) -> String {
+        self.to_collector().desc()[0].fq_name.clone()
+    }
+
+    // Label values of the series are returned if it matches the metric (names of labels and const labels values)
+    fn restore(&self, series: &SavedSeries) -> Option<Vec<String>> {
+        let collector = self.to_collector();
+        let desc = collector.desc()[0];
+        if series.labels.len() != desc.const_label_pairs.len() + desc.variable_labels.len() {
+            return None;
+        }
+        for pair in desc.const_label_pairs.iter() {
+            if series.labels.get(pair.get_name()) != Some(&pair.get_value().to_string()) {
+                return None;
+            }
+        }
+        let label_values = desc
+            .variable_labels
+            .iter()
+            .map(|label| series.labels.get(label).cloned())
+            .collect::<Option<Vec<String>>>()?;
+        let labels: Vec<&str> = label_values.iter().map(AsRef::as_ref).collect();
+
+        match self {
+            MetricWithType::SingleInt(m) => m.set(series.value as i64),
+            MetricWithType::SingleFloat(m) => m.set(series.value),
+            MetricWithType::VectorInt(m) => m.with_label_values(&labels).set(series.value as i64),
+            MetricWithType::VectorFloat(m) => m.with_label_values(&labels).set(series.value),
+            MetricWithType::Summary(_) | MetricWithType::Histogram(_) => return None,
+        }
+
+        Some(label_values)
+    }
+
+    // Summary and histogram have no single value to zero, so they are kept as is
+    fn zero(&self, label_values: &LabelValues) {
+        match self {
+            MetricWithType::SingleInt(m) => m.set(0),
+            MetricWithType::SingleFloat(m) => m.set(0.0),
+            MetricWithType::VectorInt(m) => {
+                for values in label_values {
+                    let values: Vec<&str> = values.iter().map(AsRef::as_ref).collect();
+                    m.with_label_values(&values).set(0);
+                }
+            }
+            MetricWithType::VectorFloat(m) => {
+                for values in label_values {
+                    let values: Vec<&str> = values.iter().map(AsRef::as_ref).collect();
+                    m.with_label_values(&values).set(0.0);
+                }
+            }
+            MetricWithType::Summary(_) | MetricWithType::Histogram(_) => {}
+        }
+    }
+
+    fn remove_label_values(&self, label_values: &[&str]) -> Result<(), prometheus::Error> {
+        match self {
+            MetricWithType::SingleInt(_) | MetricWithType::SingleFloat(_) => Ok(()),
+            MetricWithType::VectorInt(m) => m.remove_label_values(label_values),
+            MetricWithType::VectorFloat(m) => m.remove_label_values(label_values),
+            MetricWithType::Summary(m) => m.remove_label_values(label_values),
+            MetricWithType::Histogram(m) => m.remove_label_values(label_values),
         }
     }
 }
 
+type LabelValues = HashSet<Vec<String>>;
+
 struct QueryMetrics {
     metrics: Vec<MetricWithType>,
+    label_values: Vec<LabelValues>,
+    series_last_seen: Vec<HashMap<Vec<String>, SystemTime>>,
+    stale: Option<IntGauge>,
+    null_count: Option<IntGauge>,
+    restored_from_state: Option<IntGauge>,
+    timed_out: Option<IntGauge>,
+    // indexes of metrics by their names, for queries with templated metric names only
+    families: HashMap<String, usize>,
     is_registered: bool,
     last_updated: SystemTime,
+    last_succeeded: Option<SystemTime>,
     next_query_time: SystemTime,
 }
 
 impl QueryMetrics {
     fn from(query_config: &ScrapeConfigQuery) -> Result<Self, PsqlExporterError> {
+        let created = Self::create_metrics(query_config);
+        QUERY_CONFIG_VALID
+            .with_label_values(&[query_config.metric_name.as_str()])
+            .set(created.is_ok() as i64);
+        let (metrics, stale) = created?;
+
+        if query_config.expose_query_info {
+            QUERY_INFO
+                .with_label_values(&[
+                    query_config.metric_name.as_str(),
+                    &Self::query_info_label(&query_config.query),
+                ])
+                .set(1);
+        }
+
+        Ok(QueryMetrics {
+            label_values: vec![LabelValues::new(); metrics.len()],
+            series_last_seen: vec![HashMap::new(); metrics.len()],
+            metrics,
+            stale,
+            null_count: Self::create_null_count(query_config)?,
+            restored_from_state: None,
+            timed_out: None,
+            families: HashMap::new(),
+            is_registered: false,
+            last_updated: SystemTime::now() - query_config.metric_expiration_time,
+            last_succeeded: None,
+            next_query_time: if query_config.align_to_clock {
+                aligned_query_time(SystemTime::now(), query_config.scrape_interval)
+            } else {
+                SystemTime::now()
+            },
+        })
+    }
+
+    fn create_metrics(
+        query_config: &ScrapeConfigQuery,
+    ) -> Result<(Vec<MetricWithType>, Option<IntGauge>), PsqlExporterError> {
         let mut metrics: Vec<MetricWithType> = vec![];
 
+        // families of templated names are unknown until the query returns rows
+        if query_config.is_templated() {
+            return Ok((metrics, None));
+        }
+
         match &query_config.values {
             ScrapeConfigValues::ValueFrom(values) => {
                 let mut opts = opts!(
@@ -101,11 +280,15 @@ impl QueryMetrics {
             ScrapeConfigValues::ValuesWithSuffixes(values) => {
                 for value in values {
                     let metric_name = format!("{}_{}", query_config.metric_name, value.suffix);
-                    let metric_desc = format!(
-                        "{}: {}",
-                        query_config.description.clone().unwrap(),
-                        value.suffix
-                    );
+                    let metric_desc = match &value.description {
+                        Some(description) => description.clone(),
+                        None if query_config.suffix_in_description => format!(
+                            "{}: {}",
+                            query_config.description.clone().unwrap(),
+                            value.suffix
+                        ),
+                        None => query_config.description.clone().unwrap(),
+                    };
                     let mut opts = opts!(metric_name, metric_desc);
 
                     if let Some(const_labels) = &query_config.const_labels {
@@ -124,16 +307,202 @@ impl QueryMetrics {
                     metrics.push(new_metric);
                 }
             }
+
+            ScrapeConfigValues::Summary(_) => {
+                let mut opts = opts!(
+                    query_config.metric_name.clone(),
+                    query_config.description.clone().unwrap()
+                );
+
+                if let Some(const_labels) = &query_config.const_labels {
+                    opts = opts.const_labels(const_labels.clone());
+                }
+
+                let var_labels: Vec<&str> = query_config
+                    .var_labels
+                    .iter()
+                    .flatten()
+                    .map(AsRef::as_ref)
+                    .collect();
+                let new_metric = PrecomputedSummary::new(opts, &var_labels).map_err(|e| {
+                    PsqlExporterError::CreateMetric {
+                        metric: query_config.metric_name.clone(),
+                        cause: e,
+                    }
+                })?;
+
+                metrics.push(MetricWithType::Summary(new_metric));
+            }
+
+            ScrapeConfigValues::Info => {
+                let mut opts = opts!(
+                    query_config.metric_name.clone(),
+                    query_config.description.clone().unwrap()
+                );
+
+                if let Some(const_labels) = &query_config.const_labels {
+                    opts = opts.const_labels(const_labels.clone());
+                }
+
+                let new_metric =
+                    Self::helper_create_metric(&query_config.var_labels, &FieldType::Int, opts)
+                        .map_err(|e| PsqlExporterError::CreateMetric {
+                            metric: query_config.metric_name.clone(),
+                            cause: e,
+                        })?;
+
+                metrics.push(new_metric);
+            }
+
+            ScrapeConfigValues::KeyValue(fields) => {
+                let mut opts = opts!(
+                    query_config.metric_name.clone(),
+                    query_config.description.clone().unwrap()
+                );
+
+                if let Some(const_labels) = &query_config.const_labels {
+                    opts = opts.const_labels(const_labels.clone());
+                }
+
+                let mut var_labels = query_config.var_labels.clone().unwrap_or_default();
+                var_labels.push(fields.label.clone());
+                let new_metric =
+                    Self::helper_create_metric(&Some(var_labels), &FieldType::Float, opts)
+                        .map_err(|e| PsqlExporterError::CreateMetric {
+                            metric: query_config.metric_name.clone(),
+                            cause: e,
+                        })?;
+
+                metrics.push(new_metric);
+            }
+
+            ScrapeConfigValues::Histogram(fields) => {
+                let mut opts = opts!(
+                    query_config.metric_name.clone(),
+                    query_config.description.clone().unwrap()
+                );
+
+                if let Some(const_labels) = &query_config.const_labels {
+                    opts = opts.const_labels(const_labels.clone());
+                }
+
+                let var_labels: Vec<&str> = query_config
+                    .var_labels
+                    .iter()
+                    .flatten()
+                    .map(AsRef::as_ref)
+                    .collect();
+                let opts = HistogramOpts::from(opts).buckets(fields.buckets.clone());
+                let new_metric = HistogramVec::new(opts, &var_labels).map_err(|e| {
+                    PsqlExporterError::CreateMetric {
+                        metric: query_config.metric_name.clone(),
+                        cause: e,
+                    }
+                })?;
+
+                metrics.push(MetricWithType::Histogram(new_metric));
+            }
+
+            ScrapeConfigValues::StateSet(fields) => {
+                let mut opts = opts!(
+                    query_config.metric_name.clone(),
+                    query_config.description.clone().unwrap()
+                );
+
+                if let Some(const_labels) = &query_config.const_labels {
+                    opts = opts.const_labels(const_labels.clone());
+                }
+
+                let mut var_labels = query_config.var_labels.clone().unwrap_or_default();
+                var_labels.push(fields.label.clone());
+                let new_metric =
+                    Self::helper_create_metric(&Some(var_labels), &FieldType::Int, opts).map_err(
+                        |e| PsqlExporterError::CreateMetric {
+                            metric: query_config.metric_name.clone(),
+                            cause: e,
+                        },
+                    )?;
+
+                metrics.push(new_metric);
+            }
+
+            ScrapeConfigValues::LsnLag(_) => {
+                let mut opts = opts!(
+                    query_config.metric_name.clone(),
+                    query_config.description.clone().unwrap()
+                );
+
+                if let Some(const_labels) = &query_config.const_labels {
+                    opts = opts.const_labels(const_labels.clone());
+                }
+
+                let new_metric =
+                    Self::helper_create_metric(&query_config.var_labels, &FieldType::Float, opts)
+                        .map_err(|e| PsqlExporterError::CreateMetric {
+                        metric: query_config.metric_name.clone(),
+                        cause: e,
+                    })?;
+
+                metrics.push(new_metric);
+            }
         };
 
-        Ok(QueryMetrics {
-            metrics,
-            is_registered: false,
-            last_updated: SystemTime::now() - query_config.metric_expiration_time,
-            next_query_time: SystemTime::now(),
+        let stale = match query_config.expiration_mode {
+            ExpirationMode::Remove => None,
+            ExpirationMode::MarkStale => Some(Self::companion_gauge(
+                query_config,
+                "stale",
+                "1 if values are stale",
+            )?),
+        };
+
+        Ok((metrics, stale))
+    }
+
+    // NULL values can't be counted unless they're skipped, otherwise NULL fails the query
+    fn create_null_count(
+        query_config: &ScrapeConfigQuery,
+    ) -> Result<Option<IntGauge>, PsqlExporterError> {
+        if query_config.skip_null {
+            Self::companion_gauge(
+                query_config,
+                "null_count",
+                "number of NULL values in the last result",
+            )
+            .map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn companion_gauge(
+        query_config: &ScrapeConfigQuery,
+        suffix: &str,
+        help: &str,
+    ) -> Result<IntGauge, PsqlExporterError> {
+        let mut opts = opts!(
+            format!("{}_{suffix}", query_config.metric_name),
+            format!("{}: {help}", query_config.description.clone().unwrap())
+        );
+        if let Some(const_labels) = &query_config.const_labels {
+            opts = opts.const_labels(const_labels.clone());
+        }
+        IntGauge::with_opts(opts).map_err(|e| PsqlExporterError::CreateMetric {
+            metric: query_config.metric_name.clone(),
+            cause: e,
         })
     }
 
+    fn query_info_label(query: &str) -> String {
+        let query = query.split_whitespace().collect::<Vec<&str>>().join(" ");
+        if query.chars().count() > QUERY_INFO_MAX_LENGTH {
+            let truncated: String = query.chars().take(QUERY_INFO_MAX_LENGTH).collect();
+            format!("{truncated}...")
+        } else {
+            query
+        }
+    }
+
     fn helper_create_metric(
         var_labels: &Option<Vec<String>>,
         field_type: &FieldType,
@@ -146,24 +515,82 @@ impl QueryMetrics {
                     opts,
                     &new_labels,
                 )?)),
-                FieldType::Float => Ok(MetricWithType::VectorFloat(GaugeVec::new(
-                    opts,
-                    &new_labels,
-                )?)),
+                FieldType::Float | FieldType::Timestamp => Ok(MetricWithType::VectorFloat(
+                    GaugeVec::new(opts, &new_labels)?,
+                )),
             }
         } else {
             match field_type {
                 FieldType::Int => Ok(MetricWithType::SingleInt(IntGauge::with_opts(opts)?)),
-                FieldType::Float => Ok(MetricWithType::SingleFloat(Gauge::with_opts(opts)?)),
+                FieldType::Float | FieldType::Timestamp => {
+                    Ok(MetricWithType::SingleFloat(Gauge::with_opts(opts)?))
+                }
             }
         }
     }
 
+    // Index of the family is returned, or None if the query has too many families already
+    fn family(
+        &mut self,
+        metric_name: &str,
+        value: &FieldWithType,
+        query_config: &ScrapeConfigQuery,
+        registry: &Registry,
+    ) -> Result<Option<usize>, PsqlExporterError> {
+        if let Some(index) = self.families.get(metric_name) {
+            return Ok(Some(*index));
+        }
+        if self.families.len() >= query_config.max_families.get() {
+            return Ok(None);
+        }
+
+        let mut opts = opts!(metric_name, query_config.description.clone().unwrap());
+        if let Some(const_labels) = &query_config.const_labels {
+            opts = opts.const_labels(const_labels.clone());
+        }
+        let create_error = |e| PsqlExporterError::CreateMetric {
+            metric: metric_name.to_string(),
+            cause: e,
+        };
+        let new_metric =
+            Self::helper_create_metric(&query_config.var_labels, &value.field_type, opts)
+                .map_err(create_error)?;
+        // unlike static names, the same family may come from another query, so it isn't a BUG
+        if self.is_registered {
+            registry
+                .register(new_metric.to_collector())
+                .map_err(create_error)?;
+        }
+        debug!(
+            "new family {metric_name} of metric {}",
+            query_config.metric_name
+        );
+
+        self.metrics.push(new_metric);
+        self.label_values.push(LabelValues::new());
+        self.series_last_seen.push(HashMap::new());
+        self.families
+            .insert(metric_name.to_string(), self.metrics.len() - 1);
+
+        Ok(Some(self.metrics.len() - 1))
+    }
+
     fn register(&mut self, registry: &Registry) {
         self.last_updated = SystemTime::now();
+        if let Some(stale) = &self.stale {
+            stale.set(0);
+        }
+        if let Some(null_count) = &self.null_count {
+            null_count.set(0);
+        }
+        if let Some(restored_from_state) = self.restored_from_state.take() {
+            restored_from_state.set(0);
+        }
+        if let Some(timed_out) = self.timed_out.take() {
+            timed_out.set(0);
+        }
         if !self.is_registered {
-            for metric in self.metrics.iter() {
-                let metric = metric.to_collector();
+            for metric in self.collectors() {
                 registry
                     .register(metric)
                     .unwrap_or_else(|e| panic!("error while registering metric: {e}"));
@@ -172,10 +599,148 @@ impl QueryMetrics {
         };
     }
 
+    fn timeout(
+        &mut self,
+        source: &str,
+        database: &str,
+        query_config: &ScrapeConfigQuery,
+        registry: &Registry,
+    ) {
+        match query_config.timeout_action {
+            TimeoutAction::Keep => {}
+            TimeoutAction::Zero => {
+                for (metric, label_values) in self.metrics.iter().zip(self.label_values.iter()) {
+                    metric.zero(label_values);
+                }
+            }
+            TimeoutAction::Expire => self.expire(query_config.metric_expiration_time, registry),
+            TimeoutAction::Flag => {
+                let timed_out = QUERY_TIMED_OUT.with_label_values(&[
+                    source,
+                    database,
+                    &query_config.metric_name,
+                ]);
+                timed_out.set(1);
+                self.timed_out = Some(timed_out);
+            }
+        }
+    }
+
+    fn expire(&mut self, metric_expiration_time: Duration, registry: &Registry) {
+        if metric_expiration_time != Duration::ZERO {
+            let expiration_time = self.last_updated + metric_expiration_time;
+            if SystemTime::now() > expiration_time {
+                if let Some(stale) = &self.stale {
+                    debug!("mark metrics as stale");
+                    stale.set(1);
+                } else {
+                    debug!("deregister metrics as expired");
+                    self.unregister(registry);
+                }
+            }
+        }
+    }
+
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        let mut collectors: Vec<Box<dyn Collector>> =
+            self.metrics.iter().map(|m| m.to_collector()).collect();
+        if let Some(stale) = &self.stale {
+            collectors.push(Box::new(stale.clone()));
+        }
+        if let Some(null_count) = &self.null_count {
+            collectors.push(Box::new(null_count.clone()));
+        }
+        collectors
+    }
+
+    fn prune_missing_labels(&mut self, current_label_values: Vec<LabelValues>) {
+        for ((metric, previous), current) in self
+            .metrics
+            .iter()
+            .zip(self.label_values.iter_mut())
+            .zip(current_label_values)
+        {
+            for missing in previous.difference(&current) {
+                let missing: Vec<&str> = missing.iter().map(AsRef::as_ref).collect();
+                let metric_name = metric.name();
+                match metric.remove_label_values(&missing) {
+                    Ok(_) => {
+                        debug!("pruned missing labels {missing:?} of metric {metric_name}");
+                        SERIES_PRUNED_TOTAL
+                            .with_label_values(&[metric_name.as_str()])
+                            .inc();
+                    }
+                    Err(e) => error!("unable to prune labels of metric {metric_name}: {e}"),
+                }
+            }
+            *previous = current;
+        }
+    }
+
+    // Unlike expire, it's called after successful queries only and removes series which are absent in results for too long
+    fn expire_series(
+        &mut self,
+        current_label_values: &[LabelValues],
+        metric_expiration_time: Duration,
+    ) {
+        if metric_expiration_time == Duration::ZERO {
+            return;
+        }
+
+        let now = SystemTime::now();
+        for (((metric, last_seen), label_values), current) in self
+            .metrics
+            .iter()
+            .zip(self.series_last_seen.iter_mut())
+            .zip(self.label_values.iter_mut())
+            .zip(current_label_values)
+        {
+            for labels in current {
+                last_seen.insert(labels.clone(), now);
+            }
+            last_seen.retain(|labels, seen| {
+                if now <= *seen + metric_expiration_time {
+                    return true;
+                }
+                let expired: Vec<&str> = labels.iter().map(AsRef::as_ref).collect();
+                match metric.remove_label_values(&expired) {
+                    Ok(_) => debug!("expired labels {expired:?} of metric {}", metric.name()),
+                    Err(e) => error!("unable to expire labels of metric {}: {e}", metric.name()),
+                }
+                label_values.remove(labels);
+                false
+            });
+        }
+    }
+
+    fn delay_first_query(&mut self, start_offset: Duration) {
+        self.next_query_time += start_offset;
+    }
+
+    // Positive drift means the query can't keep its cadence
+    fn update_interval_drift(
+        &mut self,
+        source: &str,
+        database: &str,
+        metric_name: &str,
+        scrape_interval: Duration,
+    ) {
+        let now = SystemTime::now();
+        if let Some(last_succeeded) = self.last_succeeded {
+            let actual_interval = now
+                .duration_since(last_succeeded)
+                .unwrap_or_default()
+                .as_secs_f64();
+            QUERY_INTERVAL_DRIFT
+                .with_label_values(&[source, database, metric_name])
+                .set(actual_interval - scrape_interval.as_secs_f64());
+        }
+        self.last_succeeded = Some(now);
+    }
+
     fn unregister(&mut self, registry: &Registry) {
         if self.is_registered {
-            for metric in self.metrics.iter() {
-                let metric = metric.to_collector();
+            for metric in self.collectors() {
                 registry
                     .unregister(metric)
                     .unwrap_or_else(|e| panic!("error while un-registering metric: {e}"));
@@ -183,171 +748,1065 @@ impl QueryMetrics {
             self.is_registered = false;
         };
     }
-}
-
-pub async fn compose_reply() -> Result<impl warp::Reply, Infallible> {
-    let registry = prometheus::default_registry();
-    debug!("compose_reply: preparing metrics, registry={registry:?}");
-
-    let mut buffer = vec![];
-    let encoder = TextEncoder::new();
-    let metric_families = registry.gather();
-    encoder
-        .encode(&metric_families, &mut buffer)
-        .unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
-
-    Ok(String::from_utf8(buffer).unwrap_or_else(|e| panic!("looks like a BUG: {e}")))
-}
 
-pub async fn collecting_task(
-    scrape_config: ScrapeConfig,
-    shutdown_channel: ShutdownReceiver,
-) -> Result<(), PsqlExporterError> {
-    debug!("collecting_task: config={scrape_config:?}");
-    let mut handler_index: usize = 0;
-    let (tx, mut rx) = mpsc::channel(scrape_config.len());
-    let sources = scrape_config.sources;
-    for (_, source_db_instance) in sources {
-        let databases = source_db_instance.databases;
-        for database in databases {
-            let tx = tx.clone();
-            let shut_rx = shutdown_channel.clone();
-            tokio::spawn(async move {
-                let handler_result = collect_one_db_instance(database, shut_rx).await;
-                let send_result = tx
-                    .send(handler_index)
-                    .await
-                    .map_err(PsqlExporterError::MetricsBackStatusSend);
-
-                if let Err(result) = handler_result {
-                    match result {
-                        PsqlExporterError::ShutdownSignalReceived => {
-                            debug!("collect db task #{handler_index} completed by shutdown signal");
-                            Ok(())
-                        }
-                        _ => {
-                            error!("collect db task completed unexpectedly: {result}");
-                            Err(result)
+    // Restored values are exposed as stale until the first successful query
+    fn restore(
+        &mut self,
+        state: &State,
+        tenant: Option<&str>,
+        metric_name: &str,
+        registry: &Registry,
+    ) {
+        let mut restored = 0;
+        for ((metric, label_values), last_seen) in self
+            .metrics
+            .iter()
+            .zip(self.label_values.iter_mut())
+            .zip(self.series_last_seen.iter_mut())
+        {
+            let name = metric.name();
+            for series in state.series(&name, tenant) {
+                match metric.restore(series) {
+                    Some(values) => {
+                        if !values.is_empty() {
+                            last_seen.insert(values.clone(), state.saved_at());
+                            label_values.insert(values);
                         }
+                        restored += 1;
                     }
-                } else if let Err(result) = send_result {
-                    Err(result)
-                } else {
-                    handler_result
+                    None => debug!("saved series {series:?} doesn't match metric {name}, skipped"),
                 }
-            });
-            handler_index += 1;
+            }
+        }
+        if restored == 0 {
+            return;
         }
-    }
 
-    debug!("collecting_task: {handler_index} handlers have been started");
+        debug!("{restored} series of metric {metric_name} have been restored");
+        self.register(registry);
+        self.last_updated = state.saved_at();
+        if let Some(stale) = &self.stale {
+            stale.set(1);
+        }
+        let restored_from_state = RESTORED_FROM_STATE.with_label_values(&[metric_name]);
+        restored_from_state.set(1);
+        self.restored_from_state = Some(restored_from_state);
+    }
 
-    while let Some(task_index) = rx.recv().await {
-        debug!("collecting_task: collecting_task_handler #{task_index} has been completed");
-        handler_index -= 1;
-        if handler_index == 0 {
-            info!("collecting_task: all tasks have been stopped, exiting");
-            return Ok(());
+    fn reset(&mut self, registry: &Registry) {
+        self.unregister(registry);
+        for metric in self.metrics.iter() {
+            metric.reset();
+        }
+        for label_values in self.label_values.iter_mut() {
+            label_values.clear();
+        }
+        for last_seen in self.series_last_seen.iter_mut() {
+            last_seen.clear();
         }
     }
+}
 
-    Ok(())
+#[derive(Serialize, Debug, PartialEq)]
+pub struct MetricDefinition {
+    name: String,
+    #[serde(rename = "type")]
+    metric_type: String,
+    help: String,
+    const_labels: BTreeMap<String, String>,
+    var_labels: Vec<String>,
 }
 
-async fn collect_one_db_instance(
-    database: ScrapeConfigDatabase,
-    shutdown_channel: ShutdownReceiver,
-) -> Result<(), PsqlExporterError> {
-    debug!("collect_one_db_instance: start task for {database:?}");
-    let certificates =
-        PostgresSslCertificates::from(database.sslrootcert, database.sslcert, database.sslkey)?;
-    let mut db_connection = PostgresConnection::new(
-        database.connection_string,
-        database.sslmode.unwrap(),
-        certificates,
-        database.backoff_interval,
-        database.max_backoff_interval,
-        shutdown_channel.clone(),
+impl MetricDefinition {
+    fn from(desc: &Desc, metric_type: &str) -> Self {
+        Self {
+            name: desc.fq_name.clone(),
+            metric_type: metric_type.to_string(),
+            help: desc.help.clone(),
+            const_labels: desc
+                .const_label_pairs
+                .iter()
+                .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                .collect(),
+            var_labels: desc.variable_labels.clone(),
+        }
+    }
+}
+
+fn static_metric_gauge(static_metric: &StaticMetric) -> Result<Gauge, PsqlExporterError> {
+    let opts = opts!(
+        static_metric.name.clone(),
+        static_metric
+            .description
+            .clone()
+            .unwrap_or_else(|| static_metric.name.clone())
     )
-    .await?;
+    .const_labels(static_metric.const_labels.clone());
 
-    let registry = prometheus::default_registry();
-    let mut query_metrics: Vec<QueryMetrics> = Vec::with_capacity(database.queries.len());
-    let mut sleeper = SleepHelper::from(shutdown_channel.clone());
+    let gauge = Gauge::with_opts(opts).map_err(|e| PsqlExporterError::CreateMetric {
+        metric: static_metric.name.clone(),
+        cause: e,
+    })?;
+    gauge.set(static_metric.value);
 
-    for q in database.queries.iter() {
-        let metric = QueryMetrics::from(q)?;
-        query_metrics.push(metric);
+    Ok(gauge)
+}
+
+// Static metrics don't depend on any source, so they are registered once and never change
+pub fn register_static_metrics(scrape_config: &ScrapeConfig) -> Result<(), PsqlExporterError> {
+    for static_metric in scrape_config.static_metrics.iter() {
+        let gauge = static_metric_gauge(static_metric)?;
+        prometheus::register(Box::new(gauge)).map_err(|e| PsqlExporterError::CreateMetric {
+            metric: static_metric.name.clone(),
+            cause: e,
+        })?;
+    }
+
+    Ok(())
+}
+
+// Definitions of all metrics which can be produced by enabled sources, without querying DBs
+pub fn list_metrics(
+    scrape_config: &ScrapeConfig,
+) -> Result<Vec<MetricDefinition>, PsqlExporterError> {
+    let mut definitions = vec![];
+    for static_metric in scrape_config.static_metrics.iter() {
+        definitions.extend(
+            static_metric_gauge(static_metric)?
+                .desc()
+                .into_iter()
+                .map(|desc| MetricDefinition::from(desc, "gauge")),
+        );
+    }
+    for source in scrape_config.sources.values() {
+        if !source.is_enabled() {
+            continue;
+        }
+        for database in source.databases.iter() {
+            for query in database.queries.iter() {
+                // broken queries don't stop the exporter, they're reported by collectors
+                let Ok((metrics, stale)) = QueryMetrics::create_metrics(query) else {
+                    warn!("unable to create metrics of query '{}'", query.metric_name);
+                    continue;
+                };
+                for metric in metrics.iter() {
+                    let metric_type = match metric {
+                        MetricWithType::Summary(_) => "summary",
+                        MetricWithType::Histogram(_) => "histogram",
+                        _ => "gauge",
+                    };
+                    let collector = metric.to_collector();
+                    definitions.extend(
+                        collector
+                            .desc()
+                            .into_iter()
+                            .map(|desc| MetricDefinition::from(desc, metric_type)),
+                    );
+                }
+                let null_count = QueryMetrics::create_null_count(query).ok().flatten();
+                for gauge in stale.into_iter().chain(null_count) {
+                    definitions.extend(
+                        gauge
+                            .desc()
+                            .into_iter()
+                            .map(|desc| MetricDefinition::from(desc, "gauge")),
+                    );
+                }
+            }
+        }
+    }
+
+    definitions.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(definitions)
+}
+
+pub fn save_state(filename: &str) -> Result<(), PsqlExporterError> {
+    let mut series = saved_series(prometheus::default_registry(), None);
+    let tenants = TENANT_REGISTRIES
+        .read()
+        .unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
+    for (tenant, registry) in tenants.iter() {
+        series.extend(saved_series(registry, Some(tenant)));
+    }
+
+    State::new(series).save(std::path::Path::new(filename))
+}
+
+// Summaries aren't saved since they can't be restored from the exposed values
+fn saved_series(registry: &Registry, tenant: Option<&str>) -> Vec<SavedSeries> {
+    registry
+        .gather()
+        .iter()
+        .filter(|family| family.get_field_type() == MetricType::GAUGE)
+        .flat_map(|family| {
+            family.get_metric().iter().map(|metric| SavedSeries {
+                name: family.get_name().to_string(),
+                tenant: tenant.map(String::from),
+                labels: metric
+                    .get_label()
+                    .iter()
+                    .map(|label| (label.get_name().to_string(), label.get_value().to_string()))
+                    .collect(),
+                value: metric.get_gauge().get_value(),
+            })
+        })
+        .collect()
+}
+
+pub async fn list_reply(
+    definitions: Arc<Vec<MetricDefinition>>,
+) -> Result<impl warp::Reply, Infallible> {
+    Ok(warp::reply::json(definitions.as_ref()))
+}
+
+fn tenant_registry(tenant: &str) -> Registry {
+    TENANT_REGISTRIES
+        .write()
+        .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+        .entry(tenant.to_string())
+        .or_default()
+        .clone()
+}
+
+// Metrics of all tenants are exposed with additional tenant label to avoid collisions
+fn gather_all() -> Vec<MetricFamily> {
+    let mut families: BTreeMap<String, MetricFamily> = prometheus::default_registry()
+        .gather()
+        .into_iter()
+        .map(|family| (family.get_name().to_string(), family))
+        .collect();
+
+    let tenants = TENANT_REGISTRIES
+        .read()
+        .unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
+    for (tenant, registry) in tenants.iter() {
+        for mut family in registry.gather() {
+            for metric in family.mut_metric().iter_mut() {
+                let mut label = LabelPair::new();
+                label.set_name(String::from(TENANT_LABEL));
+                label.set_value(tenant.clone());
+                metric.mut_label().push(label);
+            }
+            match families.get_mut(family.get_name()) {
+                // the combined family can't have series of different types or descriptions
+                Some(existing)
+                    if existing.get_field_type() != family.get_field_type()
+                        || existing.get_help() != family.get_help() =>
+                {
+                    warn!(
+                        "gather_all: metric {} of tenant {tenant} differs in type or help from the same metric of other tenant, skipped",
+                        family.get_name()
+                    );
+                }
+                Some(existing) => existing.mut_metric().extend(family.take_metric()),
+                None => {
+                    families.insert(family.get_name().to_string(), family);
+                }
+            }
+        }
+    }
+
+    let mut families = families.into_values().collect();
+    apply_families_hooks(&mut families);
+    // hooks may append families to the end, output is kept sorted by name for stable diffs
+    families.sort_by(|a: &MetricFamily, b| a.get_name().cmp(b.get_name()));
+    families
+}
+
+// Hooks are called in order of registration on each gathering of all metrics (/metrics and stdout output,
+// but not tenant endpoints), each hook gets families modified by the previous ones and may add, change
+// or drop any of them. Hooks are called concurrently from web server tasks, so they should be Send + Sync
+// and fast enough to not delay scrapes. There are no callers in the exporter itself,
+// this is an extension point for builds which embed the exporter as a library.
+pub fn add_families_hook(hook: impl Fn(&mut Vec<MetricFamily>) + Send + Sync + 'static) {
+    FAMILIES_HOOKS
+        .write()
+        .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+        .push(Box::new(hook));
+}
+
+fn apply_families_hooks(families: &mut Vec<MetricFamily>) {
+    let hooks = FAMILIES_HOOKS
+        .read()
+        .unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
+    for hook in hooks.iter() {
+        hook(families);
+    }
+}
+
+pub async fn compose_reply(accept: Option<String>) -> Result<impl warp::Reply, Infallible> {
+    debug!("compose_reply: preparing metrics");
+    Ok(encode_reply(&gather_all(), accept))
+}
+
+pub async fn compose_tenant_reply(
+    tenant: String,
+    accept: Option<String>,
+) -> Result<impl warp::Reply, Infallible> {
+    debug!("compose_tenant_reply: preparing metrics of tenant {tenant}");
+    let registry = TENANT_REGISTRIES
+        .read()
+        .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+        .get(&tenant)
+        .cloned();
+
+    match registry {
+        Some(registry) => Ok(warp::reply::with_status(
+            encode_reply(&registry.gather(), accept),
+            StatusCode::OK,
+        )),
+        None => Ok(warp::reply::with_status(
+            encode_reply(&[], accept),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+fn encode_reply(metric_families: &[MetricFamily], accept: Option<String>) -> impl warp::Reply {
+    let mut buffer = vec![];
+    let format_type = if accept.is_some_and(|accept| accept.contains(PROTOBUF_CONTENT_TYPE)) {
+        let encoder = ProtobufEncoder::new();
+        encoder
+            .encode(metric_families, &mut buffer)
+            .unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
+        encoder.format_type().to_string()
+    } else {
+        let encoder = TextEncoder::new();
+        encoder
+            .encode(metric_families, &mut buffer)
+            .unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
+        encoder.format_type().to_string()
+    };
+
+    warp::reply::with_header(buffer, warp::http::header::CONTENT_TYPE, format_type)
+}
+
+#[derive(Debug)]
+struct Draining;
+
+impl warp::reject::Reject for Draining {}
+
+pub fn reject_while_draining(
+    draining: ShutdownReceiver,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(move || {
+            let is_draining = *draining.borrow();
+            async move {
+                if is_draining {
+                    Err(warp::reject::custom(Draining))
+                } else {
+                    Ok(())
+                }
+            }
+        })
+        .untuple_one()
+}
+
+pub async fn draining_reply(rejection: Rejection) -> Result<impl warp::Reply, Rejection> {
+    if rejection.find::<Draining>().is_some() {
+        Ok(warp::reply::with_status(
+            String::from("exporter is shutting down\n"),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ))
+    } else {
+        Err(rejection)
+    }
+}
+
+pub async fn printing_task(interval: Duration, mut out: impl Write, shutdown: ShutdownReceiver) {
+    let mut sleeper = SleepHelper::from(shutdown);
+
+    loop {
+        if let Err(e) = print_metrics(&mut out) {
+            error!("unable to print metrics: {e}");
+        }
+        if sleeper.sleep(interval).await.is_err() {
+            break;
+        }
+    }
+
+    info!("metrics printing task has been finished");
+}
+
+fn print_metrics(out: &mut impl Write) -> std::io::Result<()> {
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&gather_all(), &mut buffer)
+        .unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
+
+    out.write_all(&buffer)?;
+    writeln!(out, "{STDOUT_DELIMITER}")?;
+    out.flush()
+}
+
+pub async fn reset_reply(
+    authorization: Option<String>,
+    admin_token: Option<String>,
+    reset_channel: ResetSender,
+) -> Result<impl warp::Reply, Infallible> {
+    let Some(admin_token) = admin_token else {
+        return Ok(warp::reply::with_status(
+            String::from("admin endpoints are disabled\n"),
+            StatusCode::NOT_FOUND,
+        ));
+    };
+
+    // constant-time comparison doesn't reveal how many leading characters of the token are right
+    let expected = format!("Bearer {admin_token}");
+    let authorized = authorization.is_some_and(|authorization| {
+        authorization.len() == expected.len()
+            && openssl::memcmp::eq(authorization.as_bytes(), expected.as_bytes())
+    });
+    if !authorized {
+        warn!("reset_reply: unauthorized request to reset metrics");
+        return Ok(warp::reply::with_status(
+            String::from("unauthorized\n"),
+            StatusCode::UNAUTHORIZED,
+        ));
+    }
+
+    info!("reset_reply: resetting all metrics");
+    reset_channel.send_replace(());
+    Ok(warp::reply::with_status(
+        String::from("metrics have been reset\n"),
+        StatusCode::OK,
+    ))
+}
+
+// Collector of one database, it's stopped as soon as it's dropped (with its stop channel)
+struct RunningCollector {
+    id: usize,
+    source_name: String,
+    database: ScrapeConfigDatabase,
+    _stop_channel: ShutdownSender,
+}
+
+struct CollectorSpawner {
+    next_id: usize,
+    tx: mpsc::Sender<usize>,
+    shutdown_channel: ShutdownReceiver,
+    reset_channel: ResetReceiver,
+    query_limiter: QueryLimiter,
+    dedicated_threads: bool,
+}
+
+impl CollectorSpawner {
+    fn spawn(
+        &mut self,
+        source_name: String,
+        database: ScrapeConfigDatabase,
+        restore_state: bool,
+    ) -> Result<RunningCollector, PsqlExporterError> {
+        let handler_index = self.next_id;
+        self.next_id += 1;
+        let tx = self.tx.clone();
+        let shut_rx = self.shutdown_channel.clone();
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let reset_rx = self.reset_channel.clone();
+        let query_limiter = self.query_limiter.clone();
+        let thread_name = format!("{source_name}/{}", database.dbname);
+        internal_metrics::set_connection_status(&database.connection_string.id(), 0, false);
+        let running = RunningCollector {
+            id: handler_index,
+            source_name: source_name.clone(),
+            database: database.clone(),
+            _stop_channel: stop_tx,
+        };
+        let collector = async move {
+            let handler_result = collect_one_db_instance(
+                source_name,
+                database,
+                shut_rx,
+                stop_rx,
+                reset_rx,
+                query_limiter,
+                restore_state,
+            )
+            .await;
+            let send_result = tx
+                .send(handler_index)
+                .await
+                .map_err(PsqlExporterError::MetricsBackStatusSend);
+
+            if let Err(result) = handler_result {
+                match result {
+                    PsqlExporterError::ShutdownSignalReceived => {
+                        debug!("collect db task #{handler_index} completed by shutdown signal");
+                        Ok(())
+                    }
+                    _ => {
+                        error!("collect db task completed unexpectedly: {result}");
+                        Err(result)
+                    }
+                }
+            } else if let Err(result) = send_result {
+                Err(result)
+            } else {
+                handler_result
+            }
+        };
+        if self.dedicated_threads {
+            spawn_on_dedicated_thread(thread_name.clone(), collector).map_err(|e| {
+                PsqlExporterError::SpawnCollectorThread {
+                    thread: thread_name,
+                    cause: e,
+                }
+            })?;
+        } else {
+            tokio::spawn(collector);
+        }
+
+        Ok(running)
+    }
+}
+
+fn enabled_databases(scrape_config: ScrapeConfig) -> Vec<(String, ScrapeConfigDatabase)> {
+    let mut databases = vec![];
+    for (source_name, source_db_instance) in scrape_config.sources {
+        if !source_db_instance.is_enabled() {
+            info!("collecting_task: source '{source_name}' is disabled, skipping");
+            continue;
+        }
+        for database in source_db_instance.databases {
+            databases.push((source_name.clone(), database));
+        }
+    }
+
+    databases
+}
+
+// Collectors of unchanged databases are kept running, the rest of them are returned to stop
+fn diff_collectors(
+    running: Vec<RunningCollector>,
+    databases: &mut Vec<(String, ScrapeConfigDatabase)>,
+) -> (Vec<RunningCollector>, Vec<RunningCollector>) {
+    let mut kept = vec![];
+    let mut changed = vec![];
+    for collector in running {
+        let unchanged = databases.iter().position(|(source_name, database)| {
+            *source_name == collector.source_name && *database == collector.database
+        });
+        match unchanged {
+            Some(position) => {
+                databases.remove(position);
+                kept.push(collector);
+            }
+            None => changed.push(collector),
+        }
+    }
+
+    (kept, changed)
+}
+
+pub async fn collecting_task(
+    scrape_config: ScrapeConfig,
+    mut shutdown_channel: ShutdownReceiver,
+    reset_channel: ResetReceiver,
+    mut reload_channel: ReloadReceiver,
+    load_config: impl Fn() -> Result<ScrapeConfig, PsqlExporterError>,
+    query_limiter: QueryLimiter,
+    dedicated_threads: bool,
+) -> Result<(), PsqlExporterError> {
+    debug!("collecting_task: config={scrape_config:?}");
+    let (tx, mut rx) = mpsc::channel(scrape_config.len().max(1));
+    let databases = enabled_databases(scrape_config);
+    let mut spawner = CollectorSpawner {
+        next_id: 0,
+        tx,
+        shutdown_channel: shutdown_channel.clone(),
+        reset_channel,
+        query_limiter,
+        dedicated_threads,
+    };
+
+    let mut collectors = vec![];
+    for (source_name, database) in databases {
+        collectors.push(spawner.spawn(source_name, database, true)?);
     }
+    debug!(
+        "collecting_task: {} handlers have been started",
+        collectors.len()
+    );
 
+    // New collectors are started after the old ones have unregistered their metrics
+    let mut stopping: HashSet<usize> = HashSet::new();
+    let mut pending: Vec<(String, ScrapeConfigDatabase)> = vec![];
     loop {
-        for (query_item, index) in database.queries.iter().zip(0..query_metrics.len()) {
-            if query_metrics[index].next_query_time > SystemTime::now() {
+        tokio::select! {
+            Some(task_index) = rx.recv() => {
+                debug!("collecting_task: collecting_task_handler #{task_index} has been completed");
+                if !stopping.remove(&task_index) {
+                    collectors.retain(|collector| collector.id != task_index);
+                    if collectors.is_empty() && stopping.is_empty() && pending.is_empty() {
+                        info!("collecting_task: all tasks have been stopped, exiting");
+                        return Ok(());
+                    }
+                }
+                if stopping.is_empty() {
+                    for (source_name, database) in pending.drain(..) {
+                        collectors.push(spawner.spawn(source_name, database, false)?);
+                    }
+                }
+            }
+            Ok(_) = reload_channel.changed() => {
+                let mut databases = match load_config() {
+                    Ok(scrape_config) => enabled_databases(scrape_config),
+                    Err(e) => {
+                        error!("unable to reload config, running config is kept: {e}");
+                        continue;
+                    }
+                };
+                let (kept, changed) = diff_collectors(std::mem::take(&mut collectors), &mut databases);
+                info!(
+                    "config has been reloaded: {} collectors kept, {} stopped, {} started",
+                    kept.len(),
+                    changed.len(),
+                    databases.len()
+                );
+                collectors = kept;
+                stopping.extend(changed.iter().map(|collector| collector.id));
+                pending = databases;
+                if stopping.is_empty() {
+                    for (source_name, database) in pending.drain(..) {
+                        collectors.push(spawner.spawn(source_name, database, false)?);
+                    }
+                }
+            }
+            Ok(_) = shutdown_channel.changed() => {
+                pending.clear();
+                stopping.extend(collectors.drain(..).map(|collector| collector.id));
+                if stopping.is_empty() {
+                    info!("collecting_task: all tasks have been stopped, exiting");
+                    return Ok(());
+                }
+            }
+        }
+
+        if *shutdown_channel.borrow() && stopping.is_empty() {
+            info!("collecting_task: all tasks have been stopped, exiting");
+            return Ok(());
+        }
+    }
+}
+
+async fn collect_one_db_instance(
+    source_name: String,
+    mut database: ScrapeConfigDatabase,
+    shutdown_channel: ShutdownReceiver,
+    stop_channel: ShutdownReceiver,
+    mut reset_channel: ResetReceiver,
+    query_limiter: QueryLimiter,
+    restore_state: bool,
+) -> Result<(), PsqlExporterError> {
+    debug!("collect_one_db_instance: start task for {database:?}");
+    let client_encoding = database.connection_string.client_encoding.clone();
+    let own_registry = database.tenant.as_deref().map(tenant_registry);
+    let registry = own_registry
+        .as_ref()
+        .unwrap_or(prometheus::default_registry());
+    let mut query_metrics = CollectorMetrics {
+        query_metrics: Vec::with_capacity(database.queries.len()),
+        registry,
+        shutdown_channel,
+        source_name: source_name.clone(),
+        dbname: database.dbname.clone(),
+        connection_id: database.connection_string.id(),
+    };
+    let mut sleeper = SleepHelper::from(stop_channel.clone());
+
+    // Broken queries are excluded, so the rest of them are still collected
+    let mut queries = Vec::with_capacity(database.queries.len());
+    let mut config_error = None;
+    for q in std::mem::take(&mut database.queries) {
+        let mut metric = match QueryMetrics::from(&q) {
+            Ok(metric) => metric,
+            Err(e) => {
+                error!("query of metric '{}' is skipped: {e}", q.metric_name);
+                config_error = Some(e);
                 continue;
             }
+        };
+        metric.delay_first_query(database.start_offset);
+        if let Some(tags) = &q.tags {
+            QUERY_TAGS.set(&source_name, &database.dbname, &q.metric_name, tags);
+        }
+        // restored values are available while connection isn't established yet
+        if let Some(state) = state::restored().filter(|_| restore_state) {
+            metric.restore(state, database.tenant.as_deref(), &q.metric_name, registry);
+        }
+        query_metrics.push(metric);
+        queries.push(q);
+    }
+    database.queries = queries;
+    if let (true, Some(e)) = (query_metrics.is_empty(), config_error) {
+        return Err(e);
+    }
+
+    // database is down until the first successful round of queries
+    internal_metrics::set_database_up(&source_name, &database.dbname, false);
+    let certificates =
+        PostgresSslCertificates::from(database.sslrootcert, database.sslcert, database.sslkey)?
+            .with_crl(database.sslcrl);
+    let mut pool = Vec::with_capacity(database.pool_size.get());
+    for pool_index in 0..database.pool_size.get() {
+        pool.push(
+            PostgresConnection::new(
+                database.connection_string.clone(),
+                database.sslmode.clone().unwrap(),
+                database.verify_ca_allow.clone().unwrap(),
+                database.reconnect_on_error_codes.clone().unwrap(),
+                certificates.clone(),
+                database.backoff_interval,
+                database.max_backoff_interval,
+                stop_channel.clone(),
+                pool_index,
+            )
+            .await?,
+        );
+    }
+
+    let priorities: Vec<i32> = database.queries.iter().map(|q| q.priority).collect();
+    let execution_order = queries_execution_order(&priorities);
+    let mut jitter = Jitter::new(
+        database.scrape_jitter,
+        RandomState::new().hash_one(database.connection_string.id()),
+    );
+    let mut circuit_breaker = CircuitBreaker::new(
+        database.circuit_breaker_failures,
+        database.circuit_breaker_cooldown,
+    );
+    internal_metrics::set_circuit_open(&source_name, &database.dbname, false);
 
-            let result = db_connection
-                .query(&query_item.query, query_item.query_timeout)
-                .await;
+    loop {
+        // None if no query was due in this round
+        let mut round_succeeded: Option<bool> = None;
+        // breaker counts rounds without any successful query only
+        let mut round_has_success = false;
+        let now = SystemTime::now();
+        let due_queries: Vec<usize> = execution_order
+            .iter()
+            .copied()
+            .filter(|index| query_metrics[*index].next_query_time <= now)
+            .collect();
+        // queries of the same batch run concurrently, each one on its own connection of the pool
+        for batch in due_queries.chunks(pool.len()) {
+            let results = join_all(pool.iter_mut().zip(batch).map(|(db_connection, index)| {
+                fetch_query_result(db_connection, &database.queries[*index], &query_limiter)
+            }))
+            .await;
+            for ((index, result), db_connection) in
+                batch.iter().copied().zip(results).zip(pool.iter_mut())
+            {
+                let query_item = &database.queries[index];
+                let query_labels = [
+                    source_name.as_str(),
+                    database.dbname.as_str(),
+                    query_item.metric_name.as_str(),
+                ];
+                let Some((result, query_duration)) = result else {
+                    query_metrics[index].next_query_time = next_query_time(query_item, &mut jitter);
+                    continue;
+                };
+                if result.is_ok() {
+                    internal_metrics::set_query_duration(
+                        &source_name,
+                        &database.dbname,
+                        &query_item.metric_name,
+                        query_duration,
+                    );
+                }
+                let result = result.and_then(|rows| {
+                    let columns = rows.first().map(|row| row.columns()).unwrap_or_default();
+                    check_duplicate_columns(
+                        &query_item.metric_name,
+                        columns.iter().map(|column| column.name()),
+                    )?;
+                    if !rows.is_empty() {
+                        let names: Vec<&str> = columns.iter().map(|column| column.name()).collect();
+                        check_referenced_columns(query_item, &names)?;
+                    }
+                    Ok(limit_rows(rows, query_item.max_rows, &query_labels))
+                });
 
-            match result {
-                Ok(result) => {
-                    query_metrics[index].register(registry);
-                    match &query_item.values {
-                        ScrapeConfigValues::ValueFrom(value) => {
-                            if let Some(field) = &value.field {
-                                update_metrics(
+                match result {
+                    Ok(result) => {
+                        round_succeeded.get_or_insert(true);
+                        round_has_success = true;
+                        internal_metrics::set_query_last_success(
+                            &source_name,
+                            &database.dbname,
+                            &query_item.metric_name,
+                            SystemTime::now(),
+                        );
+                        // count rows once per query result, not per each value of multi-value queries
+                        ROWS_PROCESSED_TOTAL.inc_by(result.len() as u64);
+                        query_metrics[index].update_interval_drift(
+                            &source_name,
+                            &database.dbname,
+                            &query_item.metric_name,
+                            query_item.scrape_interval,
+                        );
+                        query_metrics[index].register(registry);
+                        let label_values: Result<Vec<LabelValues>, PsqlExporterError> =
+                            match &query_item.values {
+                                ScrapeConfigValues::ValueFrom(value)
+                                    if query_item.is_templated() =>
+                                {
+                                    update_templated_metrics(
+                                        &result,
+                                        value,
+                                        query_item,
+                                        &client_encoding,
+                                        &mut query_metrics[index],
+                                        registry,
+                                        &query_labels,
+                                    )
+                                }
+                                ScrapeConfigValues::ValueFrom(value) => update_metrics(
                                     &result,
-                                    Some(field),
-                                    &query_item.var_labels,
+                                    value.field.as_ref(),
+                                    value.aggregate.as_ref(),
+                                    &ValueTransform::new(value.scale, value.offset)
+                                        .with_resolution(value.resolution),
+                                    query_item,
+                                    &client_encoding,
                                     &query_metrics[index].metrics[0],
+                                    query_metrics[index].null_count.as_ref(),
                                 )
-                            } else {
-                                update_metrics(
+                                .map(|label_values| vec![label_values]),
+                                ScrapeConfigValues::ValuesWithLabels(values) => values
+                                    .iter()
+                                    .zip(&query_metrics[index].metrics)
+                                    .map(|(value, metric)| {
+                                        update_metrics(
+                                            &result,
+                                            Some(&value.field),
+                                            None,
+                                            &ValueTransform::new(value.scale, value.offset)
+                                                .with_resolution(value.resolution),
+                                            query_item,
+                                            &client_encoding,
+                                            metric,
+                                            query_metrics[index].null_count.as_ref(),
+                                        )
+                                    })
+                                    .collect(),
+                                ScrapeConfigValues::ValuesWithSuffixes(values) => values
+                                    .iter()
+                                    .zip(&query_metrics[index].metrics)
+                                    .map(|(value, metric)| {
+                                        update_metrics(
+                                            &result,
+                                            Some(&value.field),
+                                            None,
+                                            &ValueTransform::new(value.scale, value.offset)
+                                                .with_resolution(value.resolution),
+                                            query_item,
+                                            &client_encoding,
+                                            metric,
+                                            query_metrics[index].null_count.as_ref(),
+                                        )
+                                    })
+                                    .collect(),
+                                ScrapeConfigValues::Summary(fields) => update_summary(
                                     &result,
-                                    None,
-                                    &query_item.var_labels,
+                                    fields,
+                                    query_item,
+                                    &client_encoding,
                                     &query_metrics[index].metrics[0],
                                 )
-                            }
-                        }
-                        ScrapeConfigValues::ValuesWithLabels(values) => {
-                            for (value, metric) in values.iter().zip(&query_metrics[index].metrics)
-                            {
-                                update_metrics(
+                                .map(|label_values| vec![label_values]),
+                                ScrapeConfigValues::Info => update_info(
                                     &result,
-                                    Some(&value.field),
-                                    &query_item.var_labels,
-                                    metric,
+                                    query_item,
+                                    &client_encoding,
+                                    &query_metrics[index].metrics[0],
                                 )
-                            }
-                        }
-                        ScrapeConfigValues::ValuesWithSuffixes(values) => {
-                            for (value, metric) in values.iter().zip(&query_metrics[index].metrics)
-                            {
-                                update_metrics(
+                                .map(|label_values| vec![label_values]),
+                                ScrapeConfigValues::KeyValue(fields) => update_key_values(
+                                    &result,
+                                    fields,
+                                    query_item,
+                                    &client_encoding,
+                                    &query_metrics[index].metrics[0],
+                                )
+                                .map(|label_values| vec![label_values]),
+                                ScrapeConfigValues::Histogram(fields) => update_histogram(
+                                    &result,
+                                    fields,
+                                    query_item,
+                                    &client_encoding,
+                                    &query_metrics[index].metrics[0],
+                                )
+                                .map(|label_values| vec![label_values]),
+                                ScrapeConfigValues::StateSet(fields) => update_state_set(
+                                    &result,
+                                    fields,
+                                    query_item,
+                                    &client_encoding,
+                                    &query_metrics[index].metrics[0],
+                                )
+                                .map(|label_values| vec![label_values]),
+                                ScrapeConfigValues::LsnLag(fields) => update_lsn_lag(
                                     &result,
-                                    Some(&value.field),
-                                    &query_item.var_labels,
-                                    metric,
+                                    fields,
+                                    query_item,
+                                    &client_encoding,
+                                    &query_metrics[index].metrics[0],
                                 )
+                                .map(|label_values| vec![label_values]),
+                            };
+
+                        match label_values {
+                            Ok(label_values) => {
+                                query_metrics[index].expire_series(
+                                    &label_values,
+                                    query_item.metric_expiration_time,
+                                );
+                                if query_item.prune_missing_labels {
+                                    query_metrics[index].prune_missing_labels(label_values);
+                                }
+                            }
+                            Err(e) => {
+                                internal_metrics::count_query_error(
+                                    &source_name,
+                                    &database.dbname,
+                                    &query_item.metric_name,
+                                );
+                                error!("{e}")
+                            }
+                        }
+
+                        if let ScrapeConfigValues::ValueFrom(value) = &query_item.values {
+                            if let Some(assertion) = &value.assertion {
+                                let exposed_value =
+                                    single_value(&result, value, &query_item.nan_policy).map(|v| {
+                                        match value.field_type {
+                                            FieldType::Int => v,
+                                            _ => ValueTransform::new(value.scale, value.offset)
+                                                .with_resolution(value.resolution)
+                                                .apply(v),
+                                        }
+                                    });
+                                set_assertion(
+                                    &source_name,
+                                    &database.dbname,
+                                    &query_item.metric_name,
+                                    assertion,
+                                    exposed_value,
+                                );
+                            }
+                            if let Some(shadow_query) = &value.shadow_query {
+                                let shadow_result = db_connection
+                                    .query(
+                                        shadow_query,
+                                        query_item.query_timeout,
+                                        query_item.backoff_interval,
+                                        query_item.max_backoff_interval,
+                                        query_item.max_retries,
+                                        &query_limiter,
+                                    )
+                                    .await;
+                                match shadow_result {
+                                    Ok(shadow_result) => set_shadow_diff(
+                                        &source_name,
+                                        &database.dbname,
+                                        &query_item.metric_name,
+                                        single_value(&result, value, &query_item.nan_policy),
+                                        single_value(&shadow_result, value, &query_item.nan_policy),
+                                    ),
+                                    Err(e) => warn!(
+                                        "shadow query of metric {} failed: {e}",
+                                        query_item.metric_name
+                                    ),
+                                }
                             }
                         }
                     }
-                }
-                Err(e) => {
-                    if query_item.metric_expiration_time != Duration::ZERO {
-                        let expiration_time =
-                            query_metrics[index].last_updated + query_item.metric_expiration_time;
-                        if SystemTime::now() > expiration_time {
-                            debug!("deregister metrics as expired");
-                            query_metrics[index].unregister(registry);
+                    Err(e) => {
+                        round_succeeded = Some(false);
+                        internal_metrics::count_query_error(
+                            &source_name,
+                            &database.dbname,
+                            &query_item.metric_name,
+                        );
+                        if is_timeout(&e) {
+                            query_metrics[index].timeout(
+                                &source_name,
+                                &database.dbname,
+                                query_item,
+                                registry,
+                            );
+                        } else {
+                            query_metrics[index]
+                                .expire(query_item.metric_expiration_time, registry);
                         }
+                        let sqlstate = match &e {
+                            PsqlExporterError::PostgresQuery { cause, .. }
+                            | PsqlExporterError::StatementTimeout { cause, .. } => {
+                                cause.code().map(|c| c.code())
+                            }
+                            _ => None,
+                        };
+                        count_query_timeout(
+                            &source_name,
+                            &database.dbname,
+                            &query_item.metric_name,
+                            &e,
+                        );
+                        error_log::write(&ErrorRecord {
+                            database: &database.dbname,
+                            query: &query_item.query,
+                            sqlstate,
+                            error: &e.to_string(),
+                        });
+                        error!("{e}")
+                    }
+                };
+                query_metrics[index].next_query_time = next_query_time(query_item, &mut jitter);
+            }
+        }
+        if database.connection_info {
+            // attributes are cached, so only the first round after (re)connect queries them
+            for db_connection in pool.iter_mut() {
+                if let Err(e) = db_connection.attributes().await {
+                    warn!(
+                        "unable to get connection attributes of {}: {e}",
+                        database.dbname
+                    );
+                }
+            }
+        }
+        if let Some(up) = round_succeeded {
+            internal_metrics::set_database_up(&source_name, &database.dbname, up);
+            let was_open = circuit_breaker.is_open();
+            match circuit_breaker.record(round_has_success) {
+                Some(cooldown) => {
+                    if !was_open {
+                        warn!(
+                            "all queries to database '{}' of source '{source_name}' failed {} times in a row, pausing them for {}",
+                            database.dbname,
+                            database.circuit_breaker_failures,
+                            cooldown.human_duration()
+                        );
+                    }
+                    let resume_time = SystemTime::now() + cooldown;
+                    for metric in query_metrics.iter_mut() {
+                        metric.next_query_time = metric.next_query_time.max(resume_time);
                     }
-                    error!("{e}")
                 }
-            };
-            query_metrics[index].next_query_time = SystemTime::now() + query_item.scrape_interval;
+                None if was_open => info!(
+                    "database '{}' of source '{source_name}' has recovered, queries are resumed",
+                    database.dbname
+                ),
+                None => {}
+            }
+            internal_metrics::set_circuit_open(
+                &source_name,
+                &database.dbname,
+                circuit_breaker.is_open(),
+            );
         }
 
         let next_query_time = query_metrics
@@ -373,64 +1832,3149 @@ async fn collect_one_db_instance(
             );
         }
 
-        sleeper.sleep(sleep_time).await?;
+        tokio::select! {
+            result = sleeper.sleep(sleep_time) => result?,
+            Ok(_) = reset_channel.changed() => {
+                debug!("reset metrics of DB '{}'", database.dbname);
+                for metrics in query_metrics.iter_mut() {
+                    metrics.reset(registry);
+                }
+            }
+        }
     }
 }
 
-fn update_metrics(
-    rows: &[Row],
-    field: Option<&str>,
-    var_labels: &Option<Vec<String>>,
-    metric: &MetricWithType,
-) {
-    match metric {
-        MetricWithType::SingleInt(metric) => {
-            if let Some(field) = field {
-                metric.set(rows[0].get(field))
-            } else {
-                metric.set(rows[0].get(0))
-            }
+// Metrics of the collector stopped by config reload are unregistered, so the new collector can register them again,
+// but they're kept on shutdown to be saved to the state file
+struct CollectorMetrics<'a> {
+    query_metrics: Vec<QueryMetrics>,
+    registry: &'a Registry,
+    shutdown_channel: ShutdownReceiver,
+    source_name: String,
+    dbname: String,
+    connection_id: String,
+}
+
+impl Deref for CollectorMetrics<'_> {
+    type Target = Vec<QueryMetrics>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.query_metrics
+    }
+}
+
+impl DerefMut for CollectorMetrics<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.query_metrics
+    }
+}
+
+impl Drop for CollectorMetrics<'_> {
+    fn drop(&mut self) {
+        if *self.shutdown_channel.borrow() {
+            return;
         }
-        MetricWithType::SingleFloat(metric) => {
-            if let Some(field) = field {
-                metric.set(rows[0].get(field))
-            } else {
-                metric.set(rows[0].get(0))
-            }
+        for metrics in self.query_metrics.iter_mut() {
+            metrics.unregister(self.registry);
         }
-        MetricWithType::VectorInt(metric) => {
-            for row in rows {
-                let mut new_labels: Vec<String> = vec![];
-                if let Some(labels) = var_labels {
-                    for label in labels {
-                        new_labels.push(row.get(label.as_str()));
-                    }
-                    let new_labels: Vec<&str> = new_labels.iter().map(AsRef::as_ref).collect();
-                    let new_labels: &[&str] = new_labels.as_slice();
-                    if let Some(field) = field {
-                        metric.with_label_values(new_labels).set(row.get(field));
-                    } else {
-                        metric.with_label_values(new_labels).set(row.get(0));
-                    }
-                }
-            }
+        internal_metrics::forget_database(&self.source_name, &self.dbname, &self.connection_id);
+    }
+}
+
+// Jitter isn't applied to queries aligned to the clock, they are synchronized on purpose
+fn next_query_time(query_item: &ScrapeConfigQuery, jitter: &mut Jitter) -> SystemTime {
+    let now = SystemTime::now();
+    if query_item.align_to_clock {
+        aligned_query_time(now, query_item.scrape_interval)
+    } else {
+        jitter.apply(now + query_item.scrape_interval).max(now)
+    }
+}
+
+// Guard query is run before the main one, None is returned if it doesn't allow the main query
+async fn fetch_query_result(
+    db_connection: &mut PostgresConnection,
+    query_item: &ScrapeConfigQuery,
+    query_limiter: &QueryLimiter,
+) -> Option<(Result<Vec<Row>, PsqlExporterError>, Duration)> {
+    if let Some(guard_query) = &query_item.guard_query {
+        let guard_result = db_connection
+            .query(
+                guard_query,
+                query_item.query_timeout,
+                query_item.backoff_interval,
+                query_item.max_backoff_interval,
+                query_item.max_retries,
+                query_limiter,
+            )
+            .await
+            .map(|rows| rows.first().and_then(|row| row.try_get::<_, bool>(0).ok()));
+        if !guard_allows_query(&query_item.metric_name, guard_result) {
+            return None;
         }
-        MetricWithType::VectorFloat(metric) => {
-            for row in rows {
-                let mut new_labels: Vec<String> = vec![];
-                if let Some(labels) = var_labels {
-                    for label in labels {
-                        new_labels.push(row.get(label.as_str()));
-                    }
-                    let new_labels: Vec<&str> = new_labels.iter().map(AsRef::as_ref).collect();
-                    let new_labels: &[&str] = new_labels.as_slice();
-                    if let Some(field) = field {
-                        metric.with_label_values(new_labels).set(row.get(field));
-                    } else {
-                        metric.with_label_values(new_labels).set(row.get(0));
-                    }
-                }
-            }
+    }
+
+    let query_started_at = Instant::now();
+    let result = db_connection
+        .query(
+            &query_item.query,
+            query_item.query_timeout,
+            query_item.backoff_interval,
+            query_item.max_backoff_interval,
+            query_item.max_retries,
+            query_limiter,
+        )
+        .await;
+
+    Some((result, query_started_at.elapsed()))
+}
+
+// Main query runs only if guard query returns true in the first column of the first row
+fn guard_allows_query(
+    metric_name: &str,
+    guard_result: Result<Option<bool>, PsqlExporterError>,
+) -> bool {
+    match guard_result {
+        Ok(Some(true)) => true,
+        Ok(_) => {
+            debug!("query of metric {metric_name} has been skipped by guard query");
+            false
         }
+        Err(e) => {
+            warn!("guard query of metric {metric_name} failed, query has been skipped: {e}");
+            false
+        }
+    }
+}
+
+// Returns the nearest wall-clock boundary of the interval after the specified time
+fn aligned_query_time(now: SystemTime, interval: Duration) -> SystemTime {
+    let interval = interval.as_millis();
+    if interval == 0 {
+        return now;
+    }
+
+    let since_epoch = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let next_boundary = (since_epoch / interval + 1) * interval;
+
+    SystemTime::UNIX_EPOCH + Duration::from_millis(next_boundary as u64)
+}
+
+fn queries_execution_order(priorities: &[i32]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..priorities.len()).collect();
+    order.sort_by_key(|index| std::cmp::Reverse(priorities[*index]));
+    order
+}
+
+fn try_get_field<'a, T: FromSql<'a>>(
+    row: &'a Row,
+    field: Option<&Field>,
+) -> Result<T, tokio_postgres::Error> {
+    match field {
+        Some(Field::Name(name)) => row.try_get(name.as_str()),
+        Some(Field::Index(index)) => row.try_get(*index),
+        None => row.try_get(0),
+    }
+}
+
+// Doesn't panic on NULLs and unsupported column types, NULL is returned as None
+// if the caller can handle it
+fn get_value<'a, T: FromSql<'a>>(
+    row: &'a Row,
+    field: Option<&Field>,
+    row_index: usize,
+    nullable: bool,
+) -> Result<Option<T>, PsqlExporterError> {
+    let value = match try_get_field::<Option<NullProbe>>(row, field) {
+        Ok(None) if nullable => return Ok(None),
+        _ => try_get_field::<T>(row, field),
+    };
+
+    value.map(Some).map_err(|e| {
+        let column = match field {
+            Some(Field::Name(name)) => row.columns().iter().find(|c| c.name() == name),
+            Some(Field::Index(index)) => row.columns().get(*index),
+            None => row.columns().first(),
+        };
+        PsqlExporterError::InvalidFieldValue {
+            column: column.map(|c| c.name().to_string()).unwrap_or_default(),
+            oid: column.map(|c| c.type_().oid()).unwrap_or_default(),
+            row: row_index,
+            cause: e,
+        }
+    })
+}
+
+fn count_null<T>(value: Option<T>, null_count: Option<&IntGauge>) -> Option<T> {
+    if let (None, Some(null_count)) = (&value, null_count) {
+        null_count.inc();
+    }
+    value
+}
+
+// NULL in a label column gives an empty value, the same as the absent label
+fn var_label_values(
+    row: &Row,
+    row_index: usize,
+    query_config: &ScrapeConfigQuery,
+    client_encoding: &ClientEncoding,
+) -> Result<Vec<String>, PsqlExporterError> {
+    query_config
+        .var_labels
+        .iter()
+        .flatten()
+        .map(|label| {
+            let value = client_encoding.label_value(row, label, row_index)?;
+            Ok(query_config.label_value(label, value.unwrap_or_default()))
+        })
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn update_metrics<R: Borrow<Row>>(
+    rows: &[R],
+    field: Option<&Field>,
+    aggregate: Option<&AggregateFunction>,
+    transform: &ValueTransform,
+    query_config: &ScrapeConfigQuery,
+    client_encoding: &ClientEncoding,
+    metric: &MetricWithType,
+    null_count: Option<&IntGauge>,
+) -> Result<LabelValues, PsqlExporterError> {
+    let mut label_values = LabelValues::new();
+    // empty result leaves single values as is and produces no series of vectors
+    if rows.is_empty() {
+        return Ok(label_values);
+    }
+    let nan_policy = &query_config.nan_policy;
+    let null_value = query_config.null_value;
+    let nullable = query_config.skip_null || null_value.is_some();
+    // NULL is replaced with null_value if it's set or skipped otherwise, type of null_value is validated on load
+    let int_value = |index: usize, row: &Row| -> Result<Option<i64>, PsqlExporterError> {
+        let value = count_null(get_value::<i64>(row, field, index, nullable)?, null_count);
+        Ok(value.or_else(|| null_value.and_then(|value| value.as_i64())))
+    };
+    let float_value = |index: usize, row: &Row| -> Result<Option<f64>, PsqlExporterError> {
+        let value = count_null(
+            get_value::<FloatValue>(row, field, index, nullable)?,
+            null_count,
+        );
+        Ok(value
+            .map(|value| value.0)
+            .or_else(|| null_value.map(|value| value.as_f64())))
+    };
+
+    match metric {
+        MetricWithType::SingleInt(metric) => {
+            if let Some(aggregate) = aggregate {
+                let values = rows
+                    .iter()
+                    .enumerate()
+                    .map(|(index, row)| int_value(index, row.borrow()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let values: Vec<i64> = values.into_iter().flatten().collect();
+                if let Some(value) = aggregate_values(aggregate, &values) {
+                    metric.set(value)
+                }
+            } else if let Some(value) = int_value(0, rows[0].borrow())? {
+                metric.set(value)
+            }
+        }
+        MetricWithType::SingleFloat(metric) => {
+            if let Some(aggregate) = aggregate {
+                let values = rows
+                    .iter()
+                    .enumerate()
+                    .map(|(index, row)| float_value(index, row.borrow()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let values: Vec<f64> = values
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|value| nan_policy.apply(value))
+                    .collect();
+                if let Some(value) = aggregate_values(aggregate, &values) {
+                    metric.set(transform.apply(value))
+                }
+            } else if let Some(value) =
+                float_value(0, rows[0].borrow())?.and_then(|value| nan_policy.apply(value))
+            {
+                metric.set(transform.apply(value))
+            }
+        }
+        MetricWithType::VectorInt(metric) => {
+            // rows with skipped NULLs don't produce series at all
+            let rows_values = rows
+                .iter()
+                .enumerate()
+                .filter_map(|(index, row)| {
+                    let row = row.borrow();
+                    int_value(index, row).transpose().map(|value| {
+                        let new_labels =
+                            var_label_values(row, index, query_config, client_encoding)?;
+                        value.map(|value| (new_labels, Some(value)))
+                    })
+                })
+                .collect::<Result<Vec<_>, PsqlExporterError>>()?;
+            label_values = set_vector_values(
+                metric,
+                &query_config.metric_name,
+                rows_values.into_iter(),
+                &query_config.duplicate_labels,
+            )?;
+        }
+        MetricWithType::VectorFloat(metric) => {
+            let rows_values = rows
+                .iter()
+                .enumerate()
+                .filter_map(|(index, row)| {
+                    let row = row.borrow();
+                    float_value(index, row).transpose().map(|value| {
+                        let new_labels =
+                            var_label_values(row, index, query_config, client_encoding)?;
+                        value.map(|value| {
+                            let value = nan_policy.apply(value).map(|value| transform.apply(value));
+                            (new_labels, value)
+                        })
+                    })
+                })
+                .collect::<Result<Vec<_>, PsqlExporterError>>()?;
+            label_values = set_vector_values(
+                metric,
+                &query_config.metric_name,
+                rows_values.into_iter(),
+                &query_config.duplicate_labels,
+            )?;
+        }
+        MetricWithType::Summary(_) => {
+            panic!("looks like a BUG: summary metric should be updated using update_summary")
+        }
+        MetricWithType::Histogram(_) => {
+            panic!("looks like a BUG: histogram metric should be updated using update_histogram")
+        }
+    }
+
+    Ok(label_values)
+}
+
+// Rows are grouped by the rendered metric name, and each group updates its own family as a regular single value,
+// dropped rows are counted with query labels (source, database and metric name)
+fn update_templated_metrics(
+    rows: &[Row],
+    value: &FieldWithType,
+    query_config: &ScrapeConfigQuery,
+    client_encoding: &ClientEncoding,
+    query_metrics: &mut QueryMetrics,
+    registry: &Registry,
+    query_labels: &[&str; 3],
+) -> Result<Vec<LabelValues>, PsqlExporterError> {
+    let mut groups: BTreeMap<String, Vec<&Row>> = BTreeMap::new();
+    let mut unnamed_rows = 0;
+    for (index, row) in rows.iter().enumerate() {
+        let mut placeholders = HashMap::new();
+        for column in query_config.name_placeholders() {
+            let value = client_encoding.label_value(row, &column, index)?;
+            placeholders.insert(column, value);
+        }
+        match query_config.render_metric_name(|column| placeholders[column].clone()) {
+            Some(metric_name) => groups.entry(metric_name).or_default().push(row),
+            None => unnamed_rows += 1,
+        }
+    }
+    if unnamed_rows > 0 {
+        warn!(
+            "{unnamed_rows} rows of metric {} are dropped: NULL in metric name placeholders",
+            query_config.metric_name
+        );
+        ROWS_DROPPED_TOTAL
+            .with_label_values(query_labels)
+            .inc_by(unnamed_rows);
+    }
+
+    let mut updates = Vec::with_capacity(groups.len());
+    for (metric_name, rows) in groups {
+        match query_metrics.family(&metric_name, value, query_config, registry)? {
+            Some(index) => updates.push((index, rows)),
+            None => {
+                warn!(
+                    "family {metric_name} of metric {} is dropped: too many families",
+                    query_config.metric_name
+                );
+                ROWS_DROPPED_TOTAL
+                    .with_label_values(query_labels)
+                    .inc_by(rows.len() as u64);
+            }
+        }
+    }
+
+    // families which are absent in the result get no label values, so they may be pruned or expired
+    let mut label_values = vec![LabelValues::new(); query_metrics.metrics.len()];
+    for (index, rows) in updates {
+        label_values[index] = update_metrics(
+            &rows,
+            value.field.as_ref(),
+            value.aggregate.as_ref(),
+            &ValueTransform::new(value.scale, value.offset).with_resolution(value.resolution),
+            query_config,
+            client_encoding,
+            &query_metrics.metrics[index],
+            query_metrics.null_count.as_ref(),
+        )?;
+    }
+
+    Ok(label_values)
+}
+
+// Columns are resolved by name, so the second column with the same name would be silently ignored
+fn check_duplicate_columns<'a>(
+    metric_name: &str,
+    columns: impl Iterator<Item = &'a str>,
+) -> Result<(), PsqlExporterError> {
+    let mut names = HashSet::new();
+    let mut duplicates: Vec<String> = vec![];
+    for name in columns {
+        if !names.insert(name) && !duplicates.iter().any(|d| d == name) {
+            duplicates.push(name.to_string());
+        }
+    }
+
+    if duplicates.is_empty() {
+        Ok(())
+    } else {
+        Err(PsqlExporterError::DuplicateColumns {
+            metric: metric_name.to_string(),
+            columns: duplicates,
+        })
+    }
+}
+
+// Rows beyond the limit are dropped, so the query should be ordered to keep its top rows
+fn limit_rows<T>(
+    mut rows: Vec<T>,
+    max_rows: Option<NonZeroUsize>,
+    query_labels: &[&str; 3],
+) -> Vec<T> {
+    if let Some(max_rows) = max_rows {
+        if rows.len() > max_rows.get() {
+            ROWS_DROPPED_TOTAL
+                .with_label_values(query_labels)
+                .inc_by((rows.len() - max_rows.get()) as u64);
+            rows.truncate(max_rows.get());
+        }
+    }
+
+    rows
+}
+
+// Missing column would fail each row with an obscure error, so it's reported once per result with the option name
+fn check_referenced_columns(
+    query_config: &ScrapeConfigQuery,
+    columns: &[&str],
+) -> Result<(), PsqlExporterError> {
+    for (option, field) in query_config.referenced_fields() {
+        let (value, exists) = match &field {
+            Field::Name(name) => (name.clone(), columns.contains(&name.as_str())),
+            Field::Index(index) => (index.to_string(), *index < columns.len()),
+        };
+        if !exists {
+            return Err(PsqlExporterError::InvalidConfigValue {
+                field: option,
+                value,
+                reason: format!(
+                    "column isn't returned by the query, available columns are {columns:?}"
+                ),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// Rows with the same labels are merged before update, so the policy doesn't depend on the previous values
+fn set_vector_values<P: Atomic>(
+    metric: &GenericGaugeVec<P>,
+    metric_name: &str,
+    rows_values: impl Iterator<Item = (Vec<String>, Option<P::T>)>,
+    duplicate_labels: &DuplicateLabels,
+) -> Result<LabelValues, PsqlExporterError> {
+    let mut values: Vec<(Vec<String>, Option<P::T>)> = vec![];
+    let mut indexes: HashMap<Vec<String>, usize> = HashMap::new();
+
+    for (new_labels, value) in rows_values {
+        let Some(&index) = indexes.get(&new_labels) else {
+            indexes.insert(new_labels.clone(), values.len());
+            values.push((new_labels, value));
+            continue;
+        };
+
+        let current = &mut values[index].1;
+        match duplicate_labels {
+            DuplicateLabels::Last => {
+                if value.is_some() {
+                    *current = value;
+                }
+            }
+            DuplicateLabels::Sum => match (current.as_mut(), value) {
+                (Some(current), Some(value)) => *current += value,
+                (None, value) => *current = value,
+                (Some(_), None) => {}
+            },
+            DuplicateLabels::Error => {
+                return Err(PsqlExporterError::DuplicateLabels {
+                    metric: metric_name.to_string(),
+                    labels: new_labels,
+                })
+            }
+        }
+    }
+
+    let mut label_values = LabelValues::new();
+    for (new_labels, value) in values {
+        if let Some(value) = value {
+            let labels: Vec<&str> = new_labels.iter().map(AsRef::as_ref).collect();
+            metric.with_label_values(&labels).set(value);
+        }
+        label_values.insert(new_labels);
+    }
+
+    Ok(label_values)
+}
+
+fn single_value(rows: &[Row], value: &FieldWithType, nan_policy: &NanPolicy) -> Option<f64> {
+    let field = value.field.as_ref();
+    match value.field_type {
+        FieldType::Int => {
+            let values: Vec<i64> = rows
+                .iter()
+                .enumerate()
+                .filter_map(|(index, row)| {
+                    get_value(row, field, index, true)
+                        .inspect_err(|e| warn!("{e}"))
+                        .ok()
+                        .flatten()
+                })
+                .collect();
+            match &value.aggregate {
+                Some(aggregate) => aggregate_values(aggregate, &values),
+                None => values.first().copied(),
+            }
+            .map(|value| value as f64)
+        }
+        FieldType::Float | FieldType::Timestamp => {
+            let values: Vec<f64> = rows
+                .iter()
+                .enumerate()
+                .filter_map(|(index, row)| {
+                    get_value::<FloatValue>(row, field, index, true)
+                        .inspect_err(|e| warn!("{e}"))
+                        .ok()
+                        .flatten()
+                })
+                .filter_map(|value| nan_policy.apply(value.0))
+                .collect();
+            match &value.aggregate {
+                Some(aggregate) => aggregate_values(aggregate, &values),
+                None => values.first().copied(),
+            }
+        }
+    }
+}
+
+// Stale diff would be misleading if one of the results is empty, so the series is removed
+fn set_shadow_diff(
+    source: &str,
+    database: &str,
+    metric_name: &str,
+    primary: Option<f64>,
+    shadow: Option<f64>,
+) {
+    let labels = [source, database, metric_name];
+    match (primary, shadow) {
+        (Some(primary), Some(shadow)) => SHADOW_DIFF
+            .with_label_values(&labels)
+            .set((primary - shadow).abs()),
+        _ => {
+            debug!("shadow diff of metric {metric_name} removed: empty result");
+            let _ = SHADOW_DIFF.remove_label_values(&labels);
+        }
+    }
+}
+
+// Empty result doesn't change the assertion state
+fn set_assertion(
+    source: &str,
+    database: &str,
+    metric_name: &str,
+    assertion: &ValueAssertion,
+    value: Option<f64>,
+) {
+    let Some(value) = value else {
+        debug!("assertion of metric {metric_name} skipped: empty result");
+        return;
+    };
+
+    let failed = !assertion.holds(value);
+    if failed {
+        warn!("value {value} of metric {metric_name} is out of asserted range {assertion:?}");
+    }
+    ASSERTION_FAILED
+        .with_label_values(&[source, database, metric_name])
+        .set(failed as i64);
+}
+
+// Client-side deadline usually fires together with the server-side statement_timeout,
+// so both of them mean the query is too slow rather than broken
+fn is_timeout(error: &PsqlExporterError) -> bool {
+    matches!(
+        error,
+        PsqlExporterError::QueryTimeout { .. } | PsqlExporterError::StatementTimeout { .. }
+    )
+}
+
+fn count_query_timeout(source: &str, database: &str, metric_name: &str, error: &PsqlExporterError) {
+    if is_timeout(error) {
+        QUERY_TIMEOUT_TOTAL
+            .with_label_values(&[source, database, metric_name])
+            .inc();
+    }
+}
+
+fn update_summary(
+    rows: &[Row],
+    fields: &SummaryFields,
+    query_config: &ScrapeConfigQuery,
+    client_encoding: &ClientEncoding,
+    metric: &MetricWithType,
+) -> Result<LabelValues, PsqlExporterError> {
+    let mut label_values = LabelValues::new();
+    let var_labels = &query_config.var_labels;
+    let MetricWithType::Summary(metric) = metric else {
+        panic!("looks like a BUG: summary values with non-summary metric");
+    };
+
+    let mut summaries: HashMap<Vec<String>, SummaryValue> = HashMap::new();
+    // any numeric column is accepted, NULL (like percentile_cont over an empty group) is skipped
+    let float_value = |index: usize, row: &Row, field: &Field| {
+        get_value::<FloatValue>(row, Some(field), index, true)
+            .map(|value| value.map(|value| value.0))
+    };
+    for (index, row) in rows.iter().enumerate() {
+        let new_labels = var_label_values(row, index, query_config, client_encoding)?;
+        let summary = summaries.entry(new_labels).or_default();
+        let quantile = float_value(index, row, &fields.quantile_field)?;
+        let value = float_value(index, row, &fields.value_field)?;
+        if let (Some(quantile), Some(value)) = (quantile, value) {
+            summary.quantiles.push((quantile, value));
+        }
+        if let Some(sum_field) = &fields.sum_field {
+            if let Some(sum) = float_value(index, row, sum_field)? {
+                summary.sum = sum;
+            }
+        }
+        if let Some(count_field) = &fields.count_field {
+            if let Some(count) = float_value(index, row, count_field)? {
+                summary.count = count as u64;
+            }
+        }
+    }
+
+    for (new_labels, mut summary) in summaries {
+        summary
+            .quantiles
+            .sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        if var_labels.is_some() {
+            label_values.insert(new_labels.clone());
+        }
+        metric.set(new_labels, summary);
+    }
+
+    Ok(label_values)
+}
+
+fn update_info(
+    rows: &[Row],
+    query_config: &ScrapeConfigQuery,
+    client_encoding: &ClientEncoding,
+    metric: &MetricWithType,
+) -> Result<LabelValues, PsqlExporterError> {
+    let rows_labels = rows
+        .iter()
+        .enumerate()
+        .map(|(index, row)| var_label_values(row, index, query_config, client_encoding))
+        .collect::<Result<Vec<_>, PsqlExporterError>>()?;
+
+    Ok(set_info(metric, rows_labels.into_iter()))
+}
+
+// Info metric has constant value 1, all the information is in the labels
+fn set_info(
+    metric: &MetricWithType,
+    rows_labels: impl Iterator<Item = Vec<String>>,
+) -> LabelValues {
+    let mut label_values = LabelValues::new();
+
+    match metric {
+        MetricWithType::SingleInt(metric) => metric.set(1),
+        MetricWithType::VectorInt(metric) => {
+            for new_labels in rows_labels {
+                let labels: Vec<&str> = new_labels.iter().map(AsRef::as_ref).collect();
+                metric.with_label_values(&labels).set(1);
+                label_values.insert(new_labels);
+            }
+        }
+        _ => panic!("looks like a BUG: info values with non-int metric"),
+    }
+
+    label_values
+}
+
+fn update_histogram(
+    rows: &[Row],
+    fields: &HistogramFields,
+    query_config: &ScrapeConfigQuery,
+    client_encoding: &ClientEncoding,
+    metric: &MetricWithType,
+) -> Result<LabelValues, PsqlExporterError> {
+    let MetricWithType::Histogram(metric) = metric else {
+        panic!("looks like a BUG: histogram values with non-histogram metric");
+    };
+
+    let observations = rows
+        .iter()
+        .enumerate()
+        .map(|(index, row)| {
+            let new_labels = var_label_values(row, index, query_config, client_encoding)?;
+            let value = get_value::<KeyValue>(row, Some(&fields.field), index, true)?;
+            Ok((new_labels, value.and_then(|value| value.0)))
+        })
+        .collect::<Result<Vec<_>, PsqlExporterError>>()?;
+
+    Ok(observe_histogram(
+        metric,
+        observations.into_iter(),
+        &query_config.nan_policy,
+    ))
+}
+
+// Each row is a single observation, so the query should return only values since the previous run
+fn observe_histogram(
+    metric: &HistogramVec,
+    observations: impl Iterator<Item = (Vec<String>, Option<f64>)>,
+    nan_policy: &NanPolicy,
+) -> LabelValues {
+    let mut label_values = LabelValues::new();
+
+    for (new_labels, value) in observations {
+        let labels: Vec<&str> = new_labels.iter().map(AsRef::as_ref).collect();
+        let histogram = metric.with_label_values(&labels);
+        if let Some(value) = value.and_then(|value| nan_policy.apply(value)) {
+            histogram.observe(value);
+        }
+        label_values.insert(new_labels);
+    }
+
+    label_values
+}
+
+fn update_state_set(
+    rows: &[Row],
+    fields: &StateSetFields,
+    query_config: &ScrapeConfigQuery,
+    client_encoding: &ClientEncoding,
+    metric: &MetricWithType,
+) -> Result<LabelValues, PsqlExporterError> {
+    let MetricWithType::VectorInt(metric) = metric else {
+        panic!("looks like a BUG: state set values with non-int-vector metric");
+    };
+
+    let rows_states = rows
+        .iter()
+        .enumerate()
+        .map(|(index, row)| {
+            let new_labels = var_label_values(row, index, query_config, client_encoding)?;
+            let state_column = match &fields.field {
+                Field::Name(name) => name.as_str(),
+                Field::Index(index) => row.columns()[*index].name(),
+            };
+            let state = client_encoding.label_value(row, state_column, index)?;
+            Ok((new_labels, state))
+        })
+        .collect::<Result<Vec<_>, PsqlExporterError>>()?;
+
+    Ok(set_state_set(
+        metric,
+        &fields.states,
+        rows_states.into_iter(),
+    ))
+}
+
+// Every configured state gets its own series, so the current one is 1 and the rest are 0,
+// row with NULL state (like background workers in pg_stat_activity) doesn't produce series at all
+fn set_state_set(
+    metric: &IntGaugeVec,
+    states: &[String],
+    rows_states: impl Iterator<Item = (Vec<String>, Option<String>)>,
+) -> LabelValues {
+    let mut label_values = LabelValues::new();
+
+    for (new_labels, current_state) in rows_states {
+        let Some(current_state) = current_state else {
+            debug!("NULL state of {new_labels:?}, row is skipped");
+            continue;
+        };
+        if !states.contains(&current_state) {
+            debug!("unknown state '{current_state}' of {new_labels:?}, all states are set to 0");
+        }
+        for state in states {
+            let mut new_labels = new_labels.clone();
+            new_labels.push(state.clone());
+            let labels: Vec<&str> = new_labels.iter().map(AsRef::as_ref).collect();
+            metric
+                .with_label_values(&labels)
+                .set((*state == current_state) as i64);
+            label_values.insert(new_labels);
+        }
+    }
+
+    label_values
+}
+
+fn update_lsn_lag(
+    rows: &[Row],
+    fields: &LsnLagFields,
+    query_config: &ScrapeConfigQuery,
+    client_encoding: &ClientEncoding,
+    metric: &MetricWithType,
+) -> Result<LabelValues, PsqlExporterError> {
+    let mut rows_values = vec![];
+    for (index, row) in rows.iter().enumerate() {
+        let lsn = get_value::<PgLsn>(row, Some(&fields.field), index, query_config.skip_null)?;
+        let base_lsn =
+            get_value::<PgLsn>(row, Some(&fields.base_field), index, query_config.skip_null)?;
+        // row with skipped NULL doesn't produce series at all
+        let (Some(lsn), Some(base_lsn)) = (lsn, base_lsn) else {
+            continue;
+        };
+        let new_labels = var_label_values(row, index, query_config, client_encoding)?;
+        rows_values.push((new_labels, Some(lsn_lag(base_lsn, lsn))));
+    }
+
+    match metric {
+        MetricWithType::SingleFloat(metric) => {
+            if let Some((_, Some(lag))) = rows_values.first() {
+                metric.set(*lag);
+            }
+            Ok(LabelValues::new())
+        }
+        MetricWithType::VectorFloat(metric) => set_vector_values(
+            metric,
+            &query_config.metric_name,
+            rows_values.into_iter(),
+            &query_config.duplicate_labels,
+        ),
+        _ => panic!("looks like a BUG: lsn lag values with non-float metric"),
+    }
+}
+
+// LSN is a byte position in WAL, so the difference is a number of bytes, negative if base is behind
+fn lsn_lag(base_lsn: PgLsn, lsn: PgLsn) -> f64 {
+    (u64::from(base_lsn) as i128 - u64::from(lsn) as i128) as f64
+}
+
+fn update_key_values(
+    rows: &[Row],
+    fields: &KeyValueFields,
+    query_config: &ScrapeConfigQuery,
+    client_encoding: &ClientEncoding,
+    metric: &MetricWithType,
+) -> Result<LabelValues, PsqlExporterError> {
+    let MetricWithType::VectorFloat(metric) = metric else {
+        panic!("looks like a BUG: key/value values with non-float-vector metric");
+    };
+
+    let mut key_values = vec![];
+    for (index, row) in rows.iter().enumerate() {
+        let key_column = match &fields.key_field {
+            Field::Name(name) => name.as_str(),
+            Field::Index(index) => row.columns()[*index].name(),
+        };
+        // row without a key can't be told apart from others
+        let Some(key) = client_encoding.label_value(row, key_column, index)? else {
+            debug!(
+                "row {index} of metric {} has NULL key, skipped",
+                query_config.metric_name
+            );
+            continue;
+        };
+        let mut new_labels = var_label_values(row, index, query_config, client_encoding)?;
+        new_labels.push(key);
+
+        let value = get_value::<KeyValue>(row, Some(&fields.value_field), index, true)?;
+        key_values.push((new_labels, value.and_then(|value| value.0)));
+    }
+
+    Ok(set_key_values(
+        metric,
+        key_values.into_iter(),
+        &query_config.nan_policy,
+    ))
+}
+
+fn set_key_values(
+    metric: &GaugeVec,
+    key_values: impl Iterator<Item = (Vec<String>, Option<f64>)>,
+    nan_policy: &NanPolicy,
+) -> LabelValues {
+    let mut label_values = LabelValues::new();
+
+    for (new_labels, value) in key_values {
+        let labels: Vec<&str> = new_labels.iter().map(AsRef::as_ref).collect();
+        match value.and_then(|value| nan_policy.apply(value)) {
+            Some(value) => metric.with_label_values(&labels).set(value),
+            None => debug!("key/value: non-numeric value of {new_labels:?} skipped"),
+        }
+        label_values.insert(new_labels);
+    }
+
+    label_values
+}
+
+// Accepts any column, so Option<NullProbe> tells whether the value is NULL regardless of its type
+struct NullProbe;
+
+impl<'a> FromSql<'a> for NullProbe {
+    fn from_sql(
+        _ty: &Type,
+        _raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        Ok(Self)
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+}
+
+struct FloatValue(f64);
+
+impl<'a> FromSql<'a> for FloatValue {
+    fn from_sql(
+        ty: &Type,
+        raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        let value = match *ty {
+            Type::FLOAT4 => f32::from_sql(ty, raw)? as f64,
+            Type::NUMERIC => numeric_to_f64(raw)?,
+            Type::TIMESTAMP | Type::TIMESTAMPTZ => epoch_seconds(SystemTime::from_sql(ty, raw)?),
+            _ => f64::from_sql(ty, raw)?,
+        };
+
+        Ok(Self(value))
+    }
+
+    // Missing timestamp (like "never happened") is NaN, so it's handled by nan_policy
+    fn from_sql_null(ty: &Type) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        match *ty {
+            Type::TIMESTAMP | Type::TIMESTAMPTZ => Ok(Self(f64::NAN)),
+            _ => Err(Box::new(WasNull)),
+        }
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        matches!(
+            *ty,
+            Type::FLOAT4 | Type::FLOAT8 | Type::NUMERIC | Type::TIMESTAMP | Type::TIMESTAMPTZ
+        )
+    }
+}
+
+fn epoch_seconds(time: SystemTime) -> f64 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    }
+}
+
+// Binary NUMERIC is a header (number of digits, weight, sign, scale) and base-10000 digits,
+// it's converted to decimal text to parse it without loss of precision
+fn numeric_to_f64(raw: &[u8]) -> Result<f64, Box<dyn std::error::Error + Sync + Send>> {
+    const NUMERIC_POS: u16 = 0x0000;
+    const NUMERIC_NEG: u16 = 0x4000;
+    const NUMERIC_NAN: u16 = 0xC000;
+    const NUMERIC_PINF: u16 = 0xD000;
+    const NUMERIC_NINF: u16 = 0xF000;
+
+    let word = |offset: usize| -> Result<[u8; 2], Box<dyn std::error::Error + Sync + Send>> {
+        raw.get(offset..offset + 2)
+            .map(|bytes| [bytes[0], bytes[1]])
+            .ok_or_else(|| "invalid NUMERIC value: too short".into())
+    };
+    let ndigits = i16::from_be_bytes(word(0)?);
+    let weight = i16::from_be_bytes(word(2)?);
+    let sign = u16::from_be_bytes(word(4)?);
+
+    let mut text = match sign {
+        NUMERIC_POS => String::new(),
+        NUMERIC_NEG => String::from("-"),
+        NUMERIC_NAN => return Ok(f64::NAN),
+        NUMERIC_PINF => return Ok(f64::INFINITY),
+        NUMERIC_NINF => return Ok(f64::NEG_INFINITY),
+        _ => return Err(format!("invalid NUMERIC value: unknown sign {sign:#06x}").into()),
+    };
+    if ndigits <= 0 {
+        return Ok(0.0);
+    }
+
+    for index in 0..ndigits as usize {
+        let digit = i16::from_be_bytes(word(8 + index * 2)?);
+        if !(0..10000).contains(&digit) {
+            return Err(format!("invalid NUMERIC value: wrong digit {digit}").into());
+        }
+        text.push_str(&format!("{digit:04}"));
+    }
+    let exponent = (weight as i32 - ndigits as i32 + 1) * 4;
+    text.push_str(&format!("e{exponent}"));
+
+    Ok(text.parse()?)
+}
+
+// Value of the key/value table: numbers as is, text and booleans are converted to number
+// if possible (like '128', 'on' or 'false'), everything else (and NULL) gives None
+struct KeyValue(Option<f64>);
+
+impl<'a> FromSql<'a> for KeyValue {
+    fn from_sql(
+        ty: &Type,
+        raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        let value = match *ty {
+            Type::INT2 => Some(i16::from_sql(ty, raw)? as f64),
+            Type::INT4 => Some(i32::from_sql(ty, raw)? as f64),
+            Type::INT8 => Some(i64::from_sql(ty, raw)? as f64),
+            Type::FLOAT4 => Some(f32::from_sql(ty, raw)? as f64),
+            Type::FLOAT8 => Some(f64::from_sql(ty, raw)?),
+            Type::NUMERIC => Some(numeric_to_f64(raw)?),
+            Type::BOOL => Some(bool::from_sql(ty, raw)? as u8 as f64),
+            _ => {
+                let text = String::from_utf8_lossy(raw);
+                let text = text.trim();
+                text.parse::<f64>()
+                    .ok()
+                    .or_else(|| parse_bool(text).map(|value| value as u8 as f64))
+            }
+        };
+
+        Ok(Self(value))
+    }
+
+    fn from_sql_null(_ty: &Type) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        Ok(Self(None))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        matches!(
+            *ty,
+            Type::INT2
+                | Type::INT4
+                | Type::INT8
+                | Type::FLOAT4
+                | Type::FLOAT8
+                | Type::NUMERIC
+                | Type::BOOL
+                | Type::TEXT
+                | Type::VARCHAR
+                | Type::BPCHAR
+                | Type::NAME
+                | Type::UNKNOWN
+        )
+    }
+}
+
+trait AggregateValue: Copy + PartialOrd {
+    fn sum(values: &[Self]) -> Self;
+    fn avg(values: &[Self]) -> Self;
+}
+
+// Ints are summed up in i128, so the sum saturates instead of overflow and the average is exact
+impl AggregateValue for i64 {
+    fn sum(values: &[Self]) -> Self {
+        let sum: i128 = values.iter().map(|value| *value as i128).sum();
+        sum.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+
+    fn avg(values: &[Self]) -> Self {
+        let sum: i128 = values.iter().map(|value| *value as i128).sum();
+        (sum / values.len() as i128) as i64
+    }
+}
+
+impl AggregateValue for f64 {
+    fn sum(values: &[Self]) -> Self {
+        values.iter().sum()
+    }
+
+    fn avg(values: &[Self]) -> Self {
+        Self::sum(values) / values.len() as f64
+    }
+}
+
+fn aggregate_values<T: AggregateValue>(aggregate: &AggregateFunction, values: &[T]) -> Option<T> {
+    let (first, rest) = values.split_first()?;
+    let result = match aggregate {
+        AggregateFunction::Sum => T::sum(values),
+        AggregateFunction::Min => rest
+            .iter()
+            .fold(*first, |acc, v| if *v < acc { *v } else { acc }),
+        AggregateFunction::Max => rest
+            .iter()
+            .fold(*first, |acc, v| if *v > acc { *v } else { acc }),
+        AggregateFunction::Avg => T::avg(values),
+        AggregateFunction::Last => *values.last()?,
+    };
+
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregate_three_rows() {
+        let int_rows: Vec<i64> = vec![3, 10, 5];
+        let float_rows: Vec<f64> = vec![1.5, 0.5, 4.0];
+
+        assert_eq!(
+            aggregate_values(&AggregateFunction::Sum, &int_rows),
+            Some(18)
+        );
+        assert_eq!(
+            aggregate_values(&AggregateFunction::Min, &int_rows),
+            Some(3)
+        );
+        assert_eq!(
+            aggregate_values(&AggregateFunction::Max, &int_rows),
+            Some(10)
+        );
+        assert_eq!(
+            aggregate_values(&AggregateFunction::Avg, &int_rows),
+            Some(6)
+        );
+        assert_eq!(
+            aggregate_values(&AggregateFunction::Last, &int_rows),
+            Some(5)
+        );
+
+        assert_eq!(
+            aggregate_values(&AggregateFunction::Sum, &float_rows),
+            Some(6.0)
+        );
+        assert_eq!(
+            aggregate_values(&AggregateFunction::Avg, &float_rows),
+            Some(2.0)
+        );
+
+        let huge_rows: Vec<i64> = vec![i64::MAX, i64::MAX - 1, 1];
+        assert_eq!(
+            aggregate_values(&AggregateFunction::Sum, &huge_rows),
+            Some(i64::MAX)
+        );
+        assert_eq!(
+            aggregate_values(&AggregateFunction::Avg, &huge_rows[..2]),
+            Some(i64::MAX - 1)
+        );
+    }
+
+    #[test]
+    fn align_to_clock_boundary() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_012_345);
+
+        assert_eq!(
+            aligned_query_time(now, Duration::from_secs(60)),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_040)
+        );
+        assert_eq!(
+            aligned_query_time(now, Duration::from_secs(3600)),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_002_800)
+        );
+
+        // exactly at the boundary, the next one is used
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_040);
+        assert_eq!(
+            aligned_query_time(now, Duration::from_secs(60)),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_100)
+        );
+    }
+
+    #[test]
+    fn higher_priority_queries_go_first() {
+        assert_eq!(queries_execution_order(&[0, 10, -5, 10]), vec![1, 3, 0, 2]);
+        assert_eq!(queries_execution_order(&[0, 0, 0]), vec![0, 1, 2]);
+        assert_eq!(queries_execution_order(&[]), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn prune_disappeared_row() {
+        let opts = opts!("test_prune_disappeared_row", "test metric");
+        let gauge = IntGaugeVec::new(opts, &["slot"]).unwrap();
+        let mut query_metrics = QueryMetrics {
+            label_values: vec![LabelValues::new()],
+            series_last_seen: vec![HashMap::new()],
+            metrics: vec![MetricWithType::VectorInt(gauge.clone())],
+            stale: None,
+            null_count: None,
+            restored_from_state: None,
+            timed_out: None,
+            families: HashMap::new(),
+            is_registered: false,
+            last_updated: SystemTime::now(),
+            last_succeeded: None,
+            next_query_time: SystemTime::now(),
+        };
+        let pruned = || {
+            SERIES_PRUNED_TOTAL
+                .with_label_values(&["test_prune_disappeared_row"])
+                .get()
+        };
+
+        gauge.with_label_values(&["a"]).set(1);
+        gauge.with_label_values(&["b"]).set(2);
+        query_metrics.prune_missing_labels(vec![LabelValues::from([
+            vec![String::from("a")],
+            vec![String::from("b")],
+        ])]);
+        assert_eq!(pruned(), 0);
+
+        // row "b" disappears from the next result
+        gauge.with_label_values(&["a"]).set(3);
+        query_metrics.prune_missing_labels(vec![LabelValues::from([vec![String::from("a")]])]);
+        assert_eq!(pruned(), 1);
+        assert_eq!(gauge.collect()[0].get_metric().len(), 1);
+    }
+
+    #[test]
+    fn reset_clears_and_repopulates() {
+        let registry = Registry::new();
+        let opts = opts!("test_reset_clears_and_repopulates", "test metric");
+        let gauge = IntGaugeVec::new(opts, &["slot"]).unwrap();
+        let mut query_metrics = QueryMetrics {
+            label_values: vec![LabelValues::new()],
+            series_last_seen: vec![HashMap::new()],
+            metrics: vec![MetricWithType::VectorInt(gauge.clone())],
+            stale: None,
+            null_count: None,
+            restored_from_state: None,
+            timed_out: None,
+            families: HashMap::new(),
+            is_registered: false,
+            last_updated: SystemTime::now(),
+            last_succeeded: None,
+            next_query_time: SystemTime::now(),
+        };
+
+        query_metrics.register(&registry);
+        gauge.with_label_values(&["a"]).set(1);
+        gauge.with_label_values(&["b"]).set(2);
+        assert_eq!(registry.gather()[0].get_metric().len(), 2);
+
+        query_metrics.reset(&registry);
+        assert!(registry.gather().is_empty());
+
+        // next scrape
+        query_metrics.register(&registry);
+        gauge.with_label_values(&["a"]).set(3);
+        let families = registry.gather();
+        assert_eq!(families[0].get_metric().len(), 1);
+        assert_eq!(families[0].get_metric()[0].get_gauge().get_value(), 3.0);
+    }
+
+    #[tokio::test]
+    async fn scrapes_are_rejected_while_collectors_drain() {
+        let signal_handler = crate::utils::SignalHandler::new().unwrap();
+        let route = reject_while_draining(signal_handler.get_draining_channel())
+            .and(warp::path!("metrics").map(|| "metrics"))
+            .recover(draining_reply);
+        let scrape = |route| async move {
+            warp::test::request()
+                .path("/metrics")
+                .reply(&route)
+                .await
+                .status()
+        };
+        assert_eq!(scrape(route.clone()).await, StatusCode::OK);
+
+        let mut collector_shutdown = signal_handler.get_rx_channel();
+        let collector_route = route.clone();
+        let collector = tokio::spawn(async move {
+            collector_shutdown
+                .wait_for(|shutdown| *shutdown)
+                .await
+                .unwrap();
+            // collector finishes its current cycle while scrapes are already rejected
+            let status = scrape(collector_route).await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(collector_shutdown);
+            (status, SystemTime::now())
+        });
+
+        signal_handler.shutdown().await;
+        let shutdown_completed = SystemTime::now();
+
+        let (status_during_drain, collector_finished) = collector.await.unwrap();
+        assert_eq!(status_during_drain, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(shutdown_completed >= collector_finished);
+        assert_eq!(scrape(route).await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn printing_task_writes_periodically() {
+        internal_metrics::init();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        let out = SharedBuffer::default();
+
+        let task = tokio::spawn(printing_task(
+            Duration::from_millis(50),
+            out.clone(),
+            shutdown_rx,
+        ));
+        tokio::time::sleep(Duration::from_millis(180)).await;
+        shutdown_tx.send(true).unwrap();
+        task.await.unwrap();
+
+        let output = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let snapshots: Vec<&str> = output
+            .split_terminator(&format!("{STDOUT_DELIMITER}\n"))
+            .collect();
+        assert!(snapshots.len() >= 3, "{output}");
+        for snapshot in snapshots {
+            assert!(snapshot.contains("psql_exporter_build_info{version="));
+            assert!(snapshot.contains("process_start_time_seconds "));
+        }
+    }
+
+    #[tokio::test]
+    async fn reset_requires_admin_token() {
+        let (tx, rx) = tokio::sync::watch::channel(());
+        let status =
+            |reply: Result<_, Infallible>| warp::Reply::into_response(reply.unwrap()).status();
+
+        let reply = reset_reply(None, None, tx.clone()).await;
+        assert_eq!(status(reply), StatusCode::NOT_FOUND);
+
+        let reply = reset_reply(
+            Some(String::from("Bearer wrong")),
+            Some(String::from("secret")),
+            tx.clone(),
+        )
+        .await;
+        assert_eq!(status(reply), StatusCode::UNAUTHORIZED);
+        assert!(!rx.has_changed().unwrap());
+
+        // tokens of other length and missing header are rejected as well
+        for authorization in [Some("Bearer secret2"), Some("Bearer secre"), None] {
+            let reply = reset_reply(
+                authorization.map(String::from),
+                Some(String::from("secret")),
+                tx.clone(),
+            )
+            .await;
+            assert_eq!(status(reply), StatusCode::UNAUTHORIZED);
+        }
+        assert!(!rx.has_changed().unwrap());
+
+        let reply = reset_reply(
+            Some(String::from("Bearer secret")),
+            Some(String::from("secret")),
+            tx.clone(),
+        )
+        .await;
+        assert_eq!(status(reply), StatusCode::OK);
+        assert!(rx.has_changed().unwrap());
+    }
+
+    #[test]
+    fn suppressed_suffix_description() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: test_suffix_description
+            description: Tuples
+            query: select 1 as live, 2 as dead, 3 as total
+            suffix_in_description: false
+            values:
+              multi_suffixes:
+                - field: live
+                  suffix: live
+                - field: dead
+                  suffix: dead
+                - field: total
+                  suffix: total
+                  description: Total number of tuples
+"#,
+        )
+        .unwrap();
+        let query_config = &config.sources["postgres"].databases[0].queries[0];
+        let registry = Registry::new();
+        let mut query_metrics = QueryMetrics::from(query_config).unwrap();
+        query_metrics.register(&registry);
+
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&registry.gather(), &mut buffer)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            r#"# HELP test_suffix_description_dead Tuples
+# TYPE test_suffix_description_dead gauge
+test_suffix_description_dead 0
+# HELP test_suffix_description_live Tuples
+# TYPE test_suffix_description_live gauge
+test_suffix_description_live 0
+# HELP test_suffix_description_total Total number of tuples
+# TYPE test_suffix_description_total gauge
+test_suffix_description_total 0
+"#
+        );
+    }
+
+    fn read_varint(buffer: &[u8]) -> (usize, usize) {
+        let mut value = 0;
+        for (index, byte) in buffer.iter().enumerate() {
+            value |= ((byte & 0x7f) as usize) << (7 * index);
+            if byte & 0x80 == 0 {
+                return (value, index + 1);
+            }
+        }
+        panic!("malformed varint");
+    }
+
+    #[tokio::test]
+    async fn protobuf_negotiation() {
+        let gauge = IntGauge::new("test_protobuf_negotiation", "test metric").unwrap();
+        gauge.set(42);
+        prometheus::register(Box::new(gauge)).unwrap();
+
+        let reply = compose_reply(Some(String::from(prometheus::PROTOBUF_FORMAT)))
+            .await
+            .unwrap();
+        let response = warp::Reply::into_response(reply);
+        assert_eq!(
+            response.headers()[warp::http::header::CONTENT_TYPE],
+            prometheus::PROTOBUF_FORMAT
+        );
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+
+        // length-delimited MetricFamily messages, field #1 of each one is a metric name
+        let mut names = vec![];
+        let mut body = &body[..];
+        while !body.is_empty() {
+            let (message_len, prefix_len) = read_varint(body);
+            let message = &body[prefix_len..prefix_len + message_len];
+            assert_eq!(message[0], 0x0a);
+            let (name_len, name_prefix_len) = read_varint(&message[1..]);
+            let name_start = 1 + name_prefix_len;
+            names.push(
+                String::from_utf8(message[name_start..name_start + name_len].to_vec()).unwrap(),
+            );
+            body = &body[prefix_len + message_len..];
+        }
+        assert!(names.contains(&String::from("test_protobuf_negotiation")));
+
+        let reply = compose_reply(None).await.unwrap();
+        let response = warp::Reply::into_response(reply);
+        assert_eq!(
+            response.headers()[warp::http::header::CONTENT_TYPE],
+            prometheus::TEXT_FORMAT
+        );
+    }
+
+    #[tokio::test]
+    async fn static_metric_in_reply() {
+        let config = ScrapeConfig::from_yaml(
+            r#"
+static_metrics:
+  - name: test_deployment_info
+    description: Deployment information
+    value: 1
+    const_labels:
+      version: 1.2.3
+sources: {}
+"#,
+        )
+        .unwrap();
+        register_static_metrics(&config).unwrap();
+
+        let reply = compose_reply(None).await.unwrap();
+        let body = warp::hyper::body::to_bytes(warp::Reply::into_response(reply).into_body())
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("# HELP test_deployment_info Deployment information\n"));
+        assert!(body.contains("test_deployment_info{version=\"1.2.3\"} 1\n"));
+
+        let definitions = list_metrics(&config).unwrap();
+        assert_eq!(definitions[0].name, "test_deployment_info");
+        assert_eq!(definitions[0].const_labels["version"], "1.2.3");
+
+        // the same metric can't be registered twice
+        assert!(register_static_metrics(&config).is_err());
+    }
+
+    #[test]
+    fn query_info_metric() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: test_query_info_metric
+            expose_query_info: true
+            query: |
+              select count(*)
+              from pg_stat_activity;
+"#,
+        )
+        .unwrap();
+        QueryMetrics::from(&config.sources["postgres"].databases[0].queries[0]).unwrap();
+
+        let families: Vec<_> = prometheus::gather()
+            .into_iter()
+            .filter(|f| f.get_name() == "psql_exporter_query_info")
+            .collect();
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&families, &mut buffer).unwrap();
+
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            r#"# HELP psql_exporter_query_info Text of the query which produces the metric
+# TYPE psql_exporter_query_info gauge
+psql_exporter_query_info{metric="test_query_info_metric",query="select count(*) from pg_stat_activity;"} 1
+"#
+        );
+    }
+
+    #[test]
+    fn query_info_truncation() {
+        let query = format!("select {}", "x".repeat(300));
+        let label = QueryMetrics::query_info_label(&query);
+        assert_eq!(label.chars().count(), QUERY_INFO_MAX_LENGTH + 3);
+        assert!(label.ends_with("..."));
+    }
+
+    #[test]
+    fn aggregate_empty_result() {
+        let rows: Vec<i64> = vec![];
+        assert_eq!(aggregate_values(&AggregateFunction::Sum, &rows), None);
+    }
+
+    #[test]
+    fn assertion_out_of_range() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: assertion_test_metric
+            query: select count(*) from pg_stat_activity
+            values:
+              single:
+                assert:
+                  min: 1
+                  max: 100
+"#,
+        )
+        .unwrap();
+        let query = &config.sources["postgres"].databases[0].queries[0];
+        let ScrapeConfigValues::ValueFrom(value) = &query.values else {
+            panic!("single value expected");
+        };
+        let assertion = value.assertion.as_ref().unwrap();
+        let failed = |database: &str| {
+            ASSERTION_FAILED
+                .with_label_values(&["postgres", database, "assertion_test_metric"])
+                .get()
+        };
+
+        set_assertion(
+            "postgres",
+            "app",
+            "assertion_test_metric",
+            assertion,
+            Some(42.0),
+        );
+        assert_eq!(failed("app"), 0);
+        set_assertion(
+            "postgres",
+            "app",
+            "assertion_test_metric",
+            assertion,
+            Some(101.0),
+        );
+        assert_eq!(failed("app"), 1);
+        // empty result keeps the state
+        set_assertion("postgres", "app", "assertion_test_metric", assertion, None);
+        assert_eq!(failed("app"), 1);
+        set_assertion(
+            "postgres",
+            "app",
+            "assertion_test_metric",
+            assertion,
+            Some(100.0),
+        );
+        assert_eq!(failed("app"), 0);
+        set_assertion(
+            "postgres",
+            "app",
+            "assertion_test_metric",
+            assertion,
+            Some(0.5),
+        );
+        assert_eq!(failed("app"), 1);
+        set_assertion(
+            "postgres",
+            "app",
+            "assertion_test_metric",
+            assertion,
+            Some(f64::NAN),
+        );
+        assert_eq!(failed("app"), 1);
+        // the same metric of other DB has its own state
+        set_assertion(
+            "postgres",
+            "billing",
+            "assertion_test_metric",
+            assertion,
+            Some(42.0),
+        );
+        assert_eq!(failed("billing"), 0);
+        assert_eq!(failed("app"), 1);
+    }
+
+    #[test]
+    fn shadow_query_differs() {
+        let labels = ["postgres", "app", "shadow_test_metric"];
+        let diff = || SHADOW_DIFF.with_label_values(&labels).get();
+
+        set_shadow_diff(
+            "postgres",
+            "app",
+            "shadow_test_metric",
+            Some(10.0),
+            Some(7.5),
+        );
+        assert_eq!(diff(), 2.5);
+        set_shadow_diff(
+            "postgres",
+            "app",
+            "shadow_test_metric",
+            Some(7.5),
+            Some(10.0),
+        );
+        assert_eq!(diff(), 2.5);
+        set_shadow_diff(
+            "postgres",
+            "app",
+            "shadow_test_metric",
+            Some(3.0),
+            Some(3.0),
+        );
+        assert_eq!(diff(), 0.0);
+
+        // the same metric of other DB is exposed separately
+        set_shadow_diff(
+            "postgres",
+            "billing",
+            "shadow_test_metric",
+            Some(1.0),
+            Some(5.0),
+        );
+        assert_eq!(
+            SHADOW_DIFF
+                .with_label_values(&["postgres", "billing", "shadow_test_metric"])
+                .get(),
+            4.0
+        );
+        assert_eq!(diff(), 0.0);
+
+        // empty result removes the series instead of keeping the stale diff
+        set_shadow_diff("postgres", "app", "shadow_test_metric", None, Some(3.0));
+        assert!(SHADOW_DIFF.remove_label_values(&labels).is_err());
+    }
+
+    #[test]
+    fn query_timeouts_are_counted() {
+        let timeouts = || {
+            QUERY_TIMEOUT_TOTAL
+                .with_label_values(&["postgres", "postgres", "timeout_test_metric"])
+                .get()
+        };
+
+        count_query_timeout(
+            "postgres",
+            "postgres",
+            "timeout_test_metric",
+            &PsqlExporterError::QueryTimeout {
+                query: String::from("select pg_sleep(10)"),
+            },
+        );
+        assert_eq!(timeouts(), 1);
+
+        count_query_timeout(
+            "postgres",
+            "postgres",
+            "timeout_test_metric",
+            &PsqlExporterError::ShutdownSignalReceived,
+        );
+        assert_eq!(timeouts(), 1);
+    }
+
+    #[test]
+    fn guard_toggles_execution() {
+        assert!(guard_allows_query("guarded", Ok(Some(true))));
+        assert!(!guard_allows_query("guarded", Ok(Some(false))));
+        assert!(!guard_allows_query("guarded", Ok(None)));
+        assert!(!guard_allows_query(
+            "guarded",
+            Err(PsqlExporterError::QueryTimeout {
+                query: String::from("select enabled from flags"),
+            })
+        ));
+    }
+
+    #[test]
+    fn key_value_settings_rows() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: app_settings
+            query: select key, value from settings
+            values:
+              key_value:
+                key_field: key
+                value_field: value
+"#,
+        )
+        .unwrap();
+        let query = &config.sources["postgres"].databases[0].queries[0];
+
+        let value = |ty: &Type, raw: &[u8]| KeyValue::from_sql(ty, raw).unwrap().0;
+        let rows = vec![
+            (
+                vec![String::from("max_connections")],
+                value(&Type::TEXT, b"100"),
+            ),
+            (
+                vec![String::from("work_mem_kb")],
+                value(&Type::INT8, &4096i64.to_be_bytes()),
+            ),
+            (
+                vec![String::from("ratio")],
+                value(&Type::FLOAT8, &0.25f64.to_be_bytes()),
+            ),
+            (vec![String::from("fsync")], value(&Type::TEXT, b"on")),
+            (vec![String::from("dry_run")], value(&Type::BOOL, &[0])),
+            (
+                vec![String::from("timezone")],
+                value(&Type::TEXT, b"Europe/Kyiv"),
+            ),
+            (
+                vec![String::from("unset")],
+                KeyValue::from_sql_null(&Type::TEXT).unwrap().0,
+            ),
+        ];
+
+        let registry = Registry::new();
+        let mut query_metrics = QueryMetrics::from(query).unwrap();
+        query_metrics.register(&registry);
+        let MetricWithType::VectorFloat(metric) = &query_metrics.metrics[0] else {
+            panic!("float vector metric expected");
+        };
+        let label_values = set_key_values(metric, rows.into_iter(), &query.nan_policy);
+        assert_eq!(label_values.len(), 7);
+
+        let families = registry.gather();
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].get_name(), "app_settings");
+        let series: Vec<(&str, &str, f64)> = families[0]
+            .get_metric()
+            .iter()
+            .map(|m| {
+                (
+                    m.get_label()[0].get_name(),
+                    m.get_label()[0].get_value(),
+                    m.get_gauge().get_value(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            series,
+            vec![
+                ("key", "dry_run", 0.0),
+                ("key", "fsync", 1.0),
+                ("key", "max_connections", 100.0),
+                ("key", "ratio", 0.25),
+                ("key", "work_mem_kb", 4096.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn values_survive_restart() {
+        let config = |region_label: &str| {
+            ScrapeConfig::with_queries(&format!(
+                r#"
+          - metric_name: test_state_single
+            query: select 1
+          - metric_name: test_state_vector
+            query: select region, value from t
+            var_labels: [{region_label}]
+            values:
+              single:
+                field: value
+                type: float
+          - metric_name: test_state_multi
+            query: select 1, 2
+            const_labels:
+              source: test
+            values:
+              multi_labels:
+                - field: 0
+                  labels:
+                    kind: read
+                - field: 1
+                  labels:
+                    kind: write
+"#
+            ))
+            .unwrap()
+        };
+        let text = |registry: &Registry| {
+            let mut buffer = vec![];
+            TextEncoder::new()
+                .encode(&registry.gather(), &mut buffer)
+                .unwrap();
+            String::from_utf8(buffer).unwrap()
+        };
+        let path = std::env::temp_dir().join(format!(
+            "psql-query-exporter-restart-{}.json",
+            std::process::id()
+        ));
+
+        // before restart
+        let config_before = config("region");
+        let queries = &config_before.sources["postgres"].databases[0].queries;
+        let registry_before = Registry::new();
+        let mut metrics: Vec<QueryMetrics> = queries
+            .iter()
+            .map(|q| QueryMetrics::from(q).unwrap())
+            .collect();
+        metrics
+            .iter_mut()
+            .for_each(|m| m.register(&registry_before));
+        let MetricWithType::SingleInt(single) = &metrics[0].metrics[0] else {
+            panic!("single int metric expected");
+        };
+        single.set(42);
+        let MetricWithType::VectorFloat(vector) = &metrics[1].metrics[0] else {
+            panic!("float vector metric expected");
+        };
+        vector.with_label_values(&["eu"]).set(1.5);
+        vector.with_label_values(&["us"]).set(2.5);
+        for (metric, value) in metrics[2].metrics.iter().zip([10, 20]) {
+            let MetricWithType::SingleInt(metric) = metric else {
+                panic!("single int metric expected");
+            };
+            metric.set(value);
+        }
+        State::new(saved_series(&registry_before, None))
+            .save(&path)
+            .unwrap();
+
+        // after restart
+        let state = State::load(&path, Duration::from_secs(60))
+            .unwrap()
+            .unwrap();
+        let registry_after = Registry::new();
+        let mut metrics: Vec<QueryMetrics> = queries
+            .iter()
+            .map(|q| QueryMetrics::from(q).unwrap())
+            .collect();
+        for (metric, query) in metrics.iter_mut().zip(queries) {
+            metric.restore(&state, None, &query.metric_name, &registry_after);
+        }
+        assert_eq!(text(&registry_after), text(&registry_before));
+        assert_eq!(metrics[0].last_updated, state.saved_at());
+        assert_eq!(
+            metrics[1].label_values[0],
+            LabelValues::from([vec![String::from("eu")], vec![String::from("us")]])
+        );
+        let restored = || {
+            RESTORED_FROM_STATE
+                .with_label_values(&["test_state_single"])
+                .get()
+        };
+        assert_eq!(restored(), 1);
+        metrics[0].register(&registry_after);
+        assert_eq!(restored(), 0);
+
+        // label of the vector metric has been renamed in the config
+        let config_drifted = config("zone");
+        let queries = &config_drifted.sources["postgres"].databases[0].queries;
+        let registry_drifted = Registry::new();
+        let mut metric = QueryMetrics::from(&queries[1]).unwrap();
+        metric.restore(&state, None, &queries[1].metric_name, &registry_drifted);
+        assert!(!metric.is_registered);
+        assert!(registry_drifted.gather().is_empty());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn positive_drift_of_slow_query() {
+        let config = ScrapeConfig::from_yaml(
+            r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: postgres
+        scrape_interval: 10ms
+        queries:
+          - metric_name: test_drift_slow
+            query: select 1
+"#,
+        )
+        .unwrap();
+        let query = &config.sources["postgres"].databases[0].queries[0];
+        let drift = || {
+            QUERY_INTERVAL_DRIFT
+                .with_label_values(&["postgres", "postgres", "test_drift_slow"])
+                .get()
+        };
+
+        let mut query_metrics = QueryMetrics::from(query).unwrap();
+        query_metrics.update_interval_drift(
+            "postgres",
+            "postgres",
+            &query.metric_name,
+            query.scrape_interval,
+        );
+        assert_eq!(drift(), 0.0);
+
+        // the next execution is late for 50ms at least
+        std::thread::sleep(Duration::from_millis(60));
+        query_metrics.update_interval_drift(
+            "postgres",
+            "postgres",
+            &query.metric_name,
+            query.scrape_interval,
+        );
+        assert!(drift() >= 0.05, "{}", drift());
+    }
+
+    #[test]
+    fn hook_adds_synthetic_metric() {
+        internal_metrics::init();
+        add_families_hook(|families| {
+            let mut metric = prometheus::proto::Metric::new();
+            let mut gauge = prometheus::proto::Gauge::new();
+            gauge.set_value(0.5);
+            metric.set_gauge(gauge);
+
+            let mut family = MetricFamily::new();
+            family.set_name(String::from("test_hook_synthetic_ratio"));
+            family.set_help(String::from("Synthetic metric added by hook"));
+            family.set_field_type(MetricType::GAUGE);
+            family.mut_metric().push(metric);
+            families.push(family);
+        });
+
+        let families = gather_all();
+        let synthetic = families
+            .iter()
+            .find(|f| f.get_name() == "test_hook_synthetic_ratio")
+            .expect("synthetic metric should be added by hook");
+        assert_eq!(synthetic.get_metric()[0].get_gauge().get_value(), 0.5);
+        assert!(families
+            .iter()
+            .any(|f| f.get_name() == "psql_exporter_build_info"));
+
+        let mut buffer = vec![];
+        print_metrics(&mut buffer).unwrap();
+        assert!(String::from_utf8(buffer)
+            .unwrap()
+            .contains("test_hook_synthetic_ratio 0.5\n"));
+    }
+
+    #[test]
+    fn families_are_sorted_by_name() {
+        internal_metrics::init();
+        add_families_hook(|families| {
+            let mut family = MetricFamily::new();
+            family.set_name(String::from("a_test_hook_first_by_name"));
+            family.set_help(String::from("Synthetic metric appended by hook"));
+            family.set_field_type(MetricType::GAUGE);
+            family.mut_metric().push(prometheus::proto::Metric::new());
+            families.push(family);
+        });
+
+        let names: Vec<String> = gather_all()
+            .iter()
+            .map(|family| family.get_name().to_string())
+            .collect();
+        assert_eq!(
+            names.first().map(String::as_str),
+            Some("a_test_hook_first_by_name")
+        );
+        assert!(names.windows(2).all(|pair| pair[0] <= pair[1]), "{names:?}");
+    }
+
+    #[test]
+    fn only_selected_databases_are_collected() {
+        let mut config = ScrapeConfig::from_yaml(
+            r#"
+sources:
+  first:
+    host: db1.example.com
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: app
+        queries:
+          - metric_name: db1_app
+            query: select 1
+      - dbname: billing
+        queries:
+          - metric_name: db1_billing
+            query: select 1
+  second:
+    host: db2.example.com
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: app
+        queries:
+          - metric_name: db2_app
+            query: select 1
+      - dbname: app_archive
+        queries:
+          - metric_name: db2_app_archive
+            query: select 1
+"#,
+        )
+        .unwrap();
+
+        config.retain_databases(&[
+            String::from("db1.example.com/app"),
+            String::from("db2.*/app_*"),
+        ]);
+        let names: Vec<String> = list_metrics(&config)
+            .unwrap()
+            .into_iter()
+            .map(|definition| definition.name)
+            .collect();
+        assert_eq!(names, vec!["db1_app", "db2_app_archive"]);
+    }
+
+    #[test]
+    fn sources_start_at_offset_times() {
+        let config = ScrapeConfig::from_yaml(
+            r#"
+sources:
+  first:
+    host: db1.example.com
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: app
+        queries:
+          - metric_name: first_app
+            query: select 1
+  second:
+    host: db2.example.com
+    user: postgres
+    password: postgres
+    start_offset: 30s
+    databases:
+      - dbname: app
+        queries:
+          - metric_name: second_app
+            query: select 1
+"#,
+        )
+        .unwrap();
+
+        let first_query_time = |source: &str| {
+            let database = &config.sources[source].databases[0];
+            let mut query_metrics = QueryMetrics::from(&database.queries[0]).unwrap();
+            query_metrics.delay_first_query(database.start_offset);
+            query_metrics.next_query_time
+        };
+
+        let first = first_query_time("first");
+        let second = first_query_time("second");
+        assert!(first <= SystemTime::now());
+        let offset = second.duration_since(first).unwrap();
+        assert!(
+            offset >= Duration::from_secs(30) && offset < Duration::from_secs(31),
+            "{offset:?}"
+        );
+    }
+
+    #[test]
+    fn max_rows_keeps_top_rows() {
+        let query_labels = ["postgres", "postgres", "test_max_rows"];
+        let dropped = || ROWS_DROPPED_TOTAL.with_label_values(&query_labels).get();
+        let rows: Vec<u32> = (1..=25).rev().collect();
+
+        let top = limit_rows(rows.clone(), NonZeroUsize::new(10), &query_labels);
+        assert_eq!(top, (16..=25).rev().collect::<Vec<u32>>());
+        assert_eq!(dropped(), 15);
+
+        assert_eq!(limit_rows(rows.clone(), None, &query_labels), rows);
+        assert_eq!(
+            limit_rows(rows.clone(), NonZeroUsize::new(30), &query_labels),
+            rows
+        );
+        assert_eq!(dropped(), 15);
+    }
+
+    #[test]
+    fn missing_referenced_columns() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: backends
+            query: select datname, state, count(*) as total from pg_stat_activity group by 1, 2
+            var_labels: [datname, usename]
+            values:
+              single:
+                field: total
+          - metric_name: sizes
+            query: select datname, pg_database_size(datname) from pg_database
+            var_labels: [datname]
+            values:
+              multi_suffixes:
+                - field: 1
+                  suffix: bytes
+                - field: 2
+                  suffix: megabytes
+"#,
+        )
+        .unwrap();
+        let queries = &config.sources["postgres"].databases[0].queries;
+
+        let err =
+            check_referenced_columns(&queries[0], &["datname", "state", "total"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"invalid value 'usename' of 'backends.var_labels': column isn't returned by the query, available columns are ["datname", "state", "total"]"#
+        );
+        assert!(check_referenced_columns(&queries[0], &["datname", "usename", "total"]).is_ok());
+
+        let err =
+            check_referenced_columns(&queries[1], &["datname", "pg_database_size"]).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("invalid value '2' of 'sizes.values.multi_suffixes.field'"));
+    }
+
+    #[test]
+    fn templated_metric_name_families() {
+        let load = |values: &str| {
+            ScrapeConfig::with_queries(&format!(
+                r#"
+          - metric_name: test_app_{{category}}_count
+            query: select category, datname, count(*) as total from events group by 1, 2
+            var_labels: [datname]
+            max_families: 2
+            values:
+              {values}
+"#
+            ))
+        };
+        let config = load("single: { field: total }").unwrap();
+        let query = &config.sources["postgres"].databases[0].queries[0];
+        assert_eq!(query.name_placeholders(), vec![String::from("category")]);
+        assert_eq!(
+            query.render_metric_name(|column| Some(format!("{column}_a"))),
+            Some(String::from("test_app_category_a_count"))
+        );
+        // row with NULL in the placeholder column has no family
+        assert_eq!(query.render_metric_name(|_| None), None);
+
+        let registry = Registry::new();
+        let mut query_metrics = QueryMetrics::from(query).unwrap();
+        assert!(query_metrics.metrics.is_empty());
+        query_metrics.register(&registry);
+
+        let value = match &query.values {
+            ScrapeConfigValues::ValueFrom(value) => value,
+            _ => unreachable!(),
+        };
+        let mut family = |name: &str| query_metrics.family(name, value, query, &registry);
+        assert_eq!(family("test_app_orders_count").unwrap(), Some(0));
+        assert_eq!(family("test_app_refunds_count").unwrap(), Some(1));
+        assert_eq!(family("test_app_orders_count").unwrap(), Some(0));
+        // cardinality guard
+        assert_eq!(family("test_app_returns_count").unwrap(), None);
+        for metric in query_metrics.metrics.iter() {
+            metric.zero(&LabelValues::from([vec![String::from("postgres")]]));
+        }
+
+        let names: Vec<String> = registry
+            .gather()
+            .iter()
+            .map(|family| family.get_name().to_string())
+            .collect();
+        assert_eq!(names, ["test_app_orders_count", "test_app_refunds_count"]);
+
+        let mut query_metrics = QueryMetrics::from(query).unwrap();
+        let err = query_metrics
+            .family("test_app_bad-value_count", value, query, &registry)
+            .unwrap_err();
+        assert!(
+            matches!(err, PsqlExporterError::CreateMetric { .. }),
+            "{err}"
+        );
+
+        let err = load("multi_suffixes: [{ field: total, suffix: total }]").unwrap_err();
+        assert!(err.to_string().contains("single values only"), "{err}");
+    }
+
+    #[test]
+    fn duplicate_column_names() {
+        // select a.id, b.id, a.name, b.name, b.size from a join b using (name)
+        let columns = ["id", "id", "name", "name", "size"];
+        let err = check_duplicate_columns("joined", columns.into_iter()).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            r#"query of metric 'joined' returned duplicate columns ["id", "name"], use aliases to make their names unique"#
+        );
+        assert!(check_duplicate_columns("joined", ["a_id", "b_id", "name"].into_iter()).is_ok());
+        assert!(check_duplicate_columns("empty", std::iter::empty()).is_ok());
+    }
+
+    #[test]
+    fn lsn_lag_in_bytes() {
+        let lsn = |text: &str| text.parse::<PgLsn>().unwrap();
+
+        assert_eq!(lsn_lag(lsn("0/3000060"), lsn("0/3000000")), 96.0);
+        assert_eq!(lsn_lag(lsn("1/0"), lsn("0/FFFFFF00")), 256.0);
+        assert_eq!(lsn_lag(lsn("16/B374D848"), lsn("16/B374D848")), 0.0);
+        assert_eq!(lsn_lag(lsn("0/10"), lsn("0/20")), -16.0);
+
+        // pg_lsn is sent as big-endian 64-bit position
+        let raw = 0x0000_0001_0000_0010_u64.to_be_bytes();
+        let decoded = PgLsn::from_sql(&Type::PG_LSN, &raw).unwrap();
+        assert_eq!(decoded, lsn("1/10"));
+        assert!(!<PgLsn as FromSql>::accepts(&Type::TEXT));
+    }
+
+    #[test]
+    fn duplicate_labels_policies() {
+        let rows = || {
+            vec![
+                (vec![String::from("db1")], Some(1.5)),
+                (vec![String::from("db2")], Some(3.0)),
+                (vec![String::from("db1")], None),
+                (vec![String::from("db1")], Some(2.0)),
+            ]
+            .into_iter()
+        };
+        let new_metric = || GaugeVec::new(opts!("duplicates", "help"), &["dbname"]).unwrap();
+
+        let metric = new_metric();
+        let label_values =
+            set_vector_values(&metric, "duplicates", rows(), &DuplicateLabels::Last).unwrap();
+        assert_eq!(label_values.len(), 2);
+        assert_eq!(metric.with_label_values(&["db1"]).get(), 2.0);
+        assert_eq!(metric.with_label_values(&["db2"]).get(), 3.0);
+
+        let metric = new_metric();
+        set_vector_values(&metric, "duplicates", rows(), &DuplicateLabels::Sum).unwrap();
+        assert_eq!(metric.with_label_values(&["db1"]).get(), 3.5);
+        assert_eq!(metric.with_label_values(&["db2"]).get(), 3.0);
+
+        let metric = new_metric();
+        let error =
+            set_vector_values(&metric, "duplicates", rows(), &DuplicateLabels::Error).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "query of metric 'duplicates' returned duplicate labels [\"db1\"]"
+        );
+        // nothing is updated if the result is rejected
+        assert_eq!(metric.collect()[0].get_metric().len(), 0);
+
+        let metric = IntGaugeVec::new(opts!("duplicates", "help"), &["dbname"]).unwrap();
+        let rows = [1, 2, 3].map(|value| (vec![String::from("db1")], Some(value)));
+        set_vector_values(
+            &metric,
+            "duplicates",
+            rows.into_iter(),
+            &DuplicateLabels::Sum,
+        )
+        .unwrap();
+        assert_eq!(metric.with_label_values(&["db1"]).get(), 6);
+    }
+
+    #[test]
+    fn histogram_of_query_durations() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: query_duration_seconds
+            query: select datname, duration from recent_queries
+            var_labels: [datname]
+            values:
+              histogram:
+                field: duration
+                buckets: [0.1, 1]
+"#,
+        )
+        .unwrap();
+        let query = &config.sources["postgres"].databases[0].queries[0];
+
+        let registry = Registry::new();
+        let mut query_metrics = QueryMetrics::from(query).unwrap();
+        query_metrics.register(&registry);
+        let MetricWithType::Histogram(metric) = &query_metrics.metrics[0] else {
+            panic!("histogram metric expected");
+        };
+        let label_values = observe_histogram(
+            metric,
+            [0.05, 0.5, 3.0, f64::NAN]
+                .map(|value| (vec![String::from("app")], Some(value)))
+                .into_iter()
+                .chain([(vec![String::from("app")], None)]),
+            &NanPolicy::Skip,
+        );
+        assert_eq!(label_values.len(), 1);
+
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&registry.gather(), &mut buffer)
+            .unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.contains("# TYPE query_duration_seconds histogram\n"));
+        assert!(output.contains("query_duration_seconds_bucket{datname=\"app\",le=\"0.1\"} 1\n"));
+        assert!(output.contains("query_duration_seconds_bucket{datname=\"app\",le=\"1\"} 2\n"));
+        assert!(output.contains("query_duration_seconds_bucket{datname=\"app\",le=\"+Inf\"} 3\n"));
+        assert!(output.contains("query_duration_seconds_sum{datname=\"app\"} 3.55\n"));
+        assert!(output.contains("query_duration_seconds_count{datname=\"app\"} 3\n"));
+    }
+
+    #[test]
+    fn state_set_of_backends() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: backend_state
+            query: select pid::text, state from pg_stat_activity
+            var_labels: [pid]
+            values:
+              state_set:
+                field: state
+                states: [running, idle, blocked]
+"#,
+        )
+        .unwrap();
+        let query = &config.sources["postgres"].databases[0].queries[0];
+
+        let registry = Registry::new();
+        let mut query_metrics = QueryMetrics::from(query).unwrap();
+        query_metrics.register(&registry);
+        let MetricWithType::VectorInt(metric) = &query_metrics.metrics[0] else {
+            panic!("int vector metric expected");
+        };
+        let label_values = set_state_set(
+            metric,
+            &[
+                String::from("running"),
+                String::from("idle"),
+                String::from("blocked"),
+            ],
+            vec![
+                (vec![String::from("101")], Some(String::from("idle"))),
+                (vec![String::from("102")], Some(String::from("blocked"))),
+                (vec![String::from("103")], Some(String::from("starting"))),
+                (vec![String::from("104")], None),
+            ]
+            .into_iter(),
+        );
+        assert_eq!(label_values.len(), 9);
+        assert!(!label_values.iter().any(|labels| labels[0] == "104"));
+
+        let value = |pid: &str, state: &str| metric.with_label_values(&[pid, state]).get();
+        assert_eq!(value("101", "running"), 0);
+        assert_eq!(value("101", "idle"), 1);
+        assert_eq!(value("101", "blocked"), 0);
+        assert_eq!(value("102", "blocked"), 1);
+        assert_eq!(value("102", "idle"), 0);
+        for state in ["running", "idle", "blocked"] {
+            assert_eq!(value("103", state), 0);
+        }
+
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&registry.gather(), &mut buffer)
+            .unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.contains("backend_state{pid=\"101\",state=\"idle\"} 1\n"));
+        assert!(!output.contains("pid=\"104\""));
+    }
+
+    #[test]
+    fn numeric_columns_as_float() {
+        let numeric = |weight: i16, sign: u16, digits: &[i16]| {
+            let mut raw = vec![];
+            raw.extend((digits.len() as i16).to_be_bytes());
+            raw.extend(weight.to_be_bytes());
+            raw.extend(sign.to_be_bytes());
+            raw.extend(0u16.to_be_bytes());
+            digits.iter().for_each(|d| raw.extend(d.to_be_bytes()));
+            raw
+        };
+        let float = |raw: Vec<u8>| FloatValue::from_sql(&Type::NUMERIC, &raw).map(|v| v.0);
+
+        assert!(FloatValue::accepts(&Type::NUMERIC));
+        assert!(!FloatValue::accepts(&Type::TEXT));
+        assert_eq!(float(numeric(0, 0x0000, &[123, 4500])).unwrap(), 123.45);
+        assert_eq!(float(numeric(-1, 0x4000, &[10])).unwrap(), -0.001);
+        assert_eq!(float(numeric(1, 0x0000, &[1, 0])).unwrap(), 10000.0);
+        assert_eq!(float(numeric(0, 0x0000, &[])).unwrap(), 0.0);
+        assert!(float(numeric(0, 0xC000, &[])).unwrap().is_nan());
+        assert_eq!(float(numeric(0, 0xD000, &[])).unwrap(), f64::INFINITY);
+
+        assert_eq!(
+            float(numeric(0, 0x0000, &[12345])).unwrap_err().to_string(),
+            "invalid NUMERIC value: wrong digit 12345"
+        );
+        assert!(float(vec![0, 1, 0, 0]).is_err());
+        assert!(float(numeric(0, 0x1234, &[1])).is_err());
+
+        let value = KeyValue::from_sql(&Type::NUMERIC, &numeric(0, 0x0000, &[99])).unwrap();
+        assert_eq!(value.0, Some(99.0));
+    }
+
+    #[test]
+    fn timestamp_columns_as_epoch() {
+        // microseconds since 2000-01-01
+        let timestamp = |micros: i64| micros.to_be_bytes().to_vec();
+        let float = |ty: &Type, raw: Option<Vec<u8>>| match raw {
+            Some(raw) => FloatValue::from_sql(ty, &raw).map(|v| v.0),
+            None => FloatValue::from_sql_null(ty).map(|v| v.0),
+        };
+
+        assert!(FloatValue::accepts(&Type::TIMESTAMP));
+        assert!(FloatValue::accepts(&Type::TIMESTAMPTZ));
+        assert_eq!(
+            float(&Type::TIMESTAMPTZ, Some(timestamp(1_500_000))).unwrap(),
+            946_684_801.5
+        );
+        assert_eq!(
+            float(
+                &Type::TIMESTAMP,
+                Some(timestamp(-946_684_800_000_000 - 2_000_000))
+            )
+            .unwrap(),
+            -2.0
+        );
+
+        // NULL timestamp is NaN, so nan_policy decides
+        let never = float(&Type::TIMESTAMPTZ, None).unwrap();
+        assert!(never.is_nan());
+        assert_eq!(NanPolicy::Skip.apply(never), None);
+        assert_eq!(NanPolicy::Zero.apply(never), Some(0.0));
+        assert!(float(&Type::FLOAT8, None).is_err());
+
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: job_last_success_timestamp
+            query: select max(finished_at) from jobs
+            values:
+              single:
+                type: timestamp
+"#,
+        )
+        .unwrap();
+        let query = &config.sources["postgres"].databases[0].queries[0];
+        let query_metrics = QueryMetrics::from(query).unwrap();
+        assert!(matches!(
+            query_metrics.metrics[0],
+            MetricWithType::SingleFloat(_)
+        ));
+    }
+
+    #[test]
+    fn timestamp_in_millis() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: job_last_success_timestamp_milliseconds
+            query: select max(finished_at) from jobs
+            values:
+              single:
+                type: timestamp
+                resolution: millis
+          - metric_name: job_last_success_timestamp_seconds
+            query: select max(finished_at) from jobs
+            values:
+              single:
+                type: timestamp
+"#,
+        )
+        .unwrap();
+        let transform = |query: &ScrapeConfigQuery| {
+            let ScrapeConfigValues::ValueFrom(value) = &query.values else {
+                panic!("single value expected");
+            };
+            ValueTransform::new(value.scale, value.offset).with_resolution(value.resolution)
+        };
+        // 1.5s after epoch is -946684798.5s since 2000-01-01
+        let raw = (-946_684_798_500_000_i64).to_be_bytes();
+        let seconds = FloatValue::from_sql(&Type::TIMESTAMPTZ, &raw).unwrap().0;
+        assert_eq!(seconds, 1.5);
+
+        let queries = &config.sources["postgres"].databases[0].queries;
+        assert_eq!(transform(&queries[0]).apply(seconds), 1500.0);
+        assert_eq!(transform(&queries[1]).apply(seconds), 1.5);
+    }
+
+    #[test]
+    fn null_count_of_skipped_values() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: test_null_count_skipped
+            query: select size from partitions
+            skip_null: true
+          - metric_name: test_null_count_not_skipped
+            query: select size from partitions
+"#,
+        )
+        .unwrap();
+        let queries = &config.sources["postgres"].databases[0].queries;
+        assert!(QueryMetrics::from(&queries[1])
+            .unwrap()
+            .null_count
+            .is_none());
+
+        let null_count = |registry: &Registry| {
+            registry
+                .gather()
+                .iter()
+                .find(|f| f.get_name() == "test_null_count_skipped_null_count")
+                .expect("null count gauge should be registered")
+                .get_metric()[0]
+                .get_gauge()
+                .get_value()
+        };
+        let registry = Registry::new();
+        let mut query_metrics = QueryMetrics::from(&queries[0]).unwrap();
+        query_metrics.register(&registry);
+        let values = [Some(1), None, Some(3), None, None, Some(6)];
+        let not_null: Vec<i64> = values
+            .into_iter()
+            .filter_map(|value| count_null(value, query_metrics.null_count.as_ref()))
+            .collect();
+        assert_eq!(not_null, vec![1, 3, 6]);
+        assert_eq!(null_count(&registry), 3.0);
+
+        // the next result is counted from scratch
+        query_metrics.register(&registry);
+        assert_eq!(null_count(&registry), 0.0);
+    }
+
+    #[test]
+    fn info_metric_of_two_settings() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: psql_exporter_pg_setting
+            query: select name, setting from pg_settings where name in ('wal_level', 'max_connections')
+            var_labels: [name, setting]
+            values: info
+"#,
+        )
+        .unwrap();
+        let query = &config.sources["postgres"].databases[0].queries[0];
+
+        let registry = Registry::new();
+        let mut query_metrics = QueryMetrics::from(query).unwrap();
+        query_metrics.register(&registry);
+        let label_values = set_info(
+            &query_metrics.metrics[0],
+            vec![
+                vec![String::from("wal_level"), String::from("logical")],
+                vec![String::from("max_connections"), String::from("100")],
+            ]
+            .into_iter(),
+        );
+        assert_eq!(label_values.len(), 2);
+
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&registry.gather(), &mut buffer)
+            .unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output
+            .contains("psql_exporter_pg_setting{name=\"max_connections\",setting=\"100\"} 1\n"));
+        assert!(
+            output.contains("psql_exporter_pg_setting{name=\"wal_level\",setting=\"logical\"} 1\n")
+        );
+    }
+
+    #[test]
+    fn series_expire_individually() {
+        let config = ScrapeConfig::from_yaml(
+            r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: postgres
+        metric_expiration_time: 50ms
+        queries:
+          - metric_name: test_series_expiration
+            query: select datname, numbackends from pg_stat_database
+            var_labels: [datname]
+"#,
+        )
+        .unwrap();
+        let query = &config.sources["postgres"].databases[0].queries[0];
+
+        let registry = Registry::new();
+        let mut query_metrics = QueryMetrics::from(query).unwrap();
+        let MetricWithType::VectorInt(metric) = &query_metrics.metrics[0] else {
+            panic!("int vector metric expected");
+        };
+        let metric = metric.clone();
+        let update = |query_metrics: &mut QueryMetrics, datnames: &[&str]| {
+            query_metrics.register(&registry);
+            let rows = datnames.iter().map(|d| (vec![d.to_string()], Some(1)));
+            let label_values = set_vector_values(
+                &metric,
+                "test_series_expiration",
+                rows,
+                &DuplicateLabels::Last,
+            )
+            .unwrap();
+            query_metrics.expire_series(&[label_values], query.metric_expiration_time);
+        };
+        let series = || {
+            let mut datnames: Vec<String> = metric
+                .collect()
+                .iter()
+                .flat_map(|family| family.get_metric().iter())
+                .map(|m| m.get_label()[0].get_value().to_string())
+                .collect();
+            datnames.sort();
+            datnames
+        };
+
+        update(&mut query_metrics, &["app", "billing"]);
+        assert_eq!(series(), vec!["app", "billing"]);
+
+        // billing isn't expired yet
+        update(&mut query_metrics, &["app"]);
+        assert_eq!(series(), vec!["app", "billing"]);
+
+        std::thread::sleep(Duration::from_millis(60));
+        update(&mut query_metrics, &["app"]);
+        assert_eq!(series(), vec!["app"]);
+        assert_eq!(registry.gather().len(), 1);
+
+        // and it's back with the row
+        update(&mut query_metrics, &["app", "billing"]);
+        assert_eq!(series(), vec!["app", "billing"]);
+    }
+
+    #[test]
+    fn expiration_modes_after_outage() {
+        let config = ScrapeConfig::from_yaml(
+            r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: postgres
+        metric_expiration_time: 1ms
+        queries:
+          - metric_name: test_expiration_remove
+            query: select 1
+          - metric_name: test_expiration_mark_stale
+            query: select 1
+            expiration_mode: mark_stale
+"#,
+        )
+        .unwrap();
+        let queries = &config.sources["postgres"].databases[0].queries;
+
+        // remove
+        let registry = Registry::new();
+        let mut query_metrics = QueryMetrics::from(&queries[0]).unwrap();
+        query_metrics.register(&registry);
+        assert_eq!(registry.gather().len(), 1);
+        std::thread::sleep(Duration::from_millis(5));
+        query_metrics.expire(queries[0].metric_expiration_time, &registry);
+        assert!(registry.gather().is_empty());
+
+        // mark_stale
+        let stale_value = |registry: &Registry| {
+            registry
+                .gather()
+                .iter()
+                .find(|f| f.get_name() == "test_expiration_mark_stale_stale")
+                .expect("stale gauge should be registered")
+                .get_metric()[0]
+                .get_gauge()
+                .get_value()
+        };
+        let registry = Registry::new();
+        let mut query_metrics = QueryMetrics::from(&queries[1]).unwrap();
+        query_metrics.register(&registry);
+        assert_eq!(registry.gather().len(), 2);
+        assert_eq!(stale_value(&registry), 0.0);
+        std::thread::sleep(Duration::from_millis(5));
+        query_metrics.expire(queries[1].metric_expiration_time, &registry);
+        assert_eq!(registry.gather().len(), 2);
+        assert_eq!(stale_value(&registry), 1.0);
+
+        // database is back
+        query_metrics.register(&registry);
+        assert_eq!(stale_value(&registry), 0.0);
+    }
+
+    #[test]
+    fn timeout_actions() {
+        let config = ScrapeConfig::from_yaml(
+            r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: postgres
+        metric_expiration_time: 1ms
+        queries:
+          - metric_name: test_timeout_keep
+            query: select 'a' as slot, 5 as value
+            var_labels: [slot]
+            timeout_action: keep
+          - metric_name: test_timeout_zero
+            query: select 'a' as slot, 5 as value
+            var_labels: [slot]
+            timeout_action: zero
+          - metric_name: test_timeout_expire
+            query: select 'a' as slot, 5 as value
+            var_labels: [slot]
+          - metric_name: test_timeout_flag
+            query: select 'a' as slot, 5 as value
+            var_labels: [slot]
+            timeout_action: flag
+"#,
+        )
+        .unwrap();
+        let queries = &config.sources["postgres"].databases[0].queries;
+        let value = |registry: &Registry, name: &str| {
+            registry
+                .gather()
+                .iter()
+                .find(|f| f.get_name() == name)
+                .map(|f| f.get_metric()[0].get_gauge().get_value())
+        };
+        let timed_out = |name: &str| {
+            QUERY_TIMED_OUT
+                .with_label_values(&["postgres", "postgres", name])
+                .get()
+        };
+        let timed_out_query = |query: &ScrapeConfigQuery| {
+            let registry = Registry::new();
+            let mut query_metrics = QueryMetrics::from(query).unwrap();
+            query_metrics.register(&registry);
+            if let MetricWithType::VectorInt(metric) = &query_metrics.metrics[0] {
+                metric.with_label_values(&["a"]).set(5);
+            }
+            query_metrics.label_values[0].insert(vec![String::from("a")]);
+            std::thread::sleep(Duration::from_millis(5));
+            query_metrics.timeout("postgres", "postgres", query, &registry);
+            (registry, query_metrics)
+        };
+
+        let (registry, _) = timed_out_query(&queries[0]);
+        assert_eq!(value(&registry, "test_timeout_keep"), Some(5.0));
+
+        let (registry, _) = timed_out_query(&queries[1]);
+        assert_eq!(value(&registry, "test_timeout_zero"), Some(0.0));
+
+        let (registry, _) = timed_out_query(&queries[2]);
+        assert_eq!(value(&registry, "test_timeout_expire"), None);
+
+        let (registry, mut query_metrics) = timed_out_query(&queries[3]);
+        assert_eq!(value(&registry, "test_timeout_flag"), Some(5.0));
+        assert_eq!(timed_out("test_timeout_flag"), 1);
+        // the flag is cleared by the next successful query
+        query_metrics.register(&registry);
+        assert_eq!(timed_out("test_timeout_flag"), 0);
+    }
+
+    #[test]
+    fn query_tags_metric() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: test_query_tags_metric
+            query: select 1
+            tags:
+              team: billing
+              sev: "2"
+"#,
+        )
+        .unwrap();
+        let query_config = &config.sources["postgres"].databases[0].queries[0];
+        let tags = query_config.tags.as_ref().unwrap();
+        QUERY_TAGS.set("postgres", "app", &query_config.metric_name, tags);
+        QUERY_TAGS.set("postgres", "billing", &query_config.metric_name, tags);
+
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&prometheus::gather(), &mut buffer)
+            .unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+        // the same query of different DBs doesn't overwrite tags of each other
+        for database in ["app", "billing"] {
+            assert!(output.contains(&format!(
+                r#"psql_exporter_query_tags{{database="{database}",metric="test_query_tags_metric",sev="2",source="postgres",team="billing"}} 1"#
+            )));
+        }
+    }
+
+    #[test]
+    fn single_value_with_var_labels_is_vector() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: test_single_value_with_var_labels_is_vector
+            query: select datname, numbackends from pg_stat_database
+            var_labels: [datname]
+            values:
+              single:
+                field: numbackends
+"#,
+        )
+        .unwrap();
+        let query_config = &config.sources["postgres"].databases[0].queries[0];
+        let query_metrics = QueryMetrics::from(query_config).unwrap();
+
+        // single value with var_labels produces series per each row rather than value of the first row
+        assert_eq!(query_metrics.metrics.len(), 1);
+        let MetricWithType::VectorInt(metric) = &query_metrics.metrics[0] else {
+            panic!("vector metric expected");
+        };
+        let registry = Registry::new();
+        registry.register(Box::new(metric.clone())).unwrap();
+        let update = |rows: Vec<(&str, i64)>| {
+            let rows_values = rows
+                .into_iter()
+                .map(|(datname, value)| (vec![datname.to_string()], Some(value)));
+            set_vector_values(
+                metric,
+                &query_config.metric_name,
+                rows_values,
+                &query_config.duplicate_labels,
+            )
+            .unwrap()
+        };
+        let series = || -> Vec<(String, f64)> {
+            registry.gather()[0]
+                .get_metric()
+                .iter()
+                .map(|m| {
+                    (
+                        m.get_label()[0].get_value().to_string(),
+                        m.get_gauge().get_value(),
+                    )
+                })
+                .collect()
+        };
+
+        let label_values = update(vec![("postgres", 3), ("app", 7), ("billing", 0)]);
+        assert_eq!(
+            label_values,
+            LabelValues::from([
+                vec![String::from("postgres")],
+                vec![String::from("app")],
+                vec![String::from("billing")],
+            ])
+        );
+        assert_eq!(
+            series(),
+            vec![
+                (String::from("app"), 7.0),
+                (String::from("billing"), 0.0),
+                (String::from("postgres"), 3.0),
+            ]
+        );
+
+        // the next result updates each series by its own row
+        update(vec![("app", 9), ("postgres", 4), ("billing", 1)]);
+        assert_eq!(
+            series(),
+            vec![
+                (String::from("app"), 9.0),
+                (String::from("billing"), 1.0),
+                (String::from("postgres"), 4.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn tenants_with_the_same_metric_name() {
+        for (tenant, value) in [("test_tenant_a", 1), ("test_tenant_b", 2)] {
+            let gauge = IntGauge::new("test_tenant_metric", "test metric").unwrap();
+            tenant_registry(tenant)
+                .register(Box::new(gauge.clone()))
+                .unwrap();
+            gauge.set(value);
+        }
+
+        let tenant_value = |tenant: &str| {
+            tenant_registry(tenant).gather()[0].get_metric()[0]
+                .get_gauge()
+                .get_value()
+        };
+        assert_eq!(tenant_value("test_tenant_a"), 1.0);
+        assert_eq!(tenant_value("test_tenant_b"), 2.0);
+
+        let families = gather_all();
+        let family = families
+            .iter()
+            .find(|f| f.get_name() == "test_tenant_metric")
+            .expect("tenant metric should be in combined output");
+        let series: Vec<(&str, f64)> = family
+            .get_metric()
+            .iter()
+            .map(|m| (m.get_label()[0].get_value(), m.get_gauge().get_value()))
+            .collect();
+        assert_eq!(series, vec![("test_tenant_a", 1.0), ("test_tenant_b", 2.0)]);
+
+        // the same name of different type isn't merged into the family of the first tenant
+        let counter = prometheus::IntCounter::new("test_tenant_metric", "test metric").unwrap();
+        tenant_registry("test_tenant_c")
+            .register(Box::new(counter))
+            .unwrap();
+        let family = gather_all()
+            .into_iter()
+            .find(|f| f.get_name() == "test_tenant_metric")
+            .unwrap();
+        assert_eq!(family.get_field_type(), MetricType::GAUGE);
+        assert_eq!(family.get_metric().len(), 2);
+    }
+
+    #[test]
+    fn reload_keeps_unchanged_collectors() {
+        let config = |scrape_interval: &str, extra_database: &str| {
+            ScrapeConfig::from_yaml(&format!(
+                r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: app
+        queries:
+          - metric_name: test_reload_app
+            query: select 1
+      - dbname: stats
+        scrape_interval: {scrape_interval}
+        queries:
+          - metric_name: test_reload_stats
+            query: select 1
+{extra_database}
+"#
+            ))
+            .unwrap()
+        };
+        let running: Vec<RunningCollector> = enabled_databases(config("1m", ""))
+            .into_iter()
+            .enumerate()
+            .map(|(id, (source_name, database))| RunningCollector {
+                id,
+                source_name,
+                database,
+                _stop_channel: watch::channel(false).0,
+            })
+            .collect();
+
+        let mut databases = enabled_databases(config(
+            "5m",
+            r#"
+      - dbname: reports
+        queries:
+          - metric_name: test_reload_reports
+            query: select 1
+"#,
+        ));
+        let (kept, changed) = diff_collectors(running, &mut databases);
+        let dbnames = |collectors: &[RunningCollector]| -> Vec<String> {
+            collectors
+                .iter()
+                .map(|c| c.database.dbname.clone())
+                .collect()
+        };
+
+        assert_eq!(dbnames(&kept), vec!["app"]);
+        assert_eq!(dbnames(&changed), vec!["stats"]);
+        let started: Vec<&str> = databases.iter().map(|(_, d)| d.dbname.as_str()).collect();
+        assert_eq!(started, vec!["stats", "reports"]);
+    }
+
+    #[test]
+    fn stopped_collector_unregisters_metrics() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: test_stopped_collector
+            query: select 1
+"#,
+        )
+        .unwrap();
+        let database = &config.sources["postgres"].databases[0];
+        let registry = Registry::new();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let collector_metrics = || {
+            let mut query_metrics = QueryMetrics::from(&database.queries[0]).unwrap();
+            query_metrics.register(&registry);
+            CollectorMetrics {
+                query_metrics: vec![query_metrics],
+                registry: &registry,
+                shutdown_channel: shutdown_rx.clone(),
+                source_name: String::from("postgres"),
+                dbname: database.dbname.clone(),
+                connection_id: database.connection_string.id(),
+            }
+        };
+
+        // stopped by reload
+        let metrics = collector_metrics();
+        assert_eq!(registry.gather().len(), 1);
+        drop(metrics);
+        assert!(registry.gather().is_empty());
+
+        // stopped by shutdown, values are kept to be saved
+        shutdown_tx.send_replace(true);
+        drop(collector_metrics());
+        assert_eq!(registry.gather().len(), 1);
+    }
+
+    #[test]
+    fn invalid_query_config_is_exposed() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: test_config_valid
+            query: select 1
+          - metric_name: test-config-invalid
+            query: select 1
+"#,
+        )
+        .unwrap();
+        let queries = &config.sources["postgres"].databases[0].queries;
+
+        assert!(QueryMetrics::from(&queries[0]).is_ok());
+        assert!(QueryMetrics::from(&queries[1]).is_err());
+        let config_valid = |metric: &str| QUERY_CONFIG_VALID.with_label_values(&[metric]).get();
+        assert_eq!(config_valid("test_config_valid"), 1);
+        assert_eq!(config_valid("test-config-invalid"), 0);
+
+        let names: Vec<String> = list_metrics(&config)
+            .unwrap()
+            .into_iter()
+            .map(|d| d.name)
+            .collect();
+        assert_eq!(names, vec![String::from("test_config_valid")]);
+    }
+
+    #[test]
+    fn list_metrics_of_config() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: test_list_replication
+            description: Replication lag
+            query: select slot_name, lag from replication
+            const_labels:
+              cluster: main
+            var_labels: [slot_name]
+            expiration_mode: mark_stale
+            values:
+              single:
+                field: lag
+                type: float
+          - metric_name: test_list_stat
+            description: Stats
+            query: select 1 as commits, 2 as rollbacks
+            values:
+              multi_suffixes:
+                - field: commits
+                  suffix: commits
+                - field: rollbacks
+                  suffix: rollbacks
+  disabled:
+    enabled: false
+    host: localhost
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: postgres
+        queries:
+          - metric_name: test_list_disabled
+            query: select 1
+"#,
+        )
+        .unwrap();
+
+        let definition =
+            |name: &str, help: &str, const_labels: &[(&str, &str)], var_labels: &[&str]| {
+                MetricDefinition {
+                    name: name.to_string(),
+                    metric_type: String::from("gauge"),
+                    help: help.to_string(),
+                    const_labels: const_labels
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect(),
+                    var_labels: var_labels.iter().map(|l| l.to_string()).collect(),
+                }
+            };
+        assert_eq!(
+            list_metrics(&config).unwrap(),
+            vec![
+                definition(
+                    "test_list_replication",
+                    "Replication lag",
+                    &[("cluster", "main")],
+                    &["slot_name"]
+                ),
+                definition(
+                    "test_list_replication_stale",
+                    "Replication lag: 1 if values are stale",
+                    &[("cluster", "main")],
+                    &[]
+                ),
+                definition("test_list_stat_commits", "Stats: commits", &[], &[]),
+                definition("test_list_stat_rollbacks", "Stats: rollbacks", &[], &[]),
+            ]
+        );
     }
 }
@@ -0,0 +1,114 @@
+use crate::{errors::PsqlExporterError, scrape_config::ScrapeConfigQuery};
+
+use figment::{
+    providers::{Format, Yaml},
+    Figment,
+};
+use serde::Deserialize;
+
+const PG_STAT_DATABASE_PRESET: &str = r#"
+queries:
+  - metric_name: pg_stat_database
+    description: Statistics of the database from pg_stat_database view
+    query: |
+      select datname::text as datname,
+        numbackends::bigint as numbackends,
+        xact_commit::bigint as xact_commit,
+        xact_rollback::bigint as xact_rollback,
+        deadlocks::bigint as deadlocks,
+        coalesce(blks_hit::float / nullif(blks_hit + blks_read, 0), 0)::float as cache_hit_ratio
+      from pg_stat_database
+      where datname = current_database();
+    var_labels:
+      - datname
+    values:
+      multi_suffixes:
+        - field: numbackends
+          suffix: numbackends
+        - field: xact_commit
+          suffix: xact_commit
+        - field: xact_rollback
+          suffix: xact_rollback
+        - field: deadlocks
+          suffix: deadlocks
+        - field: cache_hit_ratio
+          type: float
+          suffix: cache_hit_ratio
+"#;
+
+// Logical slots are behind by confirmed_flush_lsn, physical ones don't have it
+const REPLICATION_SLOT_LAG_PRESET: &str = r#"
+queries:
+  - metric_name: pg_replication_slot_lag_bytes
+    description: Number of bytes of WAL retained by the replication slot
+    query: |
+      select slot_name::text as slot_name,
+        pg_current_wal_lsn() as current_lsn,
+        coalesce(confirmed_flush_lsn, restart_lsn) as slot_lsn
+      from pg_replication_slots;
+    var_labels:
+      - slot_name
+    skip_null: true
+    values:
+      lsn_lag:
+        field: slot_lsn
+        base_field: current_lsn
+"#;
+
+// Statements of all users are merged by query id, so each id is a single series;
+// the result is ordered by total time and capped by max_rows to bound cardinality
+const PG_STAT_STATEMENTS_TOP_PRESET: &str = r#"
+queries:
+  - metric_name: pg_stat_statements_top
+    description: Top statements of the database by total execution time from pg_stat_statements view
+    query: |
+      select to_hex(queryid) as queryid,
+        sum(calls)::bigint as calls,
+        (sum(total_exec_time) / 1000)::float8 as exec_seconds,
+        sum(rows)::bigint as rows
+      from pg_stat_statements
+      where dbid = (select oid from pg_database where datname = current_database())
+        and queryid is not null
+      group by queryid
+      order by exec_seconds desc;
+    var_labels:
+      - queryid
+    max_rows: 20
+    prune_missing_labels: true
+    values:
+      multi_suffixes:
+        - field: calls
+          suffix: calls_total
+        - field: exec_seconds
+          type: float
+          suffix: exec_seconds_total
+        - field: rows
+          suffix: rows_total
+"#;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryPreset {
+    PgStatDatabase,
+    ReplicationSlotLag,
+    PgStatStatementsTop,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PresetQueries {
+    queries: Vec<ScrapeConfigQuery>,
+}
+
+impl QueryPreset {
+    pub fn queries(&self) -> Result<Vec<ScrapeConfigQuery>, PsqlExporterError> {
+        let preset = match self {
+            Self::PgStatDatabase => PG_STAT_DATABASE_PRESET,
+            Self::ReplicationSlotLag => REPLICATION_SLOT_LAG_PRESET,
+            Self::PgStatStatementsTop => PG_STAT_STATEMENTS_TOP_PRESET,
+        };
+        let preset: PresetQueries = Figment::new().merge(Yaml::string(preset)).extract()?;
+
+        Ok(preset.queries)
+    }
+}
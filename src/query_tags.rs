@@ -0,0 +1,142 @@
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{Gauge, LabelPair, Metric, MetricFamily, MetricType},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+// Built-in labels of each series, tags can't use these names
+pub const QUERY_TAGS_LABELS: [&str; 3] = ["source", "database", "metric"];
+
+// Tags of each query, keyed by values of the built-in labels
+type TagsMap = BTreeMap<[String; 3], BTreeMap<String, String>>;
+
+// Info metric with arbitrary set of labels for each query,
+// so it can't be implemented using GaugeVec with fixed label names
+#[derive(Debug, Clone)]
+pub struct QueryTags {
+    desc: Desc,
+    tags: Arc<Mutex<TagsMap>>,
+}
+
+impl QueryTags {
+    pub fn new(name: &str, help: &str) -> Result<Self, prometheus::Error> {
+        let desc = Desc::new(name.to_string(), help.to_string(), vec![], HashMap::new())?;
+
+        Ok(Self {
+            desc,
+            tags: Arc::new(Mutex::new(BTreeMap::new())),
+        })
+    }
+
+    // The same query may run on several DBs, so tags of each of them are kept separately
+    pub fn set(&self, source: &str, database: &str, metric: &str, tags: &HashMap<String, String>) {
+        self.tags
+            .lock()
+            .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+            .insert(
+                [source.to_string(), database.to_string(), metric.to_string()],
+                tags.clone().into_iter().collect(),
+            );
+    }
+
+    fn make_metric(query: &[String; 3], tags: &BTreeMap<String, String>) -> Metric {
+        let mut labels: Vec<LabelPair> = Vec::with_capacity(tags.len() + QUERY_TAGS_LABELS.len());
+        for (name, value) in QUERY_TAGS_LABELS.iter().zip(query.iter()) {
+            let mut label = LabelPair::new();
+            label.set_name(name.to_string());
+            label.set_value(value.clone());
+            labels.push(label);
+        }
+        for (name, value) in tags.iter() {
+            let mut label = LabelPair::new();
+            label.set_name(name.clone());
+            label.set_value(value.clone());
+            labels.push(label);
+        }
+        labels.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+
+        let mut gauge = Gauge::new();
+        gauge.set_value(1.0);
+
+        let mut metric = Metric::new();
+        metric.mut_label().extend(labels);
+        metric.set_gauge(gauge);
+        metric
+    }
+}
+
+impl Collector for QueryTags {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let tags = self
+            .tags
+            .lock()
+            .unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
+
+        let mut family = MetricFamily::new();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::GAUGE);
+        for (query, tags) in tags.iter() {
+            family.mut_metric().push(Self::make_metric(query, tags));
+        }
+
+        vec![family]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{Encoder, Registry, TextEncoder};
+
+    #[test]
+    fn tags_with_different_names() {
+        let registry = Registry::new();
+        let query_tags = QueryTags::new("query_tags", "Tags of the query").unwrap();
+        registry.register(Box::new(query_tags.clone())).unwrap();
+
+        query_tags.set(
+            "postgres",
+            "billing",
+            "billing_invoices",
+            &HashMap::from([
+                (String::from("team"), String::from("billing")),
+                (String::from("sev"), String::from("2")),
+            ]),
+        );
+        query_tags.set(
+            "postgres",
+            "billing",
+            "replication_lag",
+            &HashMap::from([(String::from("team"), String::from("dba"))]),
+        );
+        query_tags.set(
+            "postgres",
+            "app",
+            "replication_lag",
+            &HashMap::from([(String::from("team"), String::from("app"))]),
+        );
+
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&registry.gather(), &mut buffer)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            r#"# HELP query_tags Tags of the query
+# TYPE query_tags gauge
+query_tags{database="app",metric="replication_lag",source="postgres",team="app"} 1
+query_tags{database="billing",metric="replication_lag",source="postgres",team="dba"} 1
+query_tags{database="billing",metric="billing_invoices",sev="2",source="postgres",team="billing"} 1
+"#
+        );
+    }
+}
@@ -1,6 +1,14 @@
 use crate::{
-    db::{PostgresConnectionString, PostgresSslMode},
+    db::{
+        ClientEncoding, IpFamily, PemSource, PostgresConnection, PostgresConnectionString,
+        PostgresPooler, PostgresSslCertificates, PostgresSslMode, SslProtocolVersion,
+        VerifyCaAllowance,
+    },
     errors::PsqlExporterError,
+    internal_metrics,
+    metrics::TENANT_LABEL,
+    presets::QueryPreset,
+    query_tags::QUERY_TAGS_LABELS,
 };
 
 use figment::{
@@ -8,25 +16,72 @@ use figment::{
     Figment,
 };
 
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+
 use regex::Regex;
-use serde::Deserialize;
+use serde::{de, Deserialize, Deserializer, Serialize};
 
-use std::{collections::HashMap, env, fs::read_to_string, time::Duration};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    env,
+    fs::read_to_string,
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
 
 const DEFAULT_SCRAPE_INTERVAL: Duration = Duration::from_secs(1800);
 const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(10);
 const DEFAULT_METRIC_EXPIRATION_TIME: Duration = Duration::ZERO;
 const DB_CONNECTION_DEFAULT_BACKOFF_INTERVAL: Duration = Duration::from_secs(10);
 const DB_CONNECTION_MAXIMUM_BACKOFF_INTERVAL: Duration = Duration::from_secs(300);
+const DEFAULT_CLOCK_SKEW_TOLERANCE: Duration = Duration::from_secs(1);
+const DEFAULT_CIRCUIT_BREAKER_COOLDOWN: Duration = Duration::from_secs(300);
+const MASKED_LABEL_DIGEST_BYTES: usize = 8;
+const DEFAULT_MAX_FAMILIES: NonZeroUsize = NonZeroUsize::new(100).expect("looks like a BUG");
+const METRIC_NAME_PLACEHOLDER: &str = r"\{([a-zA-Z_][a-zA-Z0-9_]*)\}";
+const LABEL_NAME_REGEX: &str = r"^[a-zA-Z_][a-zA-Z0-9_]*$";
+// Tenant metrics are exposed at /metrics/<tenant>, so these names would be shadowed by other endpoints
+const RESERVED_TENANT_NAMES: [&str; 1] = ["list"];
+const DEFAULT_RECONNECT_ON_ERROR_CODES: [&str; 6] =
+    ["57P01", "57P02", "57P03", "08000", "08003", "08006"];
 
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct ScrapeConfig {
     #[serde(default)]
     defaults: ScrapeConfigDefaults,
+    #[serde(default)]
+    pub static_metrics: Vec<StaticMetric>,
     pub sources: HashMap<String, ScrapeConfigSource>,
 }
 
+// Fully resolved (with inherited values) query for external tooling, connection doesn't include password
+#[derive(Serialize, Debug, PartialEq)]
+pub struct QueryDefinition {
+    pub source: String,
+    pub enabled: bool,
+    pub connection: String,
+    pub metric_name: String,
+    #[serde(with = "humantime_serde")]
+    pub scrape_interval: Duration,
+    #[serde(with = "humantime_serde")]
+    pub query_timeout: Duration,
+    #[serde(with = "humantime_serde")]
+    pub metric_expiration_time: Duration,
+    pub priority: i32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StaticMetric {
+    pub name: String,
+    pub description: Option<String>,
+    pub value: f64,
+    #[serde(default)]
+    pub const_labels: HashMap<String, String>,
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields, default)]
 struct ScrapeConfigDefaults {
@@ -41,22 +96,69 @@ struct ScrapeConfigDefaults {
     #[serde(with = "humantime_serde")]
     metric_expiration_time: Duration,
     metric_prefix: Option<String>,
-    sslrootcert: Option<String>,
-    sslcert: Option<String>,
-    sslkey: Option<String>,
+    sslrootcert: Option<PemSource>,
+    sslcert: Option<PemSource>,
+    sslkey: Option<PemSource>,
+    sslrootcert_pem: Option<String>,
+    sslcert_pem: Option<String>,
+    sslkey_pem: Option<String>,
+    sslcrl: Option<String>,
     sslmode: PostgresSslMode,
+    verify_ca_allow: Vec<VerifyCaAllowance>,
+    reconnect_on_error_codes: Vec<String>,
+    const_labels: HashMap<String, String>,
+    mask_labels_key: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct ScrapeConfigSource {
+    #[serde(default)]
+    enabled: EnvBool,
     host: String,
     #[serde(default = "ScrapeConfigSource::default_port")]
     port: u16,
     user: String,
+    #[serde(default)]
     password: String,
     #[serde(default)]
+    password_file: Option<String>,
+    #[serde(default)]
     sslmode: Option<PostgresSslMode>,
+    #[serde(default)]
+    verify_ca_allow: Option<Vec<VerifyCaAllowance>>,
+    #[serde(default)]
+    reconnect_on_error_codes: Option<Vec<String>>,
+    #[serde(default)]
+    pooler: PostgresPooler,
+    #[serde(default)]
+    client_encoding: ClientEncoding,
+    #[serde(default)]
+    ssl_min_protocol_version: SslProtocolVersion,
+    #[serde(default)]
+    ip_family: IpFamily,
+    #[serde(with = "humantime_serde", default)]
+    connect_timeout: Duration,
+    #[serde(
+        with = "humantime_serde",
+        default = "ScrapeConfigSource::default_clock_skew_tolerance"
+    )]
+    clock_skew_tolerance: Duration,
+    #[serde(default, deserialize_with = "deserialize_bool")]
+    connection_info: bool,
+    #[serde(default)]
+    circuit_breaker_failures: u32,
+    #[serde(
+        with = "humantime_serde",
+        default = "ScrapeConfigSource::default_circuit_breaker_cooldown"
+    )]
+    circuit_breaker_cooldown: Duration,
+    #[serde(default)]
+    tenant: Option<String>,
+    #[serde(with = "humantime_serde", default)]
+    start_offset: Duration,
+    #[serde(with = "humantime_serde", default)]
+    scrape_jitter: Duration,
     #[serde(with = "humantime_serde", default)]
     scrape_interval: Duration,
     #[serde(with = "humantime_serde", default)]
@@ -68,20 +170,46 @@ pub struct ScrapeConfigSource {
     #[serde(with = "humantime_serde", default)]
     metric_expiration_time: Duration,
     metric_prefix: Option<String>,
-    sslrootcert: Option<String>,
-    sslcert: Option<String>,
-    sslkey: Option<String>,
+    sslrootcert: Option<PemSource>,
+    sslcert: Option<PemSource>,
+    sslkey: Option<PemSource>,
+    sslrootcert_pem: Option<String>,
+    sslcert_pem: Option<String>,
+    sslkey_pem: Option<String>,
+    sslcrl: Option<String>,
+    #[serde(default)]
+    const_labels: HashMap<String, String>,
     pub databases: Vec<ScrapeConfigDatabase>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct ScrapeConfigDatabase {
     pub dbname: String,
+    #[serde(default)]
+    connect_dbname: Option<String>,
+    #[serde(default)]
+    port: Option<u16>,
     #[serde(skip)]
     pub connection_string: PostgresConnectionString,
     #[serde(skip)]
     pub sslmode: Option<PostgresSslMode>,
+    #[serde(skip)]
+    pub verify_ca_allow: Option<Vec<VerifyCaAllowance>>,
+    #[serde(skip)]
+    pub reconnect_on_error_codes: Option<Vec<String>>,
+    #[serde(skip)]
+    pub tenant: Option<String>,
+    #[serde(skip)]
+    pub start_offset: Duration,
+    #[serde(skip)]
+    pub connection_info: bool,
+    #[serde(skip)]
+    pub circuit_breaker_failures: u32,
+    #[serde(skip)]
+    pub circuit_breaker_cooldown: Duration,
+    #[serde(with = "humantime_serde", default)]
+    pub scrape_jitter: Duration,
     #[serde(with = "humantime_serde", default)]
     scrape_interval: Duration,
     #[serde(with = "humantime_serde", default)]
@@ -94,16 +222,26 @@ pub struct ScrapeConfigDatabase {
     metric_expiration_time: Duration,
     metric_prefix: Option<String>,
     #[serde(skip)]
-    pub sslrootcert: Option<String>,
-    pub sslcert: Option<String>,
-    pub sslkey: Option<String>,
+    pub sslrootcert: Option<PemSource>,
+    pub sslcert: Option<PemSource>,
+    pub sslkey: Option<PemSource>,
+    pub sslcrl: Option<String>,
+    #[serde(default)]
+    const_labels: HashMap<String, String>,
+    #[serde(default = "ScrapeConfigDatabase::default_pool_size")]
+    pub pool_size: NonZeroUsize,
+    #[serde(default)]
+    presets: Vec<QueryPreset>,
+    #[serde(default)]
     pub queries: Vec<ScrapeConfigQuery>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct ScrapeConfigQuery {
     pub query: String,
+    #[serde(default)]
+    pub guard_query: Option<String>,
     pub metric_name: String,
     pub description: Option<String>,
     metric_prefix: Option<String>,
@@ -112,16 +250,59 @@ pub struct ScrapeConfigQuery {
     #[serde(with = "humantime_serde", default)]
     pub query_timeout: Duration,
     #[serde(with = "humantime_serde", default)]
+    pub backoff_interval: Duration,
+    #[serde(with = "humantime_serde", default)]
+    pub max_backoff_interval: Duration,
+    #[serde(default)]
+    pub max_retries: u32,
+    #[serde(with = "humantime_serde", default)]
     pub metric_expiration_time: Duration,
     #[serde(default)]
+    pub expiration_mode: ExpirationMode,
+    #[serde(default)]
+    pub timeout_action: TimeoutAction,
+    #[serde(default)]
+    pub max_rows: Option<NonZeroUsize>,
+    #[serde(default = "ScrapeConfigQuery::default_max_families")]
+    pub max_families: NonZeroUsize,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default, deserialize_with = "deserialize_bool")]
+    pub align_to_clock: bool,
+    #[serde(default)]
     pub const_labels: Option<HashMap<String, String>>,
     #[serde(default)]
     pub var_labels: Option<Vec<String>>,
     #[serde(default)]
+    pub label_remap: HashMap<String, HashMap<String, String>>,
+    #[serde(default)]
+    pub mask_labels: Vec<String>,
+    #[serde(skip)]
+    pub mask_labels_key: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_bool")]
+    pub prune_missing_labels: bool,
+    #[serde(default, deserialize_with = "deserialize_bool")]
+    pub expose_query_info: bool,
+    #[serde(default)]
+    pub tags: Option<HashMap<String, String>>,
+    #[serde(default)]
+    pub nan_policy: NanPolicy,
+    #[serde(default)]
+    pub duplicate_labels: DuplicateLabels,
+    #[serde(default, deserialize_with = "deserialize_bool")]
+    pub skip_null: bool,
+    #[serde(default)]
+    pub null_value: Option<NullValue>,
+    #[serde(
+        default = "ScrapeConfigQuery::default_suffix_in_description",
+        deserialize_with = "deserialize_bool"
+    )]
+    pub suffix_in_description: bool,
+    #[serde(default)]
     pub values: ScrapeConfigValues, // These two vectors have the same size
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub enum ScrapeConfigValues {
     #[serde(rename = "single")]
@@ -130,61 +311,554 @@ pub enum ScrapeConfigValues {
     ValuesWithLabels(Vec<FieldWithLabels>),
     #[serde(rename = "multi_suffixes")]
     ValuesWithSuffixes(Vec<FieldWithSuffix>),
+    #[serde(rename = "summary")]
+    Summary(SummaryFields),
+    #[serde(rename = "key_value")]
+    KeyValue(KeyValueFields),
+    #[serde(rename = "info")]
+    Info,
+    #[serde(rename = "histogram")]
+    Histogram(HistogramFields),
+    #[serde(rename = "state_set")]
+    StateSet(StateSetFields),
+    #[serde(rename = "lsn_lag")]
+    LsnLag(LsnLagFields),
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct FieldWithType {
-    pub field: Option<String>,
+    pub field: Option<Field>,
     #[serde(rename = "type", default)]
     pub field_type: FieldType,
+    #[serde(default)]
+    pub aggregate: Option<AggregateFunction>,
+    #[serde(default)]
+    pub shadow_query: Option<String>,
+    #[serde(default, rename = "assert")]
+    pub assertion: Option<ValueAssertion>,
+    #[serde(default = "ValueTransform::default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub offset: f64,
+    #[serde(default)]
+    pub resolution: TimestampResolution,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct FieldWithLabels {
-    pub field: String,
+    pub field: Field,
     #[serde(rename = "type", default)]
     pub field_type: FieldType,
     pub labels: HashMap<String, String>,
+    #[serde(default = "ValueTransform::default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub offset: f64,
+    #[serde(default)]
+    pub resolution: TimestampResolution,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct FieldWithSuffix {
-    pub field: String,
+    pub field: Field,
     #[serde(rename = "type", default)]
     pub field_type: FieldType,
     pub suffix: String,
+    pub description: Option<String>,
+    #[serde(default = "ValueTransform::default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub offset: f64,
+    #[serde(default)]
+    pub resolution: TimestampResolution,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SummaryFields {
+    pub quantile_field: Field,
+    pub value_field: Field,
+    pub sum_field: Option<Field>,
+    pub count_field: Option<Field>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct KeyValueFields {
+    pub key_field: Field,
+    pub value_field: Field,
+    #[serde(default = "KeyValueFields::default_label")]
+    pub label: String,
+}
+
+impl KeyValueFields {
+    fn default_label() -> String {
+        String::from("key")
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StateSetFields {
+    pub field: Field,
+    pub states: Vec<String>,
+    #[serde(default = "StateSetFields::default_label")]
+    pub label: String,
+}
+
+impl StateSetFields {
+    fn default_label() -> String {
+        String::from("state")
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LsnLagFields {
+    pub field: Field,
+    pub base_field: Field,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HistogramFields {
+    pub field: Field,
+    pub buckets: Vec<f64>,
+}
+
+impl HistogramFields {
+    // Prometheus silently replaces empty buckets with the default ones, so it's checked here
+    fn validate(&self, metric_name: &str) -> Result<(), PsqlExporterError> {
+        let reason = if self.buckets.is_empty() {
+            "at least one bucket is required"
+        } else if self
+            .buckets
+            .windows(2)
+            .any(|pair| pair[0].partial_cmp(&pair[1]) != Some(Ordering::Less))
+        {
+            "buckets must be strictly increasing"
+        } else {
+            return Ok(());
+        };
+
+        Err(PsqlExporterError::InvalidConfigValue {
+            field: format!("{metric_name}.values.histogram.buckets"),
+            value: format!("{:?}", self.buckets),
+            reason: reason.to_string(),
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(from = "RawField")]
+pub enum Field {
+    Name(String),
+    Index(usize),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawField {
+    Index(usize),
+    Name(String),
+}
+
+impl From<RawField> for Field {
+    fn from(value: RawField) -> Self {
+        match value {
+            RawField::Index(index) => Self::Index(index),
+            RawField::Name(name) => match name.parse::<usize>() {
+                Ok(index) => Self::Index(index),
+                Err(_) => Self::Name(name),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ValueAssertion {
+    #[serde(default)]
+    pub min: Option<f64>,
+    #[serde(default)]
+    pub max: Option<f64>,
+}
+
+impl ValueAssertion {
+    // NaN is never in range
+    pub fn holds(&self, value: f64) -> bool {
+        self.min.is_none_or(|min| value >= min) && self.max.is_none_or(|max| value <= max)
+    }
+}
+
+// Linear transformation of float values: value * scale + offset
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValueTransform {
+    scale: f64,
+    offset: f64,
+}
+
+impl ValueTransform {
+    pub fn new(scale: f64, offset: f64) -> Self {
+        Self { scale, offset }
+    }
+
+    // Timestamp is converted to the resolution before scale and offset are applied
+    pub fn with_resolution(mut self, resolution: TimestampResolution) -> Self {
+        self.scale *= resolution.factor();
+        self
+    }
+
+    fn default_scale() -> f64 {
+        1.0
+    }
+
+    pub fn apply(&self, value: f64) -> f64 {
+        value * self.scale + self.offset
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(deny_unknown_fields, rename_all = "lowercase")]
+pub enum TimestampResolution {
+    #[default]
+    Seconds,
+    Millis,
+}
+
+impl TimestampResolution {
+    fn factor(&self) -> f64 {
+        match self {
+            Self::Seconds => 1.0,
+            Self::Millis => 1000.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 #[serde(deny_unknown_fields, rename_all = "lowercase")]
 pub enum FieldType {
     Int,
     Float,
+    Timestamp,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "lowercase")]
+pub enum AggregateFunction {
+    Sum,
+    Min,
+    Max,
+    Avg,
+    Last,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+enum EnvBool {
+    #[serde(deserialize_with = "deserialize_bool")]
+    Value(bool),
+    Template(String),
+}
+
+impl Default for EnvBool {
+    fn default() -> Self {
+        Self::Value(true)
+    }
+}
+
+impl EnvBool {
+    fn merge_env_vars(&mut self, field: &str) -> Result<(), PsqlExporterError> {
+        if let Self::Template(template) = self {
+            let value = apply_envs_to_string(template)?;
+            let value = parse_bool(&value).ok_or_else(|| PsqlExporterError::ParseBooleanValue {
+                field: field.to_string(),
+                value,
+            })?;
+            *self = Self::Value(value);
+        }
+
+        Ok(())
+    }
+
+    fn value(&self) -> bool {
+        match self {
+            Self::Value(value) => *value,
+            Self::Template(_) => panic!("looks like a BUG: environment variables aren't merged"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawBool {
+    Bool(bool),
+    Int(i64),
+    Text(String),
+}
+
+// The same boolean representations are accepted in config and in environment variables
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Some(true),
+        "false" | "0" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+fn deserialize_bool<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    match RawBool::deserialize(deserializer)? {
+        RawBool::Bool(value) => Ok(value),
+        RawBool::Int(1) => Ok(true),
+        RawBool::Int(0) => Ok(false),
+        RawBool::Int(value) => Err(de::Error::custom(format!(
+            "invalid boolean value '{value}'"
+        ))),
+        RawBool::Text(value) => parse_bool(&value)
+            .ok_or_else(|| de::Error::custom(format!("invalid boolean value '{value}'"))),
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(deny_unknown_fields, rename_all = "lowercase")]
+pub enum NanPolicy {
+    #[default]
+    Keep,
+    Zero,
+    Skip,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum NullValue {
+    Int(i64),
+    Float(f64),
+}
+
+impl NullValue {
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::Int(value) => Some(*value),
+            Self::Float(_) => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            Self::Int(value) => *value as f64,
+            Self::Float(value) => *value,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(deny_unknown_fields, rename_all = "lowercase")]
+pub enum DuplicateLabels {
+    #[default]
+    Last,
+    Sum,
+    Error,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub enum ExpirationMode {
+    #[default]
+    Remove,
+    MarkStale,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub enum TimeoutAction {
+    Keep,
+    Zero,
+    #[default]
+    Expire,
+    Flag,
+}
+
+impl NanPolicy {
+    pub fn apply(&self, value: f64) -> Option<f64> {
+        if value.is_finite() {
+            return Some(value);
+        }
+
+        match self {
+            Self::Keep => Some(value),
+            Self::Zero => Some(0.0),
+            Self::Skip => None,
+        }
+    }
 }
 
 impl ScrapeConfig {
+    // It's called on start and on each reload, failed loads are measured too
     pub fn from(filename: &String) -> Result<ScrapeConfig, PsqlExporterError> {
-        let config = read_to_string(filename).map_err(|e| PsqlExporterError::LoadConfigFile {
-            filename: filename.clone(),
-            cause: e,
-        })?;
-        let mut config: ScrapeConfig = Figment::new().merge(Yaml::string(&config)).extract()?;
+        let started = Instant::now();
+        let config = read_to_string(filename)
+            .map_err(|e| PsqlExporterError::LoadConfigFile {
+                filename: filename.clone(),
+                cause: e,
+            })
+            .and_then(|config| Self::from_yaml(&config));
+        internal_metrics::set_config_parse_duration(started.elapsed());
+
+        config
+    }
+
+    pub fn from_yaml(config: &str) -> Result<ScrapeConfig, PsqlExporterError> {
+        let mut config: ScrapeConfig = Figment::new().merge(Yaml::string(config)).extract()?;
 
         config.defaults.merge_env_vars()?;
-        for (_name, instance) in config.sources.iter_mut() {
+        for (name, instance) in config.sources.iter_mut() {
+            instance.enabled.merge_env_vars("enabled")?;
+            // disabled source may refer to variables and files which exist in other environments only
+            if !instance.is_enabled() {
+                instance.propagate_defaults(&config.defaults);
+                continue;
+            }
             instance.merge_env_vars()?;
+            if let Some(tenant) = &instance.tenant {
+                if RESERVED_TENANT_NAMES.contains(&tenant.as_str()) {
+                    return Err(PsqlExporterError::InvalidConfigValue {
+                        field: format!("{name}.tenant"),
+                        value: tenant.clone(),
+                        reason: String::from("tenant name is reserved by other endpoint"),
+                    });
+                }
+            }
+            instance.apply_presets()?;
             instance.propagate_defaults(&config.defaults);
+            for database in instance.databases.iter() {
+                // collector of the database without queries would have nothing to schedule
+                if database.queries.is_empty() {
+                    return Err(PsqlExporterError::InvalidConfigValue {
+                        field: format!("{name}.databases.queries"),
+                        value: database.dbname.clone(),
+                        reason: String::from("database should have at least one query or preset"),
+                    });
+                }
+                for query in database.queries.iter() {
+                    if let ScrapeConfigValues::Histogram(fields) = &query.values {
+                        fields.validate(&query.metric_name)?;
+                    }
+                    query.validate_null_value()?;
+                    query.validate_transform()?;
+                    query.validate_aggregate()?;
+                    query.validate_tags()?;
+                    query.validate_mask_labels()?;
+                    if database.tenant.is_some() {
+                        query.validate_tenant_label()?;
+                    }
+                    query.validate_metric_name_template()?;
+                }
+            }
         }
+        config.check_duplicate_families()?;
 
         Ok(config)
     }
 
+    // Families of enabled sources share the registry of their tenant, so the same family
+    // with the same const labels can't be registered twice
+    fn check_duplicate_families(&self) -> Result<(), PsqlExporterError> {
+        let mut families = HashSet::new();
+        for (name, source) in self.sources.iter().filter(|(_, s)| s.is_enabled()) {
+            for database in source.databases.iter() {
+                for query in database.queries.iter() {
+                    let mut const_labels: Vec<(&String, &String)> =
+                        query.const_labels.iter().flatten().collect();
+                    const_labels.sort();
+                    for family in query.family_names() {
+                        if !families.insert((&source.tenant, family.clone(), const_labels.clone()))
+                        {
+                            return Err(PsqlExporterError::InvalidConfigValue {
+                                field: format!("{name}.databases.queries.metric_name"),
+                                value: family,
+                                reason: String::from(
+                                    "the same metric with the same const_labels is produced by another query",
+                                ),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn len(&self) -> usize {
         self.sources.len()
     }
+
+    pub fn query_definitions(&self) -> Vec<QueryDefinition> {
+        let mut source_names: Vec<&String> = self.sources.keys().collect();
+        source_names.sort();
+
+        let mut definitions = vec![];
+        for source_name in source_names {
+            let source = &self.sources[source_name];
+            for database in source.databases.iter() {
+                definitions.extend(database.queries.iter().map(|query| QueryDefinition {
+                    source: source_name.clone(),
+                    enabled: source.is_enabled(),
+                    connection: database.connection_string.id(),
+                    metric_name: query.metric_name.clone(),
+                    scrape_interval: query.scrape_interval,
+                    query_timeout: query.query_timeout,
+                    metric_expiration_time: query.metric_expiration_time,
+                    priority: query.priority,
+                }));
+            }
+        }
+
+        definitions
+    }
+
+    // Certificates are loaded on each connect only, so broken paths are found before start this way
+    pub fn check_certificates(&self) -> Result<(), PsqlExporterError> {
+        for source in self.sources.values().filter(|source| source.is_enabled()) {
+            for database in source.databases.iter() {
+                let certificates = PostgresSslCertificates::from(
+                    database.sslrootcert.clone(),
+                    database.sslcert.clone(),
+                    database.sslkey.clone(),
+                )?
+                .with_crl(database.sslcrl.clone());
+                PostgresConnection::check_tls_config(
+                    database.sslmode.as_ref().unwrap(),
+                    database.verify_ca_allow.as_deref().unwrap(),
+                    database.connection_string.ssl_min_protocol_version,
+                    &certificates,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Patterns look like host/dbname, both parts may contain * wildcard
+    pub fn retain_databases(&mut self, patterns: &[String]) {
+        let patterns: Vec<Regex> = patterns
+            .iter()
+            .map(|pattern| {
+                let pattern = format!("^{}$", regex::escape(pattern).replace(r"\*", ".*"));
+                Regex::new(&pattern).unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+            })
+            .collect();
+
+        for source in self.sources.values_mut() {
+            source.databases.retain(|database| {
+                let id = format!("{}/{}", database.connection_string.host, database.dbname);
+                patterns.iter().any(|pattern| pattern.is_match(&id))
+            });
+        }
+    }
 }
 
 impl Default for ScrapeConfigDefaults {
@@ -199,21 +873,36 @@ impl Default for ScrapeConfigDefaults {
             sslrootcert: None,
             sslcert: None,
             sslkey: None,
+            sslrootcert_pem: None,
+            sslcert_pem: None,
+            sslkey_pem: None,
+            sslcrl: None,
             sslmode: PostgresSslMode::default(),
+            verify_ca_allow: VerifyCaAllowance::default_set(),
+            reconnect_on_error_codes: DEFAULT_RECONNECT_ON_ERROR_CODES
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+            const_labels: HashMap::new(),
+            mask_labels_key: None,
         }
     }
 }
 
 impl ScrapeConfigDefaults {
     fn merge_env_vars(&mut self) -> Result<(), PsqlExporterError> {
-        if let Some(rootcert) = self.sslrootcert.clone() {
-            self.sslrootcert = Some(apply_envs_to_string(&rootcert)?);
+        merge_pem_source(
+            &mut self.sslrootcert,
+            self.sslrootcert_pem.take(),
+            "sslrootcert",
+        )?;
+        merge_pem_source(&mut self.sslcert, self.sslcert_pem.take(), "sslcert")?;
+        merge_pem_source(&mut self.sslkey, self.sslkey_pem.take(), "sslkey")?;
+        if let Some(crl) = self.sslcrl.clone() {
+            self.sslcrl = Some(apply_envs_to_string(&crl)?);
         }
-        if let Some(cert) = self.sslcert.clone() {
-            self.sslcert = Some(apply_envs_to_string(&cert)?);
-        }
-        if let Some(key) = self.sslkey.clone() {
-            self.sslkey = Some(apply_envs_to_string(&key)?);
+        if let Some(key) = self.mask_labels_key.clone() {
+            self.mask_labels_key = Some(apply_envs_to_string(&key)?);
         }
 
         Ok(())
@@ -225,6 +914,14 @@ impl ScrapeConfigSource {
         5432
     }
 
+    fn default_clock_skew_tolerance() -> Duration {
+        DEFAULT_CLOCK_SKEW_TOLERANCE
+    }
+
+    fn default_circuit_breaker_cooldown() -> Duration {
+        DEFAULT_CIRCUIT_BREAKER_COOLDOWN
+    }
+
     fn propagate_defaults(&mut self, defaults: &ScrapeConfigDefaults) {
         let defaults = ScrapeConfigDefaults {
             scrape_interval: if self.scrape_interval == Duration::default() {
@@ -285,6 +982,17 @@ impl ScrapeConfigSource {
                 }
                 _ => self.sslkey.clone(),
             },
+            // inline PEM content has been merged into the fields above already
+            sslrootcert_pem: None,
+            sslcert_pem: None,
+            sslkey_pem: None,
+            sslcrl: match self.sslcrl {
+                None => {
+                    self.sslcrl.clone_from(&defaults.sslcrl);
+                    defaults.sslcrl.clone()
+                }
+                _ => self.sslcrl.clone(),
+            },
             sslmode: match self.sslmode {
                 None => {
                     self.sslmode = Some(defaults.sslmode.clone());
@@ -292,33 +1000,92 @@ impl ScrapeConfigSource {
                 }
                 _ => self.sslmode.clone().unwrap(),
             },
+            verify_ca_allow: match self.verify_ca_allow {
+                None => {
+                    self.verify_ca_allow = Some(defaults.verify_ca_allow.clone());
+                    defaults.verify_ca_allow.clone()
+                }
+                _ => self.verify_ca_allow.clone().unwrap(),
+            },
+            reconnect_on_error_codes: match self.reconnect_on_error_codes {
+                None => {
+                    self.reconnect_on_error_codes = Some(defaults.reconnect_on_error_codes.clone());
+                    defaults.reconnect_on_error_codes.clone()
+                }
+                _ => self.reconnect_on_error_codes.clone().unwrap(),
+            },
+            const_labels: merge_const_labels(&defaults.const_labels, &self.const_labels),
+            mask_labels_key: defaults.mask_labels_key.clone(),
         };
 
         self.databases.iter_mut().for_each(|db| {
             let conn_string = PostgresConnectionString {
                 host: self.host.clone(),
-                port: self.port,
+                port: db.port.unwrap_or(self.port),
                 user: self.user.clone(),
                 password: self.password.clone(),
                 sslmode: self.sslmode.clone().unwrap(),
                 dbname: db.dbname.clone(),
+                connect_dbname: db.connect_dbname.clone(),
+                pooler: self.pooler.clone(),
+                statement_timeout: Duration::ZERO,
+                client_encoding: self.client_encoding.clone(),
+                ip_family: self.ip_family,
+                ssl_min_protocol_version: self.ssl_min_protocol_version,
+                connect_timeout: self.connect_timeout,
+                clock_skew_tolerance: self.clock_skew_tolerance,
             };
+            db.tenant = self.tenant.clone();
+            db.start_offset = self.start_offset;
+            if db.scrape_jitter.is_zero() {
+                db.scrape_jitter = self.scrape_jitter;
+            }
+            db.connection_info = self.connection_info;
+            db.circuit_breaker_failures = self.circuit_breaker_failures;
+            db.circuit_breaker_cooldown = self.circuit_breaker_cooldown;
             db.propagate_defaults(&defaults, conn_string);
         });
     }
 
+    fn apply_presets(&mut self) -> Result<(), PsqlExporterError> {
+        for db in self.databases.iter_mut() {
+            db.apply_presets()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.value()
+    }
+
     fn merge_env_vars(&mut self) -> Result<(), PsqlExporterError> {
         self.host = apply_envs_to_string(&self.host)?;
         self.user = apply_envs_to_string(&self.user)?;
         self.password = apply_envs_to_string(&self.password)?;
-        if let Some(rootcert) = self.sslrootcert.clone() {
-            self.sslrootcert = Some(apply_envs_to_string(&rootcert)?);
-        }
-        if let Some(cert) = self.sslcert.clone() {
-            self.sslcert = Some(apply_envs_to_string(&cert)?);
+        if let Some(password_file) = &self.password_file {
+            if !self.password.is_empty() {
+                return Err(PsqlExporterError::InvalidConfigValue {
+                    field: String::from("password_file"),
+                    value: password_file.clone(),
+                    reason: String::from("password and password_file are mutually exclusive"),
+                });
+            }
+            let filename = apply_envs_to_string(password_file)?;
+            let password = read_to_string(&filename)
+                .map_err(|cause| PsqlExporterError::LoadPasswordFile { filename, cause })?;
+            // mounted secrets usually end with a newline which isn't a part of the password
+            self.password = password.trim_end_matches(['\n', '\r']).to_string();
         }
-        if let Some(key) = self.sslkey.clone() {
-            self.sslkey = Some(apply_envs_to_string(&key)?);
+        merge_pem_source(
+            &mut self.sslrootcert,
+            self.sslrootcert_pem.take(),
+            "sslrootcert",
+        )?;
+        merge_pem_source(&mut self.sslcert, self.sslcert_pem.take(), "sslcert")?;
+        merge_pem_source(&mut self.sslkey, self.sslkey_pem.take(), "sslkey")?;
+        if let Some(crl) = self.sslcrl.clone() {
+            self.sslcrl = Some(apply_envs_to_string(&crl)?);
         }
 
         Ok(())
@@ -326,6 +1093,26 @@ impl ScrapeConfigSource {
 }
 
 impl ScrapeConfigDatabase {
+    fn default_pool_size() -> NonZeroUsize {
+        NonZeroUsize::MIN
+    }
+
+    // Presets of different DBs produce the same families, so they're told apart by the database label
+    fn apply_presets(&mut self) -> Result<(), PsqlExporterError> {
+        for preset in self.presets.iter() {
+            self.queries
+                .extend(preset.queries()?.into_iter().map(|mut query| {
+                    query
+                        .const_labels
+                        .get_or_insert_with(HashMap::new)
+                        .insert(String::from("database"), self.dbname.clone());
+                    query
+                }));
+        }
+
+        Ok(())
+    }
+
     fn propagate_defaults(
         &mut self,
         defaults: &ScrapeConfigDefaults,
@@ -391,6 +1178,17 @@ impl ScrapeConfigDatabase {
                 }
                 _ => self.sslkey.clone(),
             },
+            // inline PEM content has been merged into the fields above already
+            sslrootcert_pem: None,
+            sslcert_pem: None,
+            sslkey_pem: None,
+            sslcrl: match self.sslcrl {
+                None => {
+                    self.sslcrl.clone_from(&defaults.sslcrl);
+                    defaults.sslcrl.clone()
+                }
+                _ => self.sslcrl.clone(),
+            },
             sslmode: match self.sslmode {
                 None => {
                     self.sslmode = Some(defaults.sslmode.clone());
@@ -398,15 +1196,464 @@ impl ScrapeConfigDatabase {
                 }
                 _ => self.sslmode.clone().unwrap(),
             },
+            verify_ca_allow: match self.verify_ca_allow {
+                None => {
+                    self.verify_ca_allow = Some(defaults.verify_ca_allow.clone());
+                    defaults.verify_ca_allow.clone()
+                }
+                _ => self.verify_ca_allow.clone().unwrap(),
+            },
+            reconnect_on_error_codes: match self.reconnect_on_error_codes {
+                None => {
+                    self.reconnect_on_error_codes = Some(defaults.reconnect_on_error_codes.clone());
+                    defaults.reconnect_on_error_codes.clone()
+                }
+                _ => self.reconnect_on_error_codes.clone().unwrap(),
+            },
+            const_labels: merge_const_labels(&defaults.const_labels, &self.const_labels),
+            mask_labels_key: defaults.mask_labels_key.clone(),
         };
 
+        self.connection_string.statement_timeout = self.query_timeout;
+
         self.queries.iter_mut().for_each(|q| {
             q.propagate_defaults(&defaults);
         });
     }
 }
 
+// Inline PEM content replaces path, so the rest of the config doesn't distinguish them
+fn merge_pem_source(
+    source: &mut Option<PemSource>,
+    pem: Option<String>,
+    option: &str,
+) -> Result<(), PsqlExporterError> {
+    if let Some(PemSource::File(path)) = source {
+        *path = apply_envs_to_string(path)?;
+    }
+    if let Some(pem) = pem {
+        if source.is_some() {
+            return Err(PsqlExporterError::InvalidConfigValue {
+                field: format!("{option}_pem"),
+                value: String::from("<inline PEM>"),
+                reason: format!("{option} and {option}_pem are mutually exclusive"),
+            });
+        }
+        *source = Some(PemSource::Inline(apply_envs_to_string(&pem)?));
+    }
+
+    Ok(())
+}
+
+// Labels of the more specific section win: query > database > source > defaults
+fn merge_const_labels(
+    inherited: &HashMap<String, String>,
+    own: &HashMap<String, String>,
+) -> HashMap<String, String> {
+    let mut labels = inherited.clone();
+    labels.extend(own.iter().map(|(k, v)| (k.clone(), v.clone())));
+    labels
+}
+
+// HMAC is stable across restarts and versions while the key is the same, so the label dimension is kept
+// without exposing the value
+fn mask_label_value(key: &str, value: &str) -> String {
+    let digest = PKey::hmac(key.as_bytes())
+        .and_then(|key| {
+            let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+            signer.update(value.as_bytes())?;
+            signer.sign_to_vec()
+        })
+        .unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
+    digest[..MASKED_LABEL_DIGEST_BYTES]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
 impl ScrapeConfigQuery {
+    fn default_suffix_in_description() -> bool {
+        true
+    }
+
+    fn default_max_families() -> NonZeroUsize {
+        DEFAULT_MAX_FAMILIES
+    }
+
+    // Columns of {column} placeholders in the metric name, each distinct value of them produces its own family
+    pub fn name_placeholders(&self) -> Vec<String> {
+        let re =
+            Regex::new(METRIC_NAME_PLACEHOLDER).unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
+        re.captures_iter(&self.metric_name)
+            .map(|item| item[1].to_string())
+            .collect()
+    }
+
+    pub fn is_templated(&self) -> bool {
+        !self.name_placeholders().is_empty()
+    }
+
+    // Families of templated names are unknown until the query returns rows
+    fn family_names(&self) -> Vec<String> {
+        if self.is_templated() {
+            return vec![];
+        }
+
+        match &self.values {
+            ScrapeConfigValues::ValuesWithSuffixes(values) => values
+                .iter()
+                .map(|value| format!("{}_{}", self.metric_name, value.suffix))
+                .collect(),
+            _ => vec![self.metric_name.clone()],
+        }
+    }
+
+    // None if any of the placeholder columns has no value (NULL)
+    pub fn render_metric_name(
+        &self,
+        column_value: impl Fn(&str) -> Option<String>,
+    ) -> Option<String> {
+        let re =
+            Regex::new(METRIC_NAME_PLACEHOLDER).unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
+        let mut complete = true;
+        let name = re
+            .replace_all(&self.metric_name, |item: &regex::Captures| {
+                column_value(&item[1]).unwrap_or_else(|| {
+                    complete = false;
+                    String::new()
+                })
+            })
+            .to_string();
+
+        complete.then_some(name)
+    }
+
+    // Families are created on the fly, so everything which needs a known name in advance is rejected
+    fn validate_metric_name_template(&self) -> Result<(), PsqlExporterError> {
+        if !self.is_templated() {
+            return Ok(());
+        }
+
+        let invalid = |reason: &str| PsqlExporterError::InvalidConfigValue {
+            field: String::from("metric_name"),
+            value: self.metric_name.clone(),
+            reason: reason.to_string(),
+        };
+        let name_re = Regex::new(r"^[a-zA-Z_:][a-zA-Z0-9_:]*$")
+            .unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
+        if !self
+            .render_metric_name(|_| Some(String::from("x")))
+            .is_some_and(|name| name_re.is_match(&name))
+        {
+            return Err(invalid("invalid metric name template"));
+        }
+        if !matches!(self.values, ScrapeConfigValues::ValueFrom(_)) {
+            return Err(invalid("placeholders are applicable to single values only"));
+        }
+        if self.expiration_mode == ExpirationMode::MarkStale {
+            return Err(invalid(
+                "placeholders can't be used with mark_stale expiration mode",
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn label_value(&self, label: &str, value: String) -> String {
+        let value = self.remap_label_value(label, value);
+        match &self.mask_labels_key {
+            Some(key) if self.mask_labels.iter().any(|l| l == label) => {
+                mask_label_value(key, &value)
+            }
+            _ => value,
+        }
+    }
+
+    // Unkeyed hash of low-cardinality values (like IP addresses) is easy to reverse by brute force
+    fn validate_mask_labels(&self) -> Result<(), PsqlExporterError> {
+        if !self.mask_labels.is_empty()
+            && self
+                .mask_labels_key
+                .as_deref()
+                .unwrap_or_default()
+                .is_empty()
+        {
+            return Err(PsqlExporterError::InvalidConfigValue {
+                field: format!("{}.mask_labels", self.metric_name),
+                value: self.mask_labels.join(", "),
+                reason: String::from("mask_labels_key should be set in defaults section"),
+            });
+        }
+
+        Ok(())
+    }
+
+    // All columns which the query result should contain, with paths of the options which refer to them
+    pub fn referenced_fields(&self) -> Vec<(String, Field)> {
+        let path = |option: &str| format!("{}.{option}", self.metric_name);
+        let mut fields: Vec<(String, Field)> = self
+            .var_labels
+            .iter()
+            .flatten()
+            .map(|label| (path("var_labels"), Field::Name(label.clone())))
+            .collect();
+        fields.extend(
+            self.name_placeholders()
+                .into_iter()
+                .map(|column| (path("metric_name"), Field::Name(column))),
+        );
+
+        match &self.values {
+            ScrapeConfigValues::ValueFrom(value) => {
+                fields.extend(
+                    value
+                        .field
+                        .iter()
+                        .map(|field| (path("values.single.field"), field.clone())),
+                );
+            }
+            ScrapeConfigValues::ValuesWithLabels(values) => fields.extend(
+                values
+                    .iter()
+                    .map(|value| (path("values.multi_labels.field"), value.field.clone())),
+            ),
+            ScrapeConfigValues::ValuesWithSuffixes(values) => fields.extend(
+                values
+                    .iter()
+                    .map(|value| (path("values.multi_suffixes.field"), value.field.clone())),
+            ),
+            ScrapeConfigValues::Summary(summary) => {
+                fields.push((
+                    path("values.summary.quantile_field"),
+                    summary.quantile_field.clone(),
+                ));
+                fields.push((
+                    path("values.summary.value_field"),
+                    summary.value_field.clone(),
+                ));
+                if let Some(field) = &summary.sum_field {
+                    fields.push((path("values.summary.sum_field"), field.clone()));
+                }
+                if let Some(field) = &summary.count_field {
+                    fields.push((path("values.summary.count_field"), field.clone()));
+                }
+            }
+            ScrapeConfigValues::KeyValue(key_value) => {
+                fields.push((
+                    path("values.key_value.key_field"),
+                    key_value.key_field.clone(),
+                ));
+                fields.push((
+                    path("values.key_value.value_field"),
+                    key_value.value_field.clone(),
+                ));
+            }
+            ScrapeConfigValues::Info => {}
+            ScrapeConfigValues::Histogram(histogram) => {
+                fields.push((path("values.histogram.field"), histogram.field.clone()));
+            }
+            ScrapeConfigValues::StateSet(state_set) => {
+                fields.push((path("values.state_set.field"), state_set.field.clone()));
+            }
+            ScrapeConfigValues::LsnLag(lsn_lag) => {
+                fields.push((path("values.lsn_lag.field"), lsn_lag.field.clone()));
+                fields.push((
+                    path("values.lsn_lag.base_field"),
+                    lsn_lag.base_field.clone(),
+                ));
+            }
+        }
+
+        fields
+    }
+
+    // Tags are label names of the tags metric, so the invalid one would break the whole exposition
+    fn validate_tags(&self) -> Result<(), PsqlExporterError> {
+        let name_re =
+            Regex::new(LABEL_NAME_REGEX).unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
+        for tag in self.tags.iter().flatten().map(|(tag, _)| tag) {
+            let reason = if !name_re.is_match(tag) {
+                "tag should be a valid label name"
+            } else if QUERY_TAGS_LABELS.contains(&tag.as_str()) {
+                "tag name is reserved for built-in label"
+            } else {
+                continue;
+            };
+            return Err(PsqlExporterError::InvalidConfigValue {
+                field: format!("{}.tags", self.metric_name),
+                value: tag.clone(),
+                reason: String::from(reason),
+            });
+        }
+
+        Ok(())
+    }
+
+    // Metrics of tenant sources get tenant label at /metrics endpoint, so the query can't have its own
+    fn validate_tenant_label(&self) -> Result<(), PsqlExporterError> {
+        let in_const_labels = self
+            .const_labels
+            .as_ref()
+            .is_some_and(|labels| labels.contains_key(TENANT_LABEL));
+        let in_var_labels = self
+            .var_labels
+            .as_ref()
+            .is_some_and(|labels| labels.iter().any(|label| label == TENANT_LABEL));
+        if in_const_labels || in_var_labels {
+            return Err(PsqlExporterError::InvalidConfigValue {
+                field: format!(
+                    "{}.{}",
+                    self.metric_name,
+                    if in_const_labels {
+                        "const_labels"
+                    } else {
+                        "var_labels"
+                    }
+                ),
+                value: String::from(TENANT_LABEL),
+                reason: String::from("label is reserved for the tenant name of tenant sources"),
+            });
+        }
+
+        Ok(())
+    }
+
+    // Each row of the query with var_labels is its own series, so there's nothing to aggregate
+    fn validate_aggregate(&self) -> Result<(), PsqlExporterError> {
+        if let ScrapeConfigValues::ValueFrom(FieldWithType {
+            aggregate: Some(aggregate),
+            ..
+        }) = &self.values
+        {
+            if self.var_labels.is_some() {
+                return Err(PsqlExporterError::InvalidConfigValue {
+                    field: format!("{}.values.single.aggregate", self.metric_name),
+                    value: format!("{aggregate:?}").to_lowercase(),
+                    reason: String::from("applicable to metrics without var_labels only"),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    // Int values are exposed as is, so the transform would be silently ignored,
+    // and resolution is meaningful for timestamps only
+    fn validate_transform(&self) -> Result<(), PsqlExporterError> {
+        let transforms: Vec<(&str, &FieldType, f64, f64, TimestampResolution)> = match &self.values
+        {
+            ScrapeConfigValues::ValueFrom(value) => vec![(
+                "single",
+                &value.field_type,
+                value.scale,
+                value.offset,
+                value.resolution,
+            )],
+            ScrapeConfigValues::ValuesWithLabels(values) => values
+                .iter()
+                .map(|value| {
+                    (
+                        "multi_labels",
+                        &value.field_type,
+                        value.scale,
+                        value.offset,
+                        value.resolution,
+                    )
+                })
+                .collect(),
+            ScrapeConfigValues::ValuesWithSuffixes(values) => values
+                .iter()
+                .map(|value| {
+                    (
+                        "multi_suffixes",
+                        &value.field_type,
+                        value.scale,
+                        value.offset,
+                        value.resolution,
+                    )
+                })
+                .collect(),
+            _ => vec![],
+        };
+
+        for (values, field_type, scale, offset, resolution) in transforms {
+            if resolution != TimestampResolution::default()
+                && !matches!(field_type, FieldType::Timestamp)
+            {
+                return Err(PsqlExporterError::InvalidConfigValue {
+                    field: format!("{}.values.{values}.resolution", self.metric_name),
+                    value: format!("{resolution:?}").to_lowercase(),
+                    reason: String::from("applicable to timestamp fields only"),
+                });
+            }
+            if !matches!(field_type, FieldType::Int) {
+                continue;
+            }
+            for (option, value, default) in [
+                ("scale", scale, ValueTransform::default_scale()),
+                ("offset", offset, 0.0),
+            ] {
+                if value != default {
+                    return Err(PsqlExporterError::InvalidConfigValue {
+                        field: format!("{}.values.{values}.{option}", self.metric_name),
+                        value: value.to_string(),
+                        reason: String::from("applicable to float fields only"),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Float replacement can't be set to int metric, and it's meaningless for non-gauge values
+    fn validate_null_value(&self) -> Result<(), PsqlExporterError> {
+        let Some(null_value) = self.null_value else {
+            return Ok(());
+        };
+
+        let field_types: Vec<&FieldType> = match &self.values {
+            ScrapeConfigValues::ValueFrom(value) => vec![&value.field_type],
+            ScrapeConfigValues::ValuesWithLabels(values) => {
+                values.iter().map(|value| &value.field_type).collect()
+            }
+            ScrapeConfigValues::ValuesWithSuffixes(values) => {
+                values.iter().map(|value| &value.field_type).collect()
+            }
+            _ => {
+                return Err(PsqlExporterError::InvalidConfigValue {
+                    field: format!("{}.null_value", self.metric_name),
+                    value: null_value.as_f64().to_string(),
+                    reason: String::from(
+                        "applicable to single, multi_labels and multi_suffixes values only",
+                    ),
+                })
+            }
+        };
+
+        if null_value.as_i64().is_none()
+            && field_types
+                .iter()
+                .any(|field_type| matches!(field_type, FieldType::Int))
+        {
+            return Err(PsqlExporterError::InvalidConfigValue {
+                field: format!("{}.null_value", self.metric_name),
+                value: null_value.as_f64().to_string(),
+                reason: String::from("float value can't be used with int field"),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn remap_label_value(&self, label: &str, value: String) -> String {
+        self.label_remap
+            .get(label)
+            .and_then(|remap| remap.get(&value))
+            .cloned()
+            .unwrap_or(value)
+    }
+
     fn propagate_defaults(&mut self, defaults: &ScrapeConfigDefaults) {
         self.scrape_interval = if self.scrape_interval == Duration::default() {
             defaults.scrape_interval
@@ -418,6 +1665,16 @@ impl ScrapeConfigQuery {
         } else {
             self.query_timeout
         };
+        self.backoff_interval = if self.backoff_interval == Duration::default() {
+            defaults.backoff_interval
+        } else {
+            self.backoff_interval
+        };
+        self.max_backoff_interval = if self.max_backoff_interval == Duration::default() {
+            defaults.max_backoff_interval
+        } else {
+            self.max_backoff_interval
+        };
         self.metric_expiration_time = if self.metric_expiration_time == Duration::default() {
             defaults.metric_expiration_time
         } else {
@@ -427,6 +1684,14 @@ impl ScrapeConfigQuery {
             None => defaults.metric_prefix.clone(),
             _ => self.metric_prefix.clone(),
         };
+        self.mask_labels_key.clone_from(&defaults.mask_labels_key);
+
+        if !defaults.const_labels.is_empty() {
+            self.const_labels = Some(merge_const_labels(
+                &defaults.const_labels,
+                &self.const_labels.take().unwrap_or_default(),
+            ));
+        }
 
         if let Some(prefix) = &self.metric_prefix {
             self.metric_name = format!("{}_{}", prefix, self.metric_name);
@@ -443,6 +1708,12 @@ impl Default for ScrapeConfigValues {
         Self::ValueFrom(FieldWithType {
             field: None,
             field_type: FieldType::Int,
+            aggregate: None,
+            shadow_query: None,
+            assertion: None,
+            scale: ValueTransform::default_scale(),
+            offset: 0.0,
+            resolution: TimestampResolution::default(),
         })
     }
 }
@@ -470,3 +1741,1191 @@ fn apply_envs_to_string(text: &str) -> Result<String, PsqlExporterError> {
 
     Ok(result)
 }
+
+// Most of the tests need a single local database only, so they differ in queries
+#[cfg(test)]
+impl ScrapeConfig {
+    pub fn with_queries(queries: &str) -> Result<ScrapeConfig, PsqlExporterError> {
+        ScrapeConfig::from_yaml(&format!(
+            r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: postgres
+        queries:
+{queries}"#
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_from_file() {
+        let path = std::env::temp_dir().join(format!(
+            "psql-query-exporter-password-{}",
+            std::process::id()
+        ));
+        std::fs::write(&path, "secret-from-file\n").unwrap();
+        let config = |password: &str, password_file: &str| {
+            ScrapeConfig::from_yaml(&format!(
+                r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    {password}
+    password_file: {password_file}
+    databases:
+      - dbname: postgres
+        queries:
+          - metric_name: test
+            query: select 1
+"#
+            ))
+        };
+
+        let loaded = config("", path.to_str().unwrap()).unwrap();
+        let database = &loaded.sources["postgres"].databases[0];
+        assert_eq!(database.connection_string.password, "secret-from-file");
+
+        let err = config("password: inline", path.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("mutually exclusive"), "{err}");
+
+        std::fs::remove_file(&path).unwrap();
+        let err = config("", path.to_str().unwrap()).unwrap_err();
+        assert!(
+            matches!(err, PsqlExporterError::LoadPasswordFile { ref filename, .. } if filename == path.to_str().unwrap()),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn config_parse_duration_is_exposed() {
+        let path = std::env::temp_dir().join(format!(
+            "psql-query-exporter-config-{}.yaml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: postgres
+        queries:
+          - metric_name: test
+            query: select 1
+"#,
+        )
+        .unwrap();
+
+        internal_metrics::CONFIG_PARSE_DURATION.set(0.0);
+        ScrapeConfig::from(&path.to_str().unwrap().to_string()).unwrap();
+        let duration = internal_metrics::CONFIG_PARSE_DURATION.get();
+        assert!(duration > 0.0 && duration < 5.0, "{duration}");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn inline_pem_options() {
+        let config = |source_options: &str| {
+            ScrapeConfig::from_yaml(&format!(
+                r#"
+defaults:
+  sslrootcert_pem: |
+    -----BEGIN CERTIFICATE-----
+    MIIB
+    -----END CERTIFICATE-----
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    {source_options}
+    databases:
+      - dbname: postgres
+        queries:
+          - metric_name: test
+            query: select 1
+"#
+            ))
+        };
+
+        let loaded = config("sslmode: require").unwrap();
+        let database = &loaded.sources["postgres"].databases[0];
+        assert_eq!(
+            database.sslrootcert,
+            Some(PemSource::Inline(String::from(
+                "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n"
+            )))
+        );
+
+        let loaded = config("sslrootcert: /etc/ssl/source-ca.pem").unwrap();
+        let database = &loaded.sources["postgres"].databases[0];
+        assert_eq!(
+            database.sslrootcert,
+            Some(PemSource::File(String::from("/etc/ssl/source-ca.pem")))
+        );
+
+        let err = config("sslkey: /etc/ssl/client.key\n    sslkey_pem: inline").unwrap_err();
+        assert!(err.to_string().contains("mutually exclusive"), "{err}");
+    }
+
+    #[test]
+    fn resolved_query_definitions() {
+        let config = ScrapeConfig::from_yaml(
+            r#"
+defaults:
+  scrape_interval: 10m
+sources:
+  postgres:
+    host: db1.example.com
+    user: exporter
+    password: secret-password
+    scrape_interval: 5m
+    databases:
+      - dbname: app
+        query_timeout: 30s
+        queries:
+          - metric_name: inherited
+            query: select 1
+          - metric_name: own
+            query: select 1
+            scrape_interval: 15s
+            priority: 10
+"#,
+        )
+        .unwrap();
+
+        let definitions = config.query_definitions();
+        assert_eq!(definitions.len(), 2);
+        assert_eq!(
+            definitions[0].connection,
+            "exporter@db1.example.com:5432/app"
+        );
+        assert_eq!(definitions[0].scrape_interval, Duration::from_secs(300));
+        assert_eq!(definitions[0].query_timeout, Duration::from_secs(30));
+        assert_eq!(definitions[1].scrape_interval, Duration::from_secs(15));
+        assert_eq!(definitions[1].priority, 10);
+
+        let json = serde_json::to_string(&definitions).unwrap();
+        assert!(json.contains(r#""scrape_interval":"5m""#), "{json}");
+        assert!(!json.contains("secret-password"));
+    }
+
+    #[test]
+    fn scrape_jitter_inheritance() {
+        let config = ScrapeConfig::from_yaml(
+            r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    scrape_jitter: 10s
+    databases:
+      - dbname: postgres
+        queries:
+          - metric_name: inherited
+            query: select 1
+      - dbname: own
+        scrape_jitter: 3s
+        queries:
+          - metric_name: overridden
+            query: select 1
+"#,
+        )
+        .unwrap();
+        let databases = &config.sources["postgres"].databases;
+        assert_eq!(databases[0].scrape_jitter, Duration::from_secs(10));
+        assert_eq!(databases[1].scrape_jitter, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn database_pool_size() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: serial
+            query: select 1
+      - dbname: busy
+        pool_size: 4
+        queries:
+          - metric_name: concurrent
+            query: select 1
+"#,
+        )
+        .unwrap();
+        let databases = &config.sources["postgres"].databases;
+        assert_eq!(databases[0].pool_size.get(), 1);
+        assert_eq!(databases[1].pool_size.get(), 4);
+
+        let config = ScrapeConfig::from_yaml(
+            r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: postgres
+        pool_size: 0
+        queries:
+          - metric_name: none
+            query: select 1
+"#,
+        );
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn const_labels_precedence() {
+        let config = ScrapeConfig::from_yaml(
+            r#"
+defaults:
+  const_labels:
+    env: prod
+    team: default
+    cluster: default
+    region: default
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    const_labels:
+      team: dba
+      cluster: source
+      region: source
+    databases:
+      - dbname: postgres
+        const_labels:
+          cluster: main
+          region: database
+        queries:
+          - metric_name: labeled
+            query: select 1
+            const_labels:
+              region: eu
+          - metric_name: inherited
+            query: select 1
+"#,
+        )
+        .unwrap();
+        let queries = &config.sources["postgres"].databases[0].queries;
+        let labels = |pairs: &[(&str, &str)]| {
+            Some(
+                pairs
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect::<HashMap<_, _>>(),
+            )
+        };
+
+        assert_eq!(
+            queries[0].const_labels,
+            labels(&[
+                ("env", "prod"),
+                ("team", "dba"),
+                ("cluster", "main"),
+                ("region", "eu")
+            ])
+        );
+        assert_eq!(
+            queries[1].const_labels,
+            labels(&[
+                ("env", "prod"),
+                ("team", "dba"),
+                ("cluster", "main"),
+                ("region", "database")
+            ])
+        );
+    }
+
+    #[test]
+    fn replication_slot_lag_preset() {
+        let config = ScrapeConfig::from_yaml(
+            r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: postgres
+        presets:
+          - replication_slot_lag
+"#,
+        )
+        .unwrap();
+
+        let queries = &config.sources["postgres"].databases[0].queries;
+        assert_eq!(queries.len(), 1);
+        assert_eq!(queries[0].metric_name, "pg_replication_slot_lag_bytes");
+        assert_eq!(queries[0].var_labels, Some(vec![String::from("slot_name")]));
+        let ScrapeConfigValues::LsnLag(fields) = &queries[0].values else {
+            panic!("preset should use lsn_lag values");
+        };
+        assert_eq!(fields.field, Field::Name(String::from("slot_lsn")));
+        assert_eq!(fields.base_field, Field::Name(String::from("current_lsn")));
+    }
+
+    #[test]
+    fn pg_stat_statements_top_preset() {
+        let config = ScrapeConfig::from_yaml(
+            r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: postgres
+        presets:
+          - pg_stat_statements_top
+"#,
+        )
+        .unwrap();
+
+        let queries = &config.sources["postgres"].databases[0].queries;
+        assert_eq!(queries.len(), 1);
+        assert_eq!(queries[0].metric_name, "pg_stat_statements_top");
+        assert_eq!(queries[0].var_labels, Some(vec![String::from("queryid")]));
+        assert_eq!(queries[0].max_rows, NonZeroUsize::new(20));
+        assert!(queries[0].prune_missing_labels);
+    }
+
+    #[test]
+    fn database_without_queries() {
+        let err = ScrapeConfig::from_yaml(
+            r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: empty
+"#,
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err, PsqlExporterError::InvalidConfigValue { ref field, ref value, .. } if field == "postgres.databases.queries" && value == "empty"),
+            "{err}"
+        );
+
+        let err = ScrapeConfig::from_yaml(
+            r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: empty
+        queries: []
+        presets: []
+"#,
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("at least one query or preset"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn presets_of_several_databases() {
+        let config = ScrapeConfig::from_yaml(
+            r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: app
+        presets: [pg_stat_database, replication_slot_lag, pg_stat_statements_top]
+      - dbname: billing
+        presets: [pg_stat_database, replication_slot_lag, pg_stat_statements_top]
+"#,
+        )
+        .unwrap();
+
+        for database in config.sources["postgres"].databases.iter() {
+            for query in database.queries.iter() {
+                assert_eq!(
+                    query.const_labels.as_ref().unwrap()["database"],
+                    database.dbname
+                );
+            }
+        }
+
+        let load = |billing_labels: &str| {
+            ScrapeConfig::from_yaml(&format!(
+                r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: app
+        queries:
+          - metric_name: users
+            query: select count(*) from users
+      - dbname: billing
+        queries:
+          - metric_name: users
+            query: select count(*) from users
+            const_labels: {billing_labels}
+"#
+            ))
+        };
+        let err = load("{}").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("invalid value 'users' of 'postgres.databases.queries.metric_name'"),
+            "{err}"
+        );
+        assert!(load("{ database: billing }").is_ok());
+    }
+
+    #[test]
+    fn pg_stat_database_preset_metric_names() {
+        let config = ScrapeConfig::from_yaml(
+            r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    metric_prefix: postgres
+    databases:
+      - dbname: postgres
+        presets:
+          - pg_stat_database
+"#,
+        )
+        .unwrap();
+
+        let queries = &config.sources["postgres"].databases[0].queries;
+        assert_eq!(queries.len(), 1);
+        assert_eq!(queries[0].metric_name, "postgres_pg_stat_database");
+        assert_eq!(queries[0].var_labels, Some(vec![String::from("datname")]));
+
+        let ScrapeConfigValues::ValuesWithSuffixes(values) = &queries[0].values else {
+            panic!("preset should use multi_suffixes values");
+        };
+        let metric_names: Vec<String> = values
+            .iter()
+            .map(|v| format!("{}_{}", queries[0].metric_name, v.suffix))
+            .collect();
+        assert_eq!(
+            metric_names,
+            vec![
+                "postgres_pg_stat_database_numbackends",
+                "postgres_pg_stat_database_xact_commit",
+                "postgres_pg_stat_database_xact_rollback",
+                "postgres_pg_stat_database_deadlocks",
+                "postgres_pg_stat_database_cache_hit_ratio",
+            ]
+        );
+    }
+
+    #[test]
+    fn source_enabled_by_env_var() {
+        let config = r#"
+sources:
+  postgres:
+    enabled: ${TEST_SOURCE_ENABLED_BY_ENV_VAR}
+    host: localhost
+    user: postgres
+    password: postgres
+    databases: []
+  always:
+    host: localhost
+    user: postgres
+    password: postgres
+    databases: []
+"#;
+
+        env::set_var("TEST_SOURCE_ENABLED_BY_ENV_VAR", "false");
+        let scrape_config = ScrapeConfig::from_yaml(config).unwrap();
+        assert!(!scrape_config.sources["postgres"].is_enabled());
+        assert!(scrape_config.sources["always"].is_enabled());
+
+        env::set_var("TEST_SOURCE_ENABLED_BY_ENV_VAR", "true");
+        let scrape_config = ScrapeConfig::from_yaml(config).unwrap();
+        assert!(scrape_config.sources["postgres"].is_enabled());
+
+        env::set_var("TEST_SOURCE_ENABLED_BY_ENV_VAR", "maybe");
+        assert!(matches!(
+            ScrapeConfig::from_yaml(config),
+            Err(PsqlExporterError::ParseBooleanValue { .. })
+        ));
+    }
+
+    #[test]
+    fn disabled_source_isnt_resolved() {
+        let config = |enabled: bool| {
+            ScrapeConfig::from_yaml(&format!(
+                r#"
+sources:
+  prod:
+    enabled: {enabled}
+    host: ${{TEST_DISABLED_SOURCE_UNSET_HOST}}
+    user: postgres
+    password_file: /nonexistent/prod-password
+    databases:
+      - dbname: postgres
+        queries: []
+"#
+            ))
+        };
+
+        let scrape_config = config(false).unwrap();
+        assert!(!scrape_config.sources["prod"].is_enabled());
+        assert!(matches!(
+            config(true),
+            Err(PsqlExporterError::EnvironmentVariableSubstitution { variable, .. })
+                if variable == "TEST_DISABLED_SOURCE_UNSET_HOST"
+        ));
+    }
+
+    #[test]
+    fn nan_policies() {
+        assert!(NanPolicy::Keep.apply(f64::NAN).unwrap().is_nan());
+        assert_eq!(NanPolicy::Zero.apply(f64::NAN), Some(0.0));
+        assert_eq!(NanPolicy::Skip.apply(f64::NAN), None);
+
+        assert_eq!(NanPolicy::Keep.apply(f64::INFINITY), Some(f64::INFINITY));
+        assert_eq!(NanPolicy::Zero.apply(f64::NEG_INFINITY), Some(0.0));
+        assert_eq!(NanPolicy::Skip.apply(f64::INFINITY), None);
+
+        for policy in [NanPolicy::Keep, NanPolicy::Zero, NanPolicy::Skip] {
+            assert_eq!(policy.apply(1.5), Some(1.5));
+        }
+    }
+
+    #[test]
+    fn positional_fields() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: single
+            query: select 1, 2
+            values:
+              single:
+                field: 1
+          - metric_name: multi
+            query: select 1 as value, 2, 3
+            values:
+              multi_suffixes:
+                - field: value
+                  suffix: named
+                - field: "1"
+                  suffix: second
+                - field: 2
+                  suffix: third
+"#,
+        )
+        .unwrap();
+
+        let queries = &config.sources["postgres"].databases[0].queries;
+        let ScrapeConfigValues::ValueFrom(value) = &queries[0].values else {
+            panic!("single value expected");
+        };
+        assert_eq!(value.field, Some(Field::Index(1)));
+
+        let ScrapeConfigValues::ValuesWithSuffixes(values) = &queries[1].values else {
+            panic!("multi_suffixes values expected");
+        };
+        let fields: Vec<&Field> = values.iter().map(|v| &v.field).collect();
+        assert_eq!(
+            fields,
+            vec![
+                &Field::Name(String::from("value")),
+                &Field::Index(1),
+                &Field::Index(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn label_remap_to_canonical_value() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: connections
+            query: select env, count(*) as value from connections group by env
+            var_labels: [env]
+            label_remap:
+              env:
+                production: prod
+                prd: prod
+            values:
+              single:
+                field: value
+"#,
+        )
+        .unwrap();
+
+        let query = &config.sources["postgres"].databases[0].queries[0];
+        assert_eq!(query.label_value("env", String::from("production")), "prod");
+        assert_eq!(query.label_value("env", String::from("prd")), "prod");
+        assert_eq!(query.label_value("env", String::from("prod")), "prod");
+        assert_eq!(query.label_value("env", String::from("dev")), "dev");
+        assert_eq!(query.label_value("other", String::from("prd")), "prd");
+    }
+
+    #[test]
+    fn masked_label_is_consistently_hashed() {
+        let config = |key: &str| {
+            ScrapeConfig::from_yaml(&format!(
+                r#"
+defaults:
+  {key}
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: postgres
+        queries:
+          - metric_name: sessions
+            query: select usename, client_addr, count(*) as value from pg_stat_activity group by 1, 2
+            var_labels: [usename, client_addr]
+            mask_labels: [client_addr]
+            values:
+              single:
+                field: value
+"#
+            ))
+        };
+
+        let config_a = config("mask_labels_key: secret-a").unwrap();
+        let query = &config_a.sources["postgres"].databases[0].queries[0];
+        let masked = query.label_value("client_addr", String::from("10.1.2.3"));
+        assert_eq!(masked.len(), MASKED_LABEL_DIGEST_BYTES * 2);
+        assert_ne!(masked, "10.1.2.3");
+        assert_eq!(
+            masked,
+            query.label_value("client_addr", String::from("10.1.2.3"))
+        );
+        assert_ne!(
+            masked,
+            query.label_value("client_addr", String::from("10.1.2.4"))
+        );
+        assert_eq!(query.label_value("usename", String::from("app")), "app");
+
+        // the hash depends on the key, so it can't be reversed without it
+        let config_b = config("mask_labels_key: secret-b").unwrap();
+        let query = &config_b.sources["postgres"].databases[0].queries[0];
+        assert_ne!(
+            masked,
+            query.label_value("client_addr", String::from("10.1.2.3"))
+        );
+
+        for key in ["scrape_interval: 30m", "mask_labels_key: \"\""] {
+            assert_eq!(
+                config(key).unwrap_err().to_string(),
+                "invalid value 'client_addr' of 'sessions.mask_labels': mask_labels_key should be set in defaults section"
+            );
+        }
+    }
+
+    #[test]
+    fn database_port_override() {
+        let config = ScrapeConfig::from_yaml(
+            r#"
+sources:
+  postgres:
+    host: localhost
+    port: 6432
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: app
+        queries:
+          - metric_name: app_up
+            query: select 1
+      - dbname: billing
+        port: 6433
+        queries:
+          - metric_name: billing_up
+            query: select 1
+"#,
+        )
+        .unwrap();
+
+        let databases = &config.sources["postgres"].databases;
+        assert_eq!(databases[0].connection_string.port, 6432);
+        assert_eq!(databases[1].connection_string.port, 6433);
+    }
+
+    #[test]
+    fn query_backoff_override() {
+        let config = ScrapeConfig::from_yaml(
+            r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    backoff_interval: 10s
+    max_backoff_interval: 60s
+    databases:
+      - dbname: postgres
+        queries:
+          - metric_name: steady
+            query: select 1
+          - metric_name: flaky
+            query: select 1
+            backoff_interval: 1s
+            max_backoff_interval: 3s
+"#,
+        )
+        .unwrap();
+
+        let queries = &config.sources["postgres"].databases[0].queries;
+        assert_eq!(queries[0].backoff_interval, Duration::from_secs(10));
+        assert_eq!(queries[0].max_backoff_interval, Duration::from_secs(60));
+        assert_eq!(queries[1].backoff_interval, Duration::from_secs(1));
+        assert_eq!(queries[1].max_backoff_interval, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn skip_null_option() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: partition_size
+            query: select relname, pg_relation_size(relid) from pg_partitions
+            var_labels: [relname]
+          - metric_name: partition_size_skip_null
+            query: select relname, pg_relation_size(relid) from pg_partitions
+            var_labels: [relname]
+            skip_null: yes
+"#,
+        )
+        .unwrap();
+        let queries = &config.sources["postgres"].databases[0].queries;
+
+        assert!(!queries[0].skip_null);
+        assert!(queries[1].skip_null);
+    }
+
+    #[test]
+    fn null_value_type_validation() {
+        let config = |field_type: &str, null_value: &str| {
+            ScrapeConfig::with_queries(&format!(
+                r#"
+          - metric_name: replication_lag
+            query: select extract(epoch from now() - pg_last_xact_replay_timestamp())
+            values:
+              single:
+                type: {field_type}
+            null_value: {null_value}
+"#
+            ))
+        };
+
+        let query = &config("float", "0").unwrap().sources["postgres"].databases[0].queries[0];
+        assert_eq!(query.null_value, Some(NullValue::Int(0)));
+        let query = &config("float", "-1.5").unwrap().sources["postgres"].databases[0].queries[0];
+        assert_eq!(query.null_value, Some(NullValue::Float(-1.5)));
+        assert!(config("int", "0").is_ok());
+
+        let err = config("int", "0.5").unwrap_err();
+        assert!(matches!(err, PsqlExporterError::InvalidConfigValue { .. }));
+        assert!(err.to_string().contains("replication_lag.null_value"));
+    }
+
+    #[test]
+    fn tag_names_validation() {
+        let config = |tag: &str| {
+            ScrapeConfig::with_queries(&format!(
+                r#"
+          - metric_name: invoices
+            query: select count(*) from invoices
+            tags:
+              {tag}: billing
+"#
+            ))
+        };
+
+        assert!(config("team_name").is_ok());
+        for (tag, reason) in [
+            ("team-name", "tag should be a valid label name"),
+            ("1team", "tag should be a valid label name"),
+            ("metric", "tag name is reserved for built-in label"),
+            ("database", "tag name is reserved for built-in label"),
+        ] {
+            let err = config(tag).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("invalid value '{tag}' of 'invoices.tags': {reason}")
+            );
+        }
+    }
+
+    #[test]
+    fn reserved_tenant_name() {
+        let err = ScrapeConfig::from_yaml(
+            r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    tenant: list
+    databases:
+      - dbname: postgres
+        queries:
+          - metric_name: invoices
+            query: select count(*) from invoices
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid value 'list' of 'postgres.tenant': tenant name is reserved by other endpoint"
+        );
+    }
+
+    #[test]
+    fn tenant_label_of_tenant_source() {
+        let config = |tenant: &str, labels: &str| {
+            ScrapeConfig::from_yaml(&format!(
+                r#"
+sources:
+  postgres:
+    host: localhost
+    user: postgres
+    password: postgres
+    {tenant}
+    databases:
+      - dbname: postgres
+        queries:
+          - metric_name: invoices
+            query: select count(*) from invoices
+            {labels}
+"#
+            ))
+        };
+
+        // tenant label is allowed in sources without tenant
+        assert!(config("", "const_labels: {tenant: billing}").is_ok());
+        assert!(config("tenant: billing", "const_labels: {team: billing}").is_ok());
+        for (labels, field) in [
+            ("const_labels: {tenant: billing}", "const_labels"),
+            ("var_labels: [tenant]", "var_labels"),
+        ] {
+            let err = config("tenant: billing", labels).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("invalid value 'tenant' of 'invoices.{field}': label is reserved for the tenant name of tenant sources")
+            );
+        }
+    }
+
+    #[test]
+    fn aggregate_without_var_labels_only() {
+        let config = |var_labels: &str| {
+            ScrapeConfig::with_queries(&format!(
+                r#"
+          - metric_name: backends
+            query: select datname, numbackends from pg_stat_database
+            {var_labels}
+            values:
+              single:
+                field: numbackends
+                aggregate: sum
+"#
+            ))
+        };
+
+        assert!(config("").is_ok());
+        let err = config("var_labels: [datname]").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid value 'sum' of 'backends.values.single.aggregate': applicable to metrics without var_labels only"
+        );
+    }
+
+    #[test]
+    fn strict_check_of_missing_certificate() {
+        let config = |enabled: bool| {
+            ScrapeConfig::from_yaml(&format!(
+                r#"
+sources:
+  postgres:
+    enabled: {enabled}
+    host: localhost
+    user: postgres
+    password: postgres
+    sslmode: verify-full
+    sslrootcert: /nonexistent/psql-query-exporter/ca.pem
+    databases:
+      - dbname: postgres
+        queries:
+          - metric_name: up
+            query: select 1
+"#
+            ))
+            .unwrap()
+        };
+
+        // nothing is loaded without connection attempt
+        let config_enabled = config(true);
+        assert!(matches!(
+            config_enabled.check_certificates(),
+            Err(PsqlExporterError::PostgresTlsRootCertificate { rootcert, .. })
+                if rootcert == "/nonexistent/psql-query-exporter/ca.pem"
+        ));
+
+        assert!(config(false).check_certificates().is_ok());
+        assert!(ScrapeConfig::from_yaml("sources: {}")
+            .unwrap()
+            .check_certificates()
+            .is_ok());
+    }
+
+    #[test]
+    fn histogram_buckets_validation() {
+        let config = |buckets: &str| {
+            ScrapeConfig::with_queries(&format!(
+                r#"
+          - metric_name: query_duration_seconds
+            query: select duration from recent_queries
+            values:
+              histogram:
+                field: duration
+                buckets: {buckets}
+"#
+            ))
+        };
+
+        let config_ok = config("[0.1, 0.5, 1, 5]").unwrap();
+        let query = &config_ok.sources["postgres"].databases[0].queries[0];
+        let ScrapeConfigValues::Histogram(fields) = &query.values else {
+            panic!("histogram values expected");
+        };
+        assert_eq!(fields.buckets, vec![0.1, 0.5, 1.0, 5.0]);
+
+        assert_eq!(
+            config("[]").unwrap_err().to_string(),
+            "invalid value '[]' of 'query_duration_seconds.values.histogram.buckets': \
+             at least one bucket is required"
+        );
+        assert_eq!(
+            config("[0.1, 1, 1, 5]").unwrap_err().to_string(),
+            "invalid value '[0.1, 1.0, 1.0, 5.0]' of 'query_duration_seconds.values.histogram.buckets': \
+             buckets must be strictly increasing"
+        );
+        assert!(matches!(
+            config("[5, 1]"),
+            Err(PsqlExporterError::InvalidConfigValue { .. })
+        ));
+    }
+
+    #[test]
+    fn misspelled_values_variant() {
+        let config = |values: &str| {
+            ScrapeConfig::with_queries(&format!(
+                r#"
+          - metric_name: misspelled
+            query: select 1, 2
+            values:
+{values}
+"#
+            ))
+        };
+
+        let error = config(
+            r#"
+              multi_lables:
+                - field: 1
+                  labels:
+                    kind: read"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unable to parse config at 'sources.postgres.databases.0.queries.0.values.multi_lables': \
+             unknown variant: found `multi_lables`, \
+             expected `one of `single`, `multi_labels`, `multi_suffixes`, `summary`, `key_value`, `info`, `histogram`, `state_set`, `lsn_lag``, \
+             did you mean `multi_labels`?"
+        );
+
+        let error = config(
+            r#"
+              multi_labels:
+                - fiel: 1
+                  labels:
+                    kind: read"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unable to parse config at 'sources.postgres.databases.0.queries.0.values.multi_labels.0.fiel': \
+             unknown field: found `fiel`, expected `one of `field`, `type`, `labels`, `scale`, `offset`, `resolution``, \
+             did you mean `field`?"
+        );
+
+        let error = config(
+            r#"
+              single:
+                field: 1
+                agregate: sum
+                completely_unknown: 1"#,
+        )
+        .unwrap_err();
+        assert!(error.to_string().ends_with(", did you mean `aggregate`?"));
+    }
+
+    #[test]
+    fn boolean_representations() {
+        for value in ["true", "TRUE", "1", "yes", "Yes", "on", " on "] {
+            assert_eq!(parse_bool(value), Some(true), "{value}");
+        }
+        for value in ["false", "False", "0", "no", "NO", "off"] {
+            assert_eq!(parse_bool(value), Some(false), "{value}");
+        }
+        for value in ["", "2", "enabled", "y"] {
+            assert_eq!(parse_bool(value), None, "{value}");
+        }
+
+        for (value, expected) in [
+            ("true", true),
+            ("1", true),
+            ("yes", true),
+            ("on", true),
+            ("false", false),
+            ("0", false),
+            ("no", false),
+            ("off", false),
+        ] {
+            let config = ScrapeConfig::from_yaml(&format!(
+                r#"
+sources:
+  postgres:
+    enabled: {value}
+    connection_info: {value}
+    host: localhost
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: postgres
+        queries:
+          - metric_name: test
+            query: select 1
+            align_to_clock: {value}
+"#
+            ))
+            .unwrap();
+
+            let source = &config.sources["postgres"];
+            assert_eq!(source.is_enabled(), expected, "{value}");
+            assert_eq!(source.databases[0].connection_info, expected, "{value}");
+            assert_eq!(
+                source.databases[0].queries[0].align_to_clock, expected,
+                "{value}"
+            );
+        }
+
+        assert!(ScrapeConfig::with_queries(
+            r#"
+          - metric_name: test
+            query: select 1
+            align_to_clock: maybe
+"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn scale_bytes_to_megabytes() {
+        let config = ScrapeConfig::with_queries(
+            r#"
+          - metric_name: database_size_megabytes
+            query: select pg_database_size(current_database())::float8 as bytes
+            values:
+              single:
+                field: bytes
+                type: float
+                scale: 0.000001
+          - metric_name: plain
+            query: select 1.5::float8
+            values:
+              single:
+                type: float
+"#,
+        )
+        .unwrap();
+
+        let queries = &config.sources["postgres"].databases[0].queries;
+        let ScrapeConfigValues::ValueFrom(value) = &queries[0].values else {
+            panic!("single value expected");
+        };
+        let transform = ValueTransform::new(value.scale, value.offset);
+        assert!((transform.apply(52_428_800.0) - 52.4288).abs() < 1e-9);
+
+        let ScrapeConfigValues::ValueFrom(value) = &queries[1].values else {
+            panic!("single value expected");
+        };
+        let transform = ValueTransform::new(value.scale, value.offset);
+        assert_eq!(transform.apply(1.5), 1.5);
+        assert_eq!(ValueTransform::new(10.0, -273.15).apply(300.0), 2726.85);
+    }
+
+    #[test]
+    fn transform_of_int_field_is_rejected() {
+        let config = |values: &str| {
+            ScrapeConfig::with_queries(&format!(
+                r#"
+          - metric_name: sizes
+            query: select 1 as a, 2 as b
+            values:
+              {values}
+"#
+            ))
+        };
+
+        assert!(config("single: {field: a, type: float, scale: 0.5}").is_ok());
+        assert!(config("single: {field: a, type: int, scale: 1.0, offset: 0}").is_ok());
+        for (values, field, value) in [
+            ("single: {field: a, scale: 0.5}", "single.scale", "0.5"),
+            (
+                "multi_labels: [{field: a, type: float, scale: 2, labels: {x: a}}, {field: b, offset: 1, labels: {x: b}}]",
+                "multi_labels.offset",
+                "1",
+            ),
+            (
+                "multi_suffixes: [{field: a, suffix: a, type: int, scale: 1000}]",
+                "multi_suffixes.scale",
+                "1000",
+            ),
+        ] {
+            assert_eq!(
+                config(values).unwrap_err().to_string(),
+                format!("invalid value '{value}' of 'sizes.values.{field}': applicable to float fields only")
+            );
+        }
+
+        assert!(config("single: {field: a, type: timestamp, resolution: millis}").is_ok());
+        assert_eq!(
+            config("multi_labels: [{field: a, type: float, resolution: millis, labels: {x: a}}]")
+                .unwrap_err()
+                .to_string(),
+            "invalid value 'millis' of 'sizes.values.multi_labels.resolution': applicable to timestamp fields only"
+        );
+    }
+}
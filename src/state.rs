@@ -0,0 +1,157 @@
+use crate::errors::PsqlExporterError;
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::Path,
+    sync::OnceLock,
+    time::{Duration, SystemTime},
+};
+use tracing::{debug, info, warn};
+
+static RESTORED_STATE: OnceLock<State> = OnceLock::new();
+
+// Unknown fields are ignored and missing ones are defaulted, so files of other versions can be read
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct State {
+    #[serde(default)]
+    saved_at: f64,
+    #[serde(default)]
+    series: Vec<SavedSeries>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SavedSeries {
+    pub name: String,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    pub value: f64,
+}
+
+impl State {
+    pub fn new(series: Vec<SavedSeries>) -> Self {
+        Self {
+            saved_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            series,
+        }
+    }
+
+    pub fn saved_at(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs_f64(self.saved_at.max(0.0))
+    }
+
+    pub fn series<'a>(
+        &'a self,
+        name: &'a str,
+        tenant: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a SavedSeries> {
+        self.series
+            .iter()
+            .filter(move |s| s.name == name && s.tenant.as_deref() == tenant)
+    }
+
+    pub fn load(filename: &Path, max_age: Duration) -> Result<Option<Self>, PsqlExporterError> {
+        let map_err = |e: io::Error| PsqlExporterError::StateFile {
+            filename: filename.display().to_string(),
+            cause: e,
+        };
+
+        let content = match fs::read_to_string(filename) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(map_err(e)),
+        };
+        let state: Self = serde_json::from_str(&content).map_err(|e| map_err(e.into()))?;
+
+        let age = SystemTime::now()
+            .duration_since(state.saved_at())
+            .unwrap_or_default();
+        if age > max_age {
+            debug!("state file is too old: {age:?}");
+            return Ok(None);
+        }
+
+        Ok(Some(state))
+    }
+
+    // Written to the temporary file first, so a crash during write doesn't break the previous state
+    pub fn save(&self, filename: &Path) -> Result<(), PsqlExporterError> {
+        let map_err = |e: io::Error| PsqlExporterError::StateFile {
+            filename: filename.display().to_string(),
+            cause: e,
+        };
+
+        let mut temp_filename = filename.to_path_buf().into_os_string();
+        temp_filename.push(".tmp");
+        let content = serde_json::to_string(self).map_err(|e| map_err(e.into()))?;
+        fs::write(&temp_filename, content).map_err(map_err)?;
+        fs::rename(&temp_filename, filename).map_err(map_err)
+    }
+}
+
+pub fn init(filename: &str, max_age: Duration) {
+    match State::load(Path::new(filename), max_age) {
+        Ok(Some(state)) => {
+            info!(
+                "{} series have been restored from state file {filename}",
+                state.series.len()
+            );
+            RESTORED_STATE
+                .set(state)
+                .unwrap_or_else(|_| panic!("looks like a BUG: state is already initialized"));
+        }
+        Ok(None) => info!("no recent state in file {filename}, starting from scratch"),
+        Err(e) => warn!("{e}, starting from scratch"),
+    }
+}
+
+pub fn restored() -> Option<&'static State> {
+    RESTORED_STATE.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn old_or_broken_state_is_ignored() {
+        let path = std::env::temp_dir().join(format!(
+            "psql-query-exporter-state-{}.json",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        assert!(State::load(&path, Duration::from_secs(60))
+            .unwrap()
+            .is_none());
+
+        fs::write(&path, r#"{"saved_at": 1.0, "series": []}"#).unwrap();
+        assert!(State::load(&path, Duration::from_secs(60))
+            .unwrap()
+            .is_none());
+
+        fs::write(&path, "not a json").unwrap();
+        assert!(State::load(&path, Duration::from_secs(60)).is_err());
+
+        // fields of other versions don't matter
+        let saved_at = State::new(vec![]).saved_at;
+        fs::write(
+            &path,
+            format!(
+                r#"{{"version": 2, "saved_at": {saved_at}, "series": [{{"name": "m", "value": 1.5, "kind": "gauge"}}]}}"#
+            ),
+        )
+        .unwrap();
+        let state = State::load(&path, Duration::from_secs(60))
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.series("m", None).next().unwrap().value, 1.5);
+
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,159 @@
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{LabelPair, Metric, MetricFamily, MetricType, Quantile, Summary},
+    Opts,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SummaryValue {
+    pub quantiles: Vec<(f64, f64)>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct PrecomputedSummary {
+    desc: Desc,
+    values: Arc<Mutex<HashMap<Vec<String>, SummaryValue>>>,
+}
+
+impl PrecomputedSummary {
+    pub fn new(opts: Opts, label_names: &[&str]) -> Result<Self, prometheus::Error> {
+        let desc = Desc::new(
+            opts.fq_name(),
+            opts.help.clone(),
+            label_names.iter().map(|l| l.to_string()).collect(),
+            opts.const_labels.clone(),
+        )?;
+
+        Ok(Self {
+            desc,
+            values: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn set(&self, label_values: Vec<String>, value: SummaryValue) {
+        self.values
+            .lock()
+            .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+            .insert(label_values, value);
+    }
+
+    pub fn remove_label_values(&self, label_values: &[&str]) -> Result<(), prometheus::Error> {
+        let label_values: Vec<String> = label_values.iter().map(|v| v.to_string()).collect();
+        match self
+            .values
+            .lock()
+            .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+            .remove(&label_values)
+        {
+            Some(_) => Ok(()),
+            None => Err(prometheus::Error::Msg(format!(
+                "missing label values {label_values:?}"
+            ))),
+        }
+    }
+
+    pub fn reset(&self) {
+        self.values
+            .lock()
+            .unwrap_or_else(|e| panic!("looks like a BUG: {e}"))
+            .clear();
+    }
+
+    fn make_metric(&self, label_values: &[String], value: &SummaryValue) -> Metric {
+        let mut labels: Vec<LabelPair> = self.desc.const_label_pairs.clone();
+        for (name, value) in self.desc.variable_labels.iter().zip(label_values) {
+            let mut label = LabelPair::new();
+            label.set_name(name.clone());
+            label.set_value(value.clone());
+            labels.push(label);
+        }
+        labels.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+
+        let mut summary = Summary::new();
+        summary.set_sample_sum(value.sum);
+        summary.set_sample_count(value.count);
+        for (q, v) in value.quantiles.iter() {
+            let mut quantile = Quantile::new();
+            quantile.set_quantile(*q);
+            quantile.set_value(*v);
+            summary.mut_quantile().push(quantile);
+        }
+
+        let mut metric = Metric::new();
+        metric.mut_label().extend(labels);
+        metric.set_summary(summary);
+        metric
+    }
+}
+
+impl Collector for PrecomputedSummary {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let values = self
+            .values
+            .lock()
+            .unwrap_or_else(|e| panic!("looks like a BUG: {e}"));
+
+        let mut family = MetricFamily::new();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::SUMMARY);
+        for (label_values, value) in values.iter() {
+            family
+                .mut_metric()
+                .push(self.make_metric(label_values, value));
+        }
+
+        vec![family]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{Encoder, Registry, TextEncoder};
+
+    #[test]
+    fn summary_with_quantiles() {
+        let registry = Registry::new();
+        let opts =
+            Opts::new("query_latency_seconds", "Query latency").const_label("source", "postgres");
+        let summary = PrecomputedSummary::new(opts, &["app"]).unwrap();
+        registry.register(Box::new(summary.clone())).unwrap();
+
+        summary.set(
+            vec![String::from("api")],
+            SummaryValue {
+                quantiles: vec![(0.5, 0.012), (0.9, 0.045), (0.99, 0.31)],
+                sum: 42.5,
+                count: 1500,
+            },
+        );
+
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&registry.gather(), &mut buffer)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            r#"# HELP query_latency_seconds Query latency
+# TYPE query_latency_seconds summary
+query_latency_seconds{app="api",source="postgres",quantile="0.5"} 0.012
+query_latency_seconds{app="api",source="postgres",quantile="0.9"} 0.045
+query_latency_seconds{app="api",source="postgres",quantile="0.99"} 0.31
+query_latency_seconds_sum{app="api",source="postgres"} 42.5
+query_latency_seconds_count{app="api",source="postgres"} 1500
+"#
+        );
+    }
+}
@@ -1,70 +1,144 @@
+use futures_util::{stream, Stream};
 use std::{
+    env,
     error::Error,
+    future::Future,
+    io,
+    os::fd::{FromRawFd, RawFd},
+    process,
+    sync::Arc,
     time::{Duration, SystemTime},
 };
 use tokio::{
-    select,
+    net::{TcpListener, TcpStream},
+    runtime, select,
     signal::unix::{signal, Signal, SignalKind},
-    sync::watch,
+    sync::{watch, Semaphore, SemaphorePermit},
 };
-use tracing::{debug, error, info};
+use tracing::{debug, info, warn};
 
 use crate::errors::PsqlExporterError;
 
 pub type ShutdownReceiver = watch::Receiver<bool>;
 pub type ShutdownSender = watch::Sender<bool>;
+pub type ResetReceiver = watch::Receiver<()>;
+pub type ResetSender = watch::Sender<()>;
+pub type ReloadReceiver = watch::Receiver<()>;
+pub type ReloadSender = watch::Sender<()>;
 
 const MAX_LOOP_SLEEP_TIME: Duration = Duration::from_secs(5);
+// First file descriptor passed by systemd socket activation, see sd_listen_fds(3)
+const SD_LISTEN_FDS_START: RawFd = 3;
+// Pause after failed accept() to not spin while the cause (like exhausted file descriptors) persists
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+enum ShutdownTrigger {
+    Signals {
+        terminate: Signal,
+        interrupt: Signal,
+        quit: Signal,
+        hangup: Signal,
+    },
+    // embedding application manages signals itself and requests shutdown by sending true
+    External(ShutdownReceiver),
+}
 
 #[derive(Debug)]
 pub struct SignalHandler {
-    terminate: Signal,
-    interrupt: Signal,
-    quit: Signal,
-    hangup: Signal,
+    trigger: ShutdownTrigger,
 
+    draining_channel_tx: ShutdownSender,
     shutdown_channel_tx: ShutdownSender,
-    shutdown_channel_rx: ShutdownReceiver,
+    reload_channel_tx: ReloadSender,
 }
 
 impl SignalHandler {
     pub fn new() -> Result<Self, Box<dyn Error>> {
-        let (shutdown_channel_tx, shutdown_channel_rx) = watch::channel(false);
-        let receiver = Self {
+        Ok(Self::with_trigger(ShutdownTrigger::Signals {
             terminate: signal(SignalKind::terminate())?,
             interrupt: signal(SignalKind::interrupt())?,
             quit: signal(SignalKind::quit())?,
             hangup: signal(SignalKind::hangup())?,
-            shutdown_channel_tx,
-            shutdown_channel_rx,
-        };
+        }))
+    }
 
-        Ok(receiver)
+    // No signal handlers are registered, so config reload isn't available this way
+    pub fn with_external_shutdown(shutdown: ShutdownReceiver) -> Self {
+        Self::with_trigger(ShutdownTrigger::External(shutdown))
+    }
+
+    fn with_trigger(trigger: ShutdownTrigger) -> Self {
+        let (draining_channel_tx, _) = watch::channel(false);
+        let (shutdown_channel_tx, _) = watch::channel(false);
+        let (reload_channel_tx, _) = watch::channel(());
+        Self {
+            trigger,
+            draining_channel_tx,
+            shutdown_channel_tx,
+            reload_channel_tx,
+        }
     }
 
     pub fn get_rx_channel(&self) -> ShutdownReceiver {
-        self.shutdown_channel_rx.clone()
+        self.shutdown_channel_tx.subscribe()
+    }
+
+    pub fn get_draining_channel(&self) -> ShutdownReceiver {
+        self.draining_channel_tx.subscribe()
+    }
+
+    pub fn get_reload_channel(&self) -> ReloadReceiver {
+        self.reload_channel_tx.subscribe()
     }
 
     pub async fn shutdown_on_signal(&mut self) {
-        let signal = self.wait_for_signal().await;
+        match self.wait_for_signal().await {
+            Some(signal) => info!("{signal} signal has been received, shutting down"),
+            None => info!("shutdown has been requested, shutting down"),
+        }
+        self.shutdown().await;
+    }
 
-        info!("{signal} signal has been received, shutting down");
-        if let Err(e) = self.shutdown_channel_tx.send(true) {
-            error!("can't send shutdown message: {}", e);
-        };
+    // Scrapes are rejected first, so nobody gets metrics of half-stopped collectors,
+    // then collectors finish their current cycle, and web server is stopped at the end
+    pub async fn shutdown(&self) {
+        info!("shutdown: stop serving metrics");
+        self.draining_channel_tx.send_replace(true);
 
-        debug!("shutdown message has been sent, waiting until all task stopped");
+        info!("shutdown: stop collectors");
+        self.shutdown_channel_tx.send_replace(true);
+        debug!("shutdown message has been sent, waiting until all collectors stopped");
         self.shutdown_channel_tx.closed().await;
-        info!("shutdown completed");
+
+        info!("shutdown: all collectors have been stopped, stop web server");
     }
 
-    async fn wait_for_signal(&mut self) -> &str {
-        select! {
-            _ = self.terminate.recv() => "TERM",
-            _ = self.interrupt.recv() => "INT",
-            _ = self.quit.recv() => "QUIT",
-            _ = self.hangup.recv() => "HANGUP",
+    // HANGUP doesn't stop the exporter, it triggers config reload;
+    // external sender which has gone away is treated as a shutdown request too
+    async fn wait_for_signal(&mut self) -> Option<&str> {
+        let (terminate, interrupt, quit, hangup) = match &mut self.trigger {
+            ShutdownTrigger::Signals {
+                terminate,
+                interrupt,
+                quit,
+                hangup,
+            } => (terminate, interrupt, quit, hangup),
+            ShutdownTrigger::External(shutdown) => {
+                let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+                return None;
+            }
+        };
+        loop {
+            select! {
+                _ = terminate.recv() => return Some("TERM"),
+                _ = interrupt.recv() => return Some("INT"),
+                _ = quit.recv() => return Some("QUIT"),
+                _ = hangup.recv() => {
+                    info!("HANGUP signal has been received, reloading config");
+                    self.reload_channel_tx.send_replace(());
+                }
+            }
         }
     }
 }
@@ -106,11 +180,19 @@ impl SleepHelper {
         }
     }
 
+    // Closed channel means its owner has gone, like a collector stopped by config reload or shutdown
     fn is_shutdown_state(&mut self) -> bool {
-        if self.shutdown_channel.has_changed().unwrap_or(true) {
-            let msg = self.shutdown_channel.borrow_and_update();
-            if *msg {
-                debug!("is_shutdown_state: shutdown signal has been received");
+        match self.shutdown_channel.has_changed() {
+            Ok(true) => {
+                let msg = self.shutdown_channel.borrow_and_update();
+                if *msg {
+                    debug!("is_shutdown_state: shutdown signal has been received");
+                    return true;
+                }
+            }
+            Ok(false) => {}
+            Err(_) => {
+                debug!("is_shutdown_state: shutdown channel has been closed");
                 return true;
             }
         }
@@ -118,3 +200,333 @@ impl SleepHelper {
         false
     }
 }
+
+#[derive(Debug, Clone, Default)]
+pub struct QueryLimiter {
+    semaphore: Option<Arc<Semaphore>>,
+}
+
+impl QueryLimiter {
+    pub fn new(max_concurrent_queries: usize) -> Self {
+        Self {
+            semaphore: (max_concurrent_queries > 0)
+                .then(|| Arc::new(Semaphore::new(max_concurrent_queries))),
+        }
+    }
+
+    pub async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        match &self.semaphore {
+            Some(semaphore) => Some(
+                semaphore
+                    .acquire()
+                    .await
+                    .unwrap_or_else(|e| panic!("looks like a BUG: {e}")),
+            ),
+            None => None,
+        }
+    }
+}
+
+// Offsets are uniformly distributed in [-max, max], the seed is explicit to make sequences reproducible in tests
+#[derive(Debug)]
+pub struct Jitter {
+    max: Duration,
+    state: u64,
+}
+
+impl Jitter {
+    pub fn new(max: Duration, seed: u64) -> Self {
+        Self { max, state: seed }
+    }
+
+    pub fn apply(&mut self, time: SystemTime) -> SystemTime {
+        if self.max.is_zero() {
+            return time;
+        }
+
+        let shift = self.max.mul_f64(2.0 * self.next_fraction());
+        if shift >= self.max {
+            time + (shift - self.max)
+        } else {
+            time - (self.max - shift)
+        }
+    }
+
+    // SplitMix64 is good enough to spread queries and doesn't need any extra dependency
+    fn next_fraction(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// Breaker opens after `threshold` failed rounds in a row, zero threshold disables it;
+// the first round after cooldown is a probe which either closes the breaker or opens it again
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    failures: u32,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            failures: 0,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.threshold > 0 && self.failures >= self.threshold
+    }
+
+    // Returns cooldown to pause queries for if the breaker is open after the round
+    pub fn record(&mut self, round_succeeded: bool) -> Option<Duration> {
+        if round_succeeded {
+            self.failures = 0;
+        } else {
+            self.failures = self.failures.saturating_add(1);
+        }
+
+        self.is_open().then_some(self.cooldown)
+    }
+}
+
+// Number of workers is equal to the number of CPU cores by default
+pub fn build_runtime(worker_threads: Option<usize>) -> io::Result<runtime::Runtime> {
+    let mut builder = runtime::Builder::new_multi_thread();
+    if let Some(worker_threads) = worker_threads {
+        builder.worker_threads(worker_threads);
+    }
+
+    builder.enable_all().build()
+}
+
+// Isolates CPU-bound work of the future from other tasks of the main runtime
+pub fn spawn_on_dedicated_thread<F>(name: String, future: F) -> io::Result<()>
+where
+    F: Future + Send + 'static,
+{
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    std::thread::Builder::new().name(name).spawn(move || {
+        runtime.block_on(future);
+    })?;
+
+    Ok(())
+}
+
+pub fn systemd_listener_fd() -> Option<RawFd> {
+    activated_listener_fd(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        process::id(),
+    )
+}
+
+fn activated_listener_fd(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    pid: u32,
+) -> Option<RawFd> {
+    let listen_pid: u32 = listen_pid?.parse().ok()?;
+    let listen_fds: u32 = listen_fds?.parse().ok()?;
+
+    if listen_pid != pid || listen_fds == 0 {
+        return None;
+    }
+    if listen_fds > 1 {
+        warn!("{listen_fds} sockets have been passed by systemd, only the first one is used");
+    }
+
+    Some(SD_LISTEN_FDS_START)
+}
+
+pub fn tcp_listener_from_fd(fd: RawFd) -> io::Result<TcpListener> {
+    // SAFETY: the descriptor has been passed to this process by systemd and isn't used anywhere else
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+// Errors of accept() are usually transient (like EMFILE or ECONNABORTED), and the server stops on the first
+// error in the stream, so they're logged and the listener keeps accepting
+pub fn incoming_connections(listener: TcpListener) -> impl Stream<Item = io::Result<TcpStream>> {
+    stream::unfold(listener, |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => return Some((Ok(stream), listener)),
+                Err(e) => {
+                    warn!("unable to accept incoming connection: {e}");
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use std::{
+        os::fd::IntoRawFd,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Instant,
+    };
+    use tokio::sync::oneshot;
+
+    #[test]
+    fn jitter_is_bounded_and_reproducible() {
+        let max = Duration::from_secs(10);
+        let base = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let offsets = |seed| {
+            let mut jitter = Jitter::new(max, seed);
+            (0..100)
+                .map(|_| {
+                    let time = jitter.apply(base);
+                    match time.duration_since(base) {
+                        Ok(ahead) => ahead.as_secs_f64(),
+                        Err(e) => -e.duration().as_secs_f64(),
+                    }
+                })
+                .collect::<Vec<f64>>()
+        };
+
+        let first = offsets(42);
+        assert_eq!(first, offsets(42));
+        assert_ne!(first, offsets(43));
+        assert!(first.iter().all(|offset| offset.abs() <= 10.0), "{first:?}");
+        assert!(first.iter().any(|offset| *offset < -5.0));
+        assert!(first.iter().any(|offset| *offset > 5.0));
+
+        let mut disabled = Jitter::new(Duration::ZERO, 42);
+        assert_eq!(disabled.apply(base), base);
+    }
+
+    #[test]
+    fn circuit_breaker_opens_and_recloses() {
+        let cooldown = Duration::from_secs(60);
+        let mut breaker = CircuitBreaker::new(3, cooldown);
+
+        assert_eq!(breaker.record(false), None);
+        assert_eq!(breaker.record(true), None);
+        assert_eq!(breaker.record(false), None);
+        assert_eq!(breaker.record(false), None);
+        assert!(!breaker.is_open());
+        assert_eq!(breaker.record(false), Some(cooldown));
+        assert!(breaker.is_open());
+
+        // failed probe keeps it open for one more cooldown
+        assert_eq!(breaker.record(false), Some(cooldown));
+        assert!(breaker.is_open());
+        assert_eq!(breaker.record(true), None);
+        assert!(!breaker.is_open());
+
+        let mut disabled = CircuitBreaker::new(0, cooldown);
+        assert!((0..10).all(|_| disabled.record(false).is_none()));
+    }
+
+    #[test]
+    fn runtime_worker_threads() {
+        let runtime = build_runtime(Some(3)).unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 3);
+
+        let runtime = build_runtime(None).unwrap();
+        assert!(runtime.metrics().num_workers() >= 1);
+    }
+
+    #[test]
+    fn socket_activation_env() {
+        let pid = process::id();
+        let pid_str = pid.to_string();
+
+        assert_eq!(
+            activated_listener_fd(Some(&pid_str), Some("1"), pid),
+            Some(SD_LISTEN_FDS_START)
+        );
+        assert_eq!(
+            activated_listener_fd(Some(&pid_str), Some("2"), pid),
+            Some(SD_LISTEN_FDS_START)
+        );
+        assert_eq!(activated_listener_fd(Some(&pid_str), Some("0"), pid), None);
+        assert_eq!(activated_listener_fd(Some("1"), Some("1"), pid), None);
+        assert_eq!(activated_listener_fd(None, Some("1"), pid), None);
+        assert_eq!(activated_listener_fd(Some(&pid_str), None, pid), None);
+        assert_eq!(activated_listener_fd(Some("x"), Some("1"), pid), None);
+    }
+
+    #[tokio::test]
+    async fn accept_on_passed_fd() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fd = listener.into_raw_fd();
+
+        let listener = tcp_listener_from_fd(fd).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+
+        let client = tokio::spawn(TcpStream::connect(addr));
+        let mut incoming = Box::pin(incoming_connections(listener));
+        let accepted = incoming.next().await.unwrap().unwrap();
+        let client = client.await.unwrap().unwrap();
+
+        assert_eq!(accepted.peer_addr().unwrap(), client.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn query_limiter_caps_in_flight_queries() {
+        const CAP: usize = 3;
+        let limiter = QueryLimiter::new(CAP);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..20)
+            .map(|_| {
+                let limiter = limiter.clone();
+                let in_flight = in_flight.clone();
+                let max_in_flight = max_in_flight.clone();
+                tokio::spawn(async move {
+                    let _permit = limiter.acquire().await;
+                    let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(current, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), CAP);
+        assert!(QueryLimiter::new(0).acquire().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn dedicated_thread_isolates_busy_collector() {
+        let (done_tx, mut done_rx) = oneshot::channel();
+        spawn_on_dedicated_thread(String::from("busy"), async move {
+            // CPU-bound work without any await point
+            let started = Instant::now();
+            while started.elapsed() < Duration::from_millis(300) {
+                std::hint::spin_loop();
+            }
+            done_tx.send(()).unwrap();
+        })
+        .unwrap();
+
+        let started = Instant::now();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(started.elapsed() < Duration::from_millis(200));
+        assert!(done_rx.try_recv().is_err());
+
+        done_rx.await.unwrap();
+    }
+}
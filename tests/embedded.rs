@@ -0,0 +1,64 @@
+use clap::Parser;
+use psql_query_exporter::{run_exporter, AppConfig, ShutdownReceiver};
+use std::{cell::Cell, net::TcpListener, time::Duration};
+use tokio::sync::watch;
+
+#[tokio::test]
+async fn external_shutdown_without_signals() {
+    let config = std::env::temp_dir().join(format!(
+        "psql-query-exporter-embedded-{}.yaml",
+        std::process::id()
+    ));
+    std::fs::write(
+        &config,
+        r#"
+sources:
+  unreachable:
+    host: 127.0.0.1
+    port: 1
+    backoff_interval: 1s
+    user: postgres
+    password: postgres
+    databases:
+      - dbname: postgres
+        queries:
+          - metric_name: embedded_up
+            query: select 1
+"#,
+    )
+    .unwrap();
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let app_config = AppConfig::parse_from([
+        "psql-query-exporter",
+        "--listen-on",
+        "127.0.0.1",
+        "--port",
+        &port.to_string(),
+        "--config",
+        config.to_str().unwrap(),
+    ]);
+
+    let (shutdown_tx, shutdown_rx): (_, ShutdownReceiver) = watch::channel(false);
+    let requested = Cell::new(false);
+    let request_shutdown = async {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        requested.set(true);
+        shutdown_tx.send_replace(true);
+    };
+
+    let (result, _) = tokio::time::timeout(Duration::from_secs(10), async {
+        tokio::join!(
+            run_exporter(app_config, Some(shutdown_rx)),
+            request_shutdown
+        )
+    })
+    .await
+    .expect("exporter should stop on external request");
+    result.unwrap();
+    assert!(requested.get());
+    std::fs::remove_file(&config).unwrap();
+}